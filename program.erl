@@ -0,0 +1,20 @@
+/* the functions of program.txt in the Erlang flavoured syntax */
+logicfun(A, B) -> A == B.
+
+mathfun(A, B) when (A - B) == 5 -> 0;
+mathfun(A, B) -> 1 + mul(A, B).
+
+mul(A, B) -> A * B.
+
+map([], F, Acc) -> Acc;
+map([H|T], F, Acc) -> map(T, F, [F(H)|Acc]).
+
+sum([], Acc) -> Acc;
+sum([H|T], Acc) when is_number(H), H > 0; H == -1 -> sum(T, Acc + H);
+sum([_|T], Acc) -> sum(T, Acc).
+
+main() ->
+    A = mathfun(2, 5),
+    B = logicfun(true, false),
+    C = sum([1, -1, -2, 3], 0),
+    [A, B, C].
@@ -0,0 +1,657 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use crate::{
+    bytecode::{Compiled, CompiledFun, Op},
+    eval::Data,
+    function::FunctionName,
+    logic_expr::CompareOp,
+    numeric_expr::NumericData,
+    types::Type,
+};
+
+// The compiled form written by the compile subcommand, run loads it
+// without going through the front end.
+//
+//     "RTBC"                 magic
+//     version                u32
+//     source path            string
+//     source hash            u64, FNV-1a of the source text
+//     functions              u32 count, then each function, its instructions,
+//                            the lines of the function and its binds, and
+//                            the bind each instruction belongs to
+//
+// Numbers are little endian, strings and vectors are prefixed with their
+// length as a u32, enums with a one byte tag. A file is only loaded when
+// its version is this VERSION and, if the source is still there, when the
+// source has not changed since it was compiled.
+
+const MAGIC: &[u8; 4] = b"RTBC";
+pub const VERSION: u32 = 8;
+
+#[derive(Debug)]
+pub enum LoadError {
+    NotCompiled,
+    Version(u32),
+    Stale(String),
+    Corrupt(usize),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::NotCompiled => write!(f, "not a compiled program"),
+            LoadError::Version(v) => write!(
+                f,
+                "compiled with format version {}, this build reads version {}",
+                v, VERSION
+            ),
+            LoadError::Stale(source) => {
+                write!(
+                    f,
+                    "{} changed since it was compiled, compile it again",
+                    source
+                )
+            }
+            LoadError::Corrupt(at) => write!(f, "corrupt compiled program at byte {}", at),
+        }
+    }
+}
+
+pub fn source_hash(source: &str) -> u64 {
+    return source.bytes().fold(0xcbf29ce484222325, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+}
+
+pub fn encode(code: &Compiled, source_path: &str, source: &str) -> Vec<u8> {
+    let mut w = Writer { out: Vec::new() };
+    w.out.extend_from_slice(MAGIC);
+    w.u32(VERSION);
+    w.string(&source_path.chars().collect::<Vec<char>>());
+    w.u64(source_hash(source));
+    w.u32(code.funs.len() as u32);
+    for f in code.funs.iter() {
+        w.function_name(&f.name);
+        w.u32(f.arity as u32);
+        w.u32(f.slots as u32);
+        match &f.spec {
+            None => w.u8(0),
+            Some(t) => {
+                w.u8(1);
+                w.ty(t);
+            }
+        }
+        w.u32(f.code.len() as u32);
+        f.code.iter().for_each(|op| w.op(op));
+        w.u32(f.line as u32);
+        w.u32(f.bind_lines.len() as u32);
+        f.bind_lines.iter().for_each(|l| w.u32(*l as u32));
+        for c in f.clauses.iter() {
+            match c {
+                None => w.u8(0),
+                Some(c) => {
+                    w.u8(1);
+                    w.u32(*c as u32);
+                }
+            }
+        }
+    }
+    return w.out;
+}
+
+// read_source gives the text of the source file the program was compiled from, if it still exists
+pub fn decode(
+    bytes: &[u8],
+    read_source: impl Fn(&str) -> Option<String>,
+) -> Result<Compiled, LoadError> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(LoadError::NotCompiled);
+    }
+    let mut r = Reader {
+        bytes: bytes,
+        pos: MAGIC.len(),
+    };
+    let version = r.u32()?;
+    if version != VERSION {
+        return Err(LoadError::Version(version));
+    }
+    let path: String = r.string()?.into_iter().collect();
+    let hash = r.u64()?;
+    if let Some(source) = read_source(&path) {
+        if source_hash(&source) != hash {
+            return Err(LoadError::Stale(path));
+        }
+    }
+    let count = r.u32()?;
+    let mut funs = Vec::new();
+    for _ in 0..count {
+        let name = r.function_name()?;
+        let arity = r.u32()? as usize;
+        let slots = r.u32()? as usize;
+        let spec = match r.u8()? {
+            0 => None,
+            1 => Some(r.ty()?),
+            _ => return Err(r.corrupt()),
+        };
+        let len = r.u32()?;
+        let mut code = Vec::new();
+        for _ in 0..len {
+            code.push(r.op()?);
+        }
+        let line = r.usize()?;
+        let binds = r.usize()?;
+        let mut bind_lines = Vec::new();
+        for _ in 0..binds {
+            bind_lines.push(r.usize()?);
+        }
+        let mut clauses = Vec::new();
+        for _ in 0..len {
+            clauses.push(match r.u8()? {
+                0 => None,
+                1 => Some(r.usize()?),
+                _ => return Err(r.corrupt()),
+            });
+        }
+        funs.push(CompiledFun {
+            name: name,
+            arity: arity,
+            slots: slots,
+            code: code,
+            line: line,
+            bind_lines: bind_lines,
+            clauses: clauses,
+            spec: spec,
+        });
+    }
+    if r.pos != bytes.len() || !funs.iter().all(|f| in_range(f, funs.len())) {
+        return Err(r.corrupt());
+    }
+    let index: HashMap<FunctionName, usize> = funs
+        .iter()
+        .enumerate()
+        .map(|(i, f)| (f.name.clone(), i))
+        .collect();
+    return Ok(Compiled {
+        funs: funs,
+        index: index,
+    });
+}
+
+// jumps stay inside their function, calls and slots point at something
+fn in_range(f: &CompiledFun, funs: usize) -> bool {
+    return f.arity <= f.slots
+        && f.clauses.len() == f.code.len()
+        && f.clauses.iter().flatten().all(|c| *c < f.bind_lines.len())
+        && f.code.iter().all(|op| match op {
+            Op::MatchBound(slot, t) => *slot < f.slots && *t < f.code.len(),
+            Op::MatchEq(_, t)
+            | Op::MatchCons(t)
+            | Op::Jump(t)
+            | Op::JumpIfFalse(t)
+            | Op::JumpIfTrue(t)
+            | Op::Try(t) => *t < f.code.len(),
+            Op::Load(slot)
+            | Op::Store(slot)
+            | Op::CallDynamic(slot, _)
+            | Op::BadMatch(slot)
+            | Op::CaseClause(slot) => *slot < f.slots,
+            Op::Call(i, _) | Op::TailCall(i, _) => *i < funs,
+            Op::Switch(consts, cons, default) => consts
+                .iter()
+                .map(|(_, t)| t)
+                .chain(cons.iter())
+                .chain(std::iter::once(default))
+                .all(|t| *t < f.code.len()),
+            _ => true,
+        });
+}
+
+struct Writer {
+    out: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, b: u8) {
+        self.out.push(b);
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.out.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn u64(&mut self, n: u64) {
+        self.out.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn string(&mut self, s: &[char]) {
+        let s: String = s.iter().collect();
+        self.u32(s.len() as u32);
+        self.out.extend_from_slice(s.as_bytes());
+    }
+
+    fn function_name(&mut self, f: &FunctionName) {
+        match f {
+            FunctionName::Static(n) => self.tagged(0, n),
+            FunctionName::Dynamic(n) => self.tagged(1, n),
+            FunctionName::Rust(n) => self.tagged(2, n),
+            FunctionName::Builtin(n) => self.tagged(3, n),
+        }
+    }
+
+    fn tagged(&mut self, tag: u8, s: &[char]) {
+        self.u8(tag);
+        self.string(s);
+    }
+
+    fn data(&mut self, d: &Data) {
+        match d {
+            Data::Number(NumericData::Int(i)) => {
+                self.u8(0);
+                self.u64(*i as u64);
+            }
+            Data::Boolean(b) => {
+                self.u8(1);
+                self.u8(*b as u8);
+            }
+            Data::Emptylist => self.u8(2),
+            Data::List(h, t) => {
+                self.u8(3);
+                self.data(h);
+                self.data(t);
+            }
+            Data::FunctionPointer(f) => {
+                self.u8(4);
+                self.function_name(f);
+            }
+            Data::Atom(a) => self.tagged(5, a),
+        }
+    }
+
+    fn ty(&mut self, t: &Type) {
+        match t {
+            Type::Var(v) => {
+                self.u8(0);
+                self.u32(*v as u32);
+            }
+            Type::Number => self.u8(1),
+            Type::Boolean => self.u8(2),
+            Type::List(t) => {
+                self.u8(3);
+                self.ty(t);
+            }
+            Type::Fun(args, ret) => {
+                self.u8(4);
+                self.u32(args.len() as u32);
+                args.iter().for_each(|a| self.ty(a));
+                self.ty(ret);
+            }
+            Type::Named(n) => self.tagged(5, n),
+            Type::Atom => self.u8(6),
+        }
+    }
+
+    fn op(&mut self, op: &Op) {
+        match op {
+            Op::Const(d) => {
+                self.u8(0);
+                self.data(d);
+            }
+            Op::Load(n) => self.with(1, *n),
+            Op::Store(n) => self.with(2, *n),
+            Op::Reset => self.u8(3),
+            Op::MatchEq(d, target) => {
+                self.u8(4);
+                self.data(d);
+                self.u32(*target as u32);
+            }
+            Op::MatchCons(target) => self.with(5, *target),
+            Op::Switch(consts, cons, default) => {
+                self.u8(34);
+                self.u32(consts.len() as u32);
+                for (d, target) in consts.iter() {
+                    self.data(d);
+                    self.u32(*target as u32);
+                }
+                match cons {
+                    None => self.u8(0),
+                    Some(target) => {
+                        self.u8(1);
+                        self.u32(*target as u32);
+                    }
+                }
+                self.u32(*default as u32);
+            }
+            Op::Jump(target) => self.with(6, *target),
+            Op::JumpIfFalse(target) => self.with(7, *target),
+            Op::JumpIfTrue(target) => self.with(8, *target),
+            Op::Add(n) => self.with(9, *n),
+            Op::Mul(n) => self.with(10, *n),
+            Op::Sub => self.u8(11),
+            Op::Div => self.u8(12),
+            Op::Neg => self.u8(13),
+            Op::CheckNumber => self.u8(14),
+            Op::CheckBoolean => self.u8(15),
+            Op::Xor(n) => self.with(18, *n),
+            Op::Not => self.u8(19),
+            Op::Eq(n) => self.with(20, *n),
+            Op::Neq => self.u8(21),
+            Op::Compare(c) => {
+                self.u8(22);
+                self.u8(match c {
+                    CompareOp::LT => 0,
+                    CompareOp::GT => 1,
+                    CompareOp::LTE => 2,
+                    CompareOp::GTE => 3,
+                });
+            }
+            Op::Cons => self.u8(23),
+            Op::Call(i, argc) => {
+                self.with(24, *i);
+                self.u32(*argc as u32);
+            }
+            Op::TailCall(i, argc) => {
+                self.with(25, *i);
+                self.u32(*argc as u32);
+            }
+            Op::CallDynamic(slot, argc) => {
+                self.with(26, *slot);
+                self.u32(*argc as u32);
+            }
+            Op::CallHost(f, argc) => {
+                self.u8(27);
+                self.function_name(f);
+                self.u32(*argc as u32);
+            }
+            Op::Return => self.u8(28),
+            Op::NoMatch => self.u8(29),
+            Op::BadMatch(slot) => self.with(30, *slot),
+            Op::Unbound(n) => self.tagged(31, n),
+            Op::MatchBound(slot, target) => {
+                self.u8(32);
+                self.u32(*slot as u32);
+                self.u32(*target as u32);
+            }
+            Op::Undefined(f, argc) => {
+                self.u8(33);
+                self.function_name(f);
+                self.u32(*argc as u32);
+            }
+            Op::Try(handler) => self.with(35, *handler),
+            Op::EndTry => self.u8(36),
+            Op::Raise => self.u8(37),
+            Op::CaseClause(slot) => self.with(38, *slot),
+            Op::IfClause => self.u8(39),
+        }
+    }
+
+    fn with(&mut self, tag: u8, n: usize) {
+        self.u8(tag);
+        self.u32(n as u32);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn corrupt(&self) -> LoadError {
+        return LoadError::Corrupt(self.pos);
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], LoadError> {
+        if self.pos + n > self.bytes.len() {
+            return Err(self.corrupt());
+        }
+        let s = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        return Ok(s);
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        return Ok(self.take(1)?[0]);
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        return Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()));
+    }
+
+    fn u64(&mut self) -> Result<u64, LoadError> {
+        return Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()));
+    }
+
+    fn usize(&mut self) -> Result<usize, LoadError> {
+        return Ok(self.u32()? as usize);
+    }
+
+    fn string(&mut self) -> Result<Vec<char>, LoadError> {
+        let len = self.usize()?;
+        let at = self.pos;
+        match std::str::from_utf8(self.take(len)?) {
+            Ok(s) => Ok(s.chars().collect()),
+            Err(_) => Err(LoadError::Corrupt(at)),
+        }
+    }
+
+    fn function_name(&mut self) -> Result<FunctionName, LoadError> {
+        match self.u8()? {
+            0 => Ok(FunctionName::Static(self.string()?)),
+            1 => Ok(FunctionName::Dynamic(self.string()?)),
+            2 => Ok(FunctionName::Rust(self.string()?)),
+            3 => Ok(FunctionName::Builtin(self.string()?)),
+            _ => Err(self.corrupt()),
+        }
+    }
+
+    fn data(&mut self) -> Result<Data, LoadError> {
+        match self.u8()? {
+            0 => Ok(Data::Number(NumericData::Int(self.u64()? as i64))),
+            1 => match self.u8()? {
+                0 => Ok(Data::Boolean(false)),
+                1 => Ok(Data::Boolean(true)),
+                _ => Err(self.corrupt()),
+            },
+            2 => Ok(Data::Emptylist),
+            3 => {
+                let h = self.data()?;
+                let t = self.data()?;
+                Ok(Data::List(Arc::new(h), Arc::new(t)))
+            }
+            4 => Ok(Data::FunctionPointer(self.function_name()?)),
+            5 => Ok(Data::Atom(self.string()?)),
+            _ => Err(self.corrupt()),
+        }
+    }
+
+    fn ty(&mut self) -> Result<Type, LoadError> {
+        match self.u8()? {
+            0 => Ok(Type::Var(self.usize()?)),
+            1 => Ok(Type::Number),
+            2 => Ok(Type::Boolean),
+            3 => Ok(Type::List(Box::new(self.ty()?))),
+            4 => {
+                let len = self.usize()?;
+                let mut args = Vec::new();
+                for _ in 0..len {
+                    args.push(self.ty()?);
+                }
+                Ok(Type::Fun(args, Box::new(self.ty()?)))
+            }
+            5 => Ok(Type::Named(self.string()?)),
+            6 => Ok(Type::Atom),
+            _ => Err(self.corrupt()),
+        }
+    }
+
+    fn op(&mut self) -> Result<Op, LoadError> {
+        let op = match self.u8()? {
+            0 => Op::Const(self.data()?),
+            1 => Op::Load(self.usize()?),
+            2 => Op::Store(self.usize()?),
+            3 => Op::Reset,
+            4 => {
+                let d = self.data()?;
+                Op::MatchEq(d, self.usize()?)
+            }
+            5 => Op::MatchCons(self.usize()?),
+            6 => Op::Jump(self.usize()?),
+            7 => Op::JumpIfFalse(self.usize()?),
+            8 => Op::JumpIfTrue(self.usize()?),
+            9 => Op::Add(self.usize()?),
+            10 => Op::Mul(self.usize()?),
+            11 => Op::Sub,
+            12 => Op::Div,
+            13 => Op::Neg,
+            14 => Op::CheckNumber,
+            15 => Op::CheckBoolean,
+            18 => Op::Xor(self.usize()?),
+            19 => Op::Not,
+            20 => Op::Eq(self.usize()?),
+            21 => Op::Neq,
+            22 => Op::Compare(match self.u8()? {
+                0 => CompareOp::LT,
+                1 => CompareOp::GT,
+                2 => CompareOp::LTE,
+                3 => CompareOp::GTE,
+                _ => return Err(self.corrupt()),
+            }),
+            23 => Op::Cons,
+            24 => {
+                let i = self.usize()?;
+                Op::Call(i, self.usize()?)
+            }
+            25 => {
+                let i = self.usize()?;
+                Op::TailCall(i, self.usize()?)
+            }
+            26 => {
+                let slot = self.usize()?;
+                Op::CallDynamic(slot, self.usize()?)
+            }
+            27 => {
+                let f = self.function_name()?;
+                Op::CallHost(f, self.usize()?)
+            }
+            28 => Op::Return,
+            29 => Op::NoMatch,
+            30 => Op::BadMatch(self.usize()?),
+            31 => Op::Unbound(self.string()?),
+            32 => {
+                let slot = self.usize()?;
+                Op::MatchBound(slot, self.usize()?)
+            }
+            33 => {
+                let f = self.function_name()?;
+                Op::Undefined(f, self.usize()?)
+            }
+            34 => {
+                let len = self.usize()?;
+                let mut consts = Vec::new();
+                for _ in 0..len {
+                    let d = self.data()?;
+                    consts.push((d, self.usize()?));
+                }
+                let cons = match self.u8()? {
+                    0 => None,
+                    1 => Some(self.usize()?),
+                    _ => return Err(self.corrupt()),
+                };
+                Op::Switch(consts, cons, self.usize()?)
+            }
+            35 => Op::Try(self.usize()?),
+            36 => Op::EndTry,
+            37 => Op::Raise,
+            38 => Op::CaseClause(self.usize()?),
+            39 => Op::IfClause,
+            _ => return Err(self.corrupt()),
+        };
+        return Ok(op);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bytecode, capability::Capabilities, eval, program, vm};
+
+    const SOURCE: &str = "program.txt";
+
+    fn compiled() -> (Vec<u8>, String) {
+        let source = std::fs::read_to_string(SOURCE).expect("Cant read file");
+        let code = bytecode::compile_program(&program::from_file(SOURCE));
+        return (encode(&code, SOURCE, &source), source);
+    }
+
+    #[test]
+    fn every_sample_loads_and_runs_as_compiled() {
+        let main = FunctionName::Static("main".chars().collect());
+        for path in program::SAMPLES {
+            let source = std::fs::read_to_string(path).expect("Cant read file");
+            let code = program::from_file(path);
+            let bytes = encode(&bytecode::compile_program(&code), path, &source);
+            let loaded = match decode(&bytes, |_| Some(source.clone())) {
+                Ok(loaded) => loaded,
+                Err(e) => panic!("{} does not load: {}", path, e),
+            };
+            assert_eq!(encode(&loaded, path, &source), bytes, "{}", path);
+            let ran = vm::call(
+                &loaded,
+                &main,
+                Vec::new(),
+                &vm::Limits::default(),
+                &Capabilities::All,
+            );
+            match ran {
+                Ok(res) => assert_eq!(Ok(res), eval::run_main(&code), "{}", path),
+                Err(e) => panic!("{} fails once loaded: {}", path, e),
+            }
+        }
+    }
+
+    #[test]
+    fn other_files_are_not_compiled_programs() {
+        let (_, source) = compiled();
+        let res = decode(source.as_bytes(), |_| None);
+        assert!(matches!(res, Err(LoadError::NotCompiled)), "{:?}", res);
+    }
+
+    #[test]
+    fn another_version_is_refused() {
+        let (mut bytes, _) = compiled();
+        bytes[4..8].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        match decode(&bytes, |_| None) {
+            Err(e @ LoadError::Version(_)) => assert_eq!(
+                e.to_string(),
+                format!(
+                    "compiled with format version {}, this build reads version {}",
+                    VERSION + 1,
+                    VERSION
+                )
+            ),
+            res => panic!("loaded another version: {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn a_changed_source_is_stale() {
+        let (bytes, source) = compiled();
+        let res = decode(&bytes, |_| Some(format!("{}\n", source)));
+        assert!(
+            matches!(&res, Err(LoadError::Stale(p)) if p == SOURCE),
+            "{:?}",
+            res.map(|_| ())
+        );
+        // without the source there is nothing to compare with
+        assert!(decode(&bytes, |_| None).is_ok());
+    }
+
+    #[test]
+    fn a_cut_file_is_corrupt() {
+        let (bytes, _) = compiled();
+        for len in [8, bytes.len() / 2, bytes.len() - 1] {
+            let res = decode(&bytes[0..len], |_| None);
+            assert!(matches!(res, Err(LoadError::Corrupt(_))), "{}", len);
+        }
+    }
+}
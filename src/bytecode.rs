@@ -0,0 +1,697 @@
+use std::collections::HashMap;
+
+use crate::{
+    decision,
+    eval::{self, Data},
+    expr::{Catch, Clause, Expr},
+    function::{self, FunctionName},
+    logic_expr::{CompareOp, LogicExpr},
+    numeric_expr::{ArrayNumOp, NumericExpr, OrderedNumOp},
+    pattern_match::{self, ArgBind},
+    program, types,
+    vm::RuntimeErrorKind,
+};
+
+// Compiles the lowered program into bytecode for vm.rs
+//
+// Every function is one flat list of instructions over an operand stack
+// and numbered local slots, the arguments are in slots 0..arity.
+// Each bind tests its patterns, then each filter tests its guard and
+// returns its body, a failed pattern or guard jumps to the next one:
+//
+//     Reset                     bind 1
+//     Load 0, MatchCons fail1   ([H|T], ..)
+//     Store 2, Store 3
+//     <guard> JumpIfFalse next  { .. }
+//     <body> Return             / .. \
+//     fail1: Reset              bind 2
+//     ...
+//     NoMatch
+//
+// Most functions match their heads with a decision tree instead (decision.rs),
+// each Switch tests one argument position and leads to the binds that can
+// still match, whose guards are tried in order.
+//
+// A call in tail position reuses the frame of the caller.
+//
+// A try runs its body between Try and EndTry, an error inside jumps to the
+// handler with the class and the term on the stack:
+//
+//     Try handler
+//     <body> EndTry Jump end
+//     handler: Store class, Store term
+//     <class and pattern of clause 1> <code> Jump end
+//     ...
+//     Load class, Load term, Raise
+//     end:
+//
+// A case keeps its value in a slot, each clause matches it like a bind
+// matches its arguments and falls through to the next on a failed guard:
+//
+//     <arg> Store value
+//     <pattern of clause 1> <guard> JumpIfFalse next <code> Jump end
+//     ...
+//     CaseClause value
+//     end:
+//
+// An if is the same without the value and the patterns, ending in IfClause.
+
+#[derive(Debug, Clone)]
+pub enum Op {
+    Const(Data),
+    Load(usize),
+    Store(usize),
+    // drops what a failed pattern left on the stack of this frame
+    Reset,
+
+    // patterns, each pops the value it tests and jumps to the target when it does not match
+    MatchEq(Data, usize),
+    // pushes the tail then the head
+    MatchCons(usize),
+    // a variable bound before matches the value in its slot
+    MatchBound(usize, usize),
+    // one test of a decision tree: jumps to the target of the constant the value equals,
+    // or with a list, pushes the tail then the head and jumps to the second target
+    Switch(Vec<(Data, usize)>, Option<usize>, usize),
+
+    Jump(usize),
+    JumpIfFalse(usize),
+    JumpIfTrue(usize),
+
+    // arithmetic over the top n values
+    Add(usize),
+    Mul(usize),
+    Sub,
+    Div,
+    Neg,
+    // a variable or call used in arithmetic or logic must give that type
+    CheckNumber,
+    CheckBoolean,
+
+    // strict logic over the top n values, && and || are jumps
+    Xor(usize),
+    Not,
+    Eq(usize),
+    Neq,
+    Compare(CompareOp),
+
+    // pops the tail then the head
+    Cons,
+
+    // function index and argument count
+    Call(usize, usize),
+    TailCall(usize, usize),
+    // slot holding the function and argument count
+    CallDynamic(usize, usize),
+    // rust:: and builtin functions
+    CallHost(FunctionName, usize),
+    Return,
+
+    // errors the tree walker reports at the same point
+    NoMatch,
+    // the slot holding the value that did not match
+    BadMatch(usize),
+    CaseClause(usize),
+    IfClause,
+    Unbound(Vec<char>),
+    Undefined(FunctionName, usize),
+
+    // where to go when the body of the try fails
+    Try(usize),
+    EndTry,
+    // pops the term then the class
+    Raise,
+}
+
+#[derive(Debug, Clone)]
+pub struct CompiledFun {
+    pub name: FunctionName,
+    pub arity: usize,
+    pub slots: usize,
+    pub code: Vec<Op>,
+    // where the function is defined, where each bind is and
+    // the bind each instruction belongs to, None for matching the head
+    pub line: usize,
+    pub bind_lines: Vec<usize>,
+    pub clauses: Vec<Option<usize>>,
+    pub spec: Option<types::Type>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Compiled {
+    pub funs: Vec<CompiledFun>,
+    pub index: HashMap<FunctionName, usize>,
+}
+
+pub fn compile_program(code: &eval::Program) -> Compiled {
+    let mut names: Vec<&FunctionName> = code.keys().collect();
+    names.sort();
+    let index: HashMap<FunctionName, usize> = names
+        .iter()
+        .enumerate()
+        .map(|(i, n)| ((*n).clone(), i))
+        .collect();
+    let funs = names
+        .iter()
+        .map(|n| compile_fun(*n, &code[*n], &index))
+        .collect();
+    return Compiled {
+        funs: funs,
+        index: index,
+    };
+}
+
+fn compile_fun(
+    name: &FunctionName,
+    fun: &program::Fun,
+    index: &HashMap<FunctionName, usize>,
+) -> CompiledFun {
+    let arity = fun.binds.first().map(|b| b.pattern.len()).unwrap_or(0);
+    let mut c = Compiler {
+        code: Vec::new(),
+        clauses: Vec::new(),
+        clause: None,
+        index: index,
+        vars: HashMap::new(),
+        positions: HashMap::new(),
+        slots: arity,
+        // a tail call would skip the check of the result against the spec
+        tail_calls: fun.spec.is_none(),
+    };
+    if let Some(tree) = decision::build(&fun.binds) {
+        c.positions = decision::slots(&tree, arity);
+        c.slots = c.positions.len();
+        c.tree(&tree, &fun.binds);
+        return CompiledFun {
+            name: name.clone(),
+            arity: arity,
+            slots: c.slots,
+            code: c.code,
+            line: fun.line,
+            bind_lines: fun.binds.iter().map(|b| b.line).collect(),
+            clauses: c.clauses,
+            spec: fun.spec.clone(),
+        };
+    }
+    // heads the decision tree does not take are matched one bind at a time
+    for (i, bind) in fun.binds.iter().enumerate() {
+        c.vars.clear();
+        c.clause = Some(i);
+        c.emit(Op::Reset);
+        let mut fails = Vec::new();
+        for (i, p) in bind.pattern.iter().enumerate() {
+            c.emit(Op::Load(i));
+            c.pattern(p, &mut fails);
+        }
+        c.filters(bind);
+        let here = c.code.len();
+        c.patch(&fails, here);
+    }
+    c.clause = None;
+    c.emit(Op::NoMatch);
+    return CompiledFun {
+        name: name.clone(),
+        arity: arity,
+        slots: c.slots,
+        code: c.code,
+        line: fun.line,
+        bind_lines: fun.binds.iter().map(|b| b.line).collect(),
+        clauses: c.clauses,
+        spec: fun.spec.clone(),
+    };
+}
+
+struct Compiler<'a> {
+    code: Vec<Op>,
+    clauses: Vec<Option<usize>>,
+    clause: Option<usize>,
+    index: &'a HashMap<FunctionName, usize>,
+    // slots of the variables bound so far in this bind
+    vars: HashMap<Vec<char>, usize>,
+    // slots of the argument positions a decision tree has looked at
+    positions: HashMap<decision::Occurrence, usize>,
+    slots: usize,
+    tail_calls: bool,
+}
+
+impl CompiledFun {
+    pub fn clause_at(&self, pc: usize) -> Option<usize> {
+        return self.clauses.get(pc).cloned().flatten();
+    }
+
+    pub fn line_at(&self, pc: usize) -> usize {
+        match self.clause_at(pc) {
+            Some(c) => return self.bind_lines[c],
+            None => return self.line,
+        }
+    }
+}
+
+impl<'a> Compiler<'a> {
+    fn emit(&mut self, op: Op) -> usize {
+        self.code.push(op);
+        self.clauses.push(self.clause);
+        return self.code.len() - 1;
+    }
+
+    // points the jumps at target once it is known
+    fn patch(&mut self, jumps: &[usize], target: usize) {
+        for j in jumps.iter() {
+            self.code[*j] = match &self.code[*j] {
+                Op::MatchEq(d, _) => Op::MatchEq(d.clone(), target),
+                Op::MatchCons(_) => Op::MatchCons(target),
+                Op::MatchBound(slot, _) => Op::MatchBound(*slot, target),
+                Op::Jump(_) => Op::Jump(target),
+                Op::JumpIfFalse(_) => Op::JumpIfFalse(target),
+                Op::JumpIfTrue(_) => Op::JumpIfTrue(target),
+                Op::Try(_) => Op::Try(target),
+                op => op.clone(),
+            };
+        }
+    }
+
+    // the guards of the bind in order, falls through when they all fail
+    fn filters(&mut self, bind: &program::Bind) {
+        self.branches(&bind.filters, true, &mut Vec::new());
+    }
+
+    // guards in order, each body returns in tail position or else jumps to
+    // one of ends, falls through when every guard fails
+    fn branches(&mut self, filters: &[program::Filter], tail: bool, ends: &mut Vec<usize>) {
+        for filter in filters.iter() {
+            let mut next = Vec::new();
+            if !matches!(filter.head, LogicExpr::True) {
+                self.logic(&filter.head);
+                next.push(self.emit(Op::JumpIfFalse(0)));
+            }
+            if tail {
+                self.tail(&filter.code);
+            } else {
+                self.expr(&filter.code);
+                ends.push(self.emit(Op::Jump(0)));
+            }
+            let here = self.code.len();
+            self.patch(&next, here);
+        }
+    }
+
+    fn tree(&mut self, t: &decision::Tree, binds: &[program::Bind]) {
+        match t {
+            decision::Tree::Fail => {
+                self.clause = None;
+                self.emit(Op::NoMatch);
+            }
+            decision::Tree::Leaf { bind, fallback } => {
+                self.clause = Some(*bind);
+                // the variables are the positions the tree already stored
+                self.vars = decision::bindings(&binds[*bind])
+                    .into_iter()
+                    .map(|(n, at)| (n, self.positions[&at]))
+                    .collect();
+                self.filters(&binds[*bind]);
+                self.tree(fallback, binds);
+            }
+            decision::Tree::Switch { at, cases, default } => {
+                self.clause = None;
+                self.emit(Op::Load(self.positions[at]));
+                let switch = self.emit(Op::Switch(Vec::new(), None, 0));
+                let mut consts = Vec::new();
+                let mut cons = None;
+                for (ctor, sub) in cases.iter() {
+                    let here = self.code.len();
+                    self.clause = None;
+                    match ctor {
+                        decision::Ctor::Const(d) => consts.push((d.clone(), here)),
+                        decision::Ctor::Cons => {
+                            cons = Some(here);
+                            let mut head = at.clone();
+                            head.push(0);
+                            let mut tail = at.clone();
+                            tail.push(1);
+                            self.emit(Op::Store(self.positions[&head]));
+                            self.emit(Op::Store(self.positions[&tail]));
+                        }
+                    }
+                    self.tree(sub, binds);
+                }
+                let here = self.code.len();
+                self.code[switch] = Op::Switch(consts, cons, here);
+                self.tree(default, binds);
+            }
+        }
+    }
+
+    // matches the value on top of the stack, jumps that need a target go in fails
+    fn pattern(&mut self, p: &ArgBind, fails: &mut Vec<usize>) {
+        match p {
+            // stored where nothing reads it, only to take it off the stack
+            ArgBind::Identifier(n) if pattern_match::is_wildcard(n) => {
+                let slot = self.slots;
+                self.slots += 1;
+                self.emit(Op::Store(slot));
+            }
+            ArgBind::Identifier(n) => match self.vars.get(n) {
+                // bound before, so the value has to equal it
+                Some(bound) => fails.push(self.emit(Op::MatchBound(*bound, 0))),
+                None => {
+                    let slot = self.slots;
+                    self.slots += 1;
+                    self.vars.insert(n.clone(), slot);
+                    self.emit(Op::Store(slot));
+                }
+            },
+            ArgBind::Emptylist => fails.push(self.emit(Op::MatchEq(Data::Emptylist, 0))),
+            ArgBind::ConstPattern(d) => fails.push(self.emit(Op::MatchEq(d.clone(), 0))),
+            ArgBind::ListPattern { head, tail } => {
+                fails.push(self.emit(Op::MatchCons(0)));
+                self.pattern(head, fails);
+                self.pattern(tail, fails);
+            }
+        }
+    }
+
+    // a body, its value is returned
+    fn tail(&mut self, e: &Expr) {
+        match e {
+            Expr::Assign { pattern, arg, rest } => {
+                self.assign(pattern, arg);
+                self.tail(rest);
+            }
+            Expr::Case { arg, clauses } => self.case(arg, clauses, true),
+            Expr::If(branches) => self.if_branches(branches, true),
+            Expr::Call(FunctionName::Static(n), args) if self.tail_calls => {
+                match self.index.get(&FunctionName::Static(n.clone())) {
+                    Some(i) => {
+                        let i = *i;
+                        args.iter().for_each(|a| self.expr(a));
+                        self.emit(Op::TailCall(i, args.len()));
+                    }
+                    None => {
+                        self.expr(e);
+                        self.emit(Op::Return);
+                    }
+                }
+            }
+            _ => {
+                self.expr(e);
+                self.emit(Op::Return);
+            }
+        }
+    }
+
+    fn assign(&mut self, pattern: &ArgBind, arg: &Expr) {
+        self.expr(arg);
+        // kept to report it when it does not match
+        let value = self.slots;
+        self.slots += 1;
+        self.emit(Op::Store(value));
+        self.emit(Op::Load(value));
+        let mut fails = Vec::new();
+        self.pattern(pattern, &mut fails);
+        if !fails.is_empty() {
+            let skip = self.emit(Op::Jump(0));
+            let here = self.code.len();
+            self.patch(&fails, here);
+            self.emit(Op::BadMatch(value));
+            let after = self.code.len();
+            self.patch(&[skip], after);
+        }
+    }
+
+    fn variable(&mut self, n: &[char]) {
+        match self.vars.get(n) {
+            Some(slot) => {
+                let slot = *slot;
+                self.emit(Op::Load(slot));
+            }
+            None => {
+                self.emit(Op::Unbound(n.to_vec()));
+            }
+        }
+    }
+
+    fn expr(&mut self, e: &Expr) {
+        match e {
+            Expr::Constant(d) => {
+                self.emit(Op::Const(d.clone()));
+            }
+            Expr::Identifier(n) => self.variable(n),
+            Expr::Call(f, args) => self.call(f, args),
+            Expr::ListBuild(h, t) => {
+                self.expr(h);
+                self.expr(t);
+                self.emit(Op::Cons);
+            }
+            Expr::NumericExpr(n) => self.numeric(n),
+            Expr::LogicExpr(l) => self.logic(l),
+            // only bodies have assignments, see tail
+            Expr::Assign { pattern, arg, rest } => {
+                self.assign(pattern, arg);
+                self.expr(rest);
+            }
+            Expr::Try { body, catches } => self.try_catch(body, catches),
+            Expr::Case { arg, clauses } => self.case(arg, clauses, false),
+            Expr::If(branches) => self.if_branches(branches, false),
+        }
+    }
+
+    // in tail position every clause returns its value, otherwise they jump to the end
+    fn case(&mut self, arg: &Expr, clauses: &[Clause], tail: bool) {
+        self.expr(arg);
+        let value = self.slots;
+        self.slots += 1;
+        self.emit(Op::Store(value));
+        // variables of a clause are not bound after the case
+        let outer = self.vars.clone();
+        let mut ends = Vec::new();
+        for clause in clauses.iter() {
+            self.vars = outer.clone();
+            let mut fails = Vec::new();
+            self.pattern_at(&clause.pattern, value, &mut fails);
+            self.branches(&clause.filters, tail, &mut ends);
+            let here = self.code.len();
+            self.patch(&fails, here);
+        }
+        self.emit(Op::CaseClause(value));
+        let here = self.code.len();
+        self.patch(&ends, here);
+        self.vars = outer;
+    }
+
+    fn if_branches(&mut self, branches: &[program::Filter], tail: bool) {
+        let mut ends = Vec::new();
+        self.branches(branches, tail, &mut ends);
+        self.emit(Op::IfClause);
+        let here = self.code.len();
+        self.patch(&ends, here);
+    }
+
+    // the body is not in tail position, its frame has to stay for the handler
+    fn try_catch(&mut self, body: &Expr, catches: &[Catch]) {
+        // variables of the body are not bound in the catch clauses
+        let outer = self.vars.clone();
+        let start = self.emit(Op::Try(0));
+        self.expr(body);
+        self.emit(Op::EndTry);
+        let mut ends = vec![self.emit(Op::Jump(0))];
+        let here = self.code.len();
+        self.patch(&[start], here);
+        let class = self.slots;
+        let term = self.slots + 1;
+        self.slots += 2;
+        self.emit(Op::Store(term));
+        self.emit(Op::Store(class));
+        for catch in catches.iter() {
+            self.vars = outer.clone();
+            let mut fails = Vec::new();
+            self.pattern_at(&catch.class, class, &mut fails);
+            self.pattern_at(&catch.pattern, term, &mut fails);
+            self.expr(&catch.code);
+            ends.push(self.emit(Op::Jump(0)));
+            let here = self.code.len();
+            self.patch(&fails, here);
+        }
+        // none of the clauses takes it, so it goes on to the next try
+        self.emit(Op::Load(class));
+        self.emit(Op::Load(term));
+        self.emit(Op::Raise);
+        let here = self.code.len();
+        self.patch(&ends, here);
+        self.vars = outer;
+    }
+
+    // like pattern, but for the value in a slot, a failed test leaves nothing
+    // on the stack since the parts of a list go to slots as well
+    fn pattern_at(&mut self, p: &ArgBind, slot: usize, fails: &mut Vec<usize>) {
+        match p {
+            ArgBind::Identifier(n) if pattern_match::is_wildcard(n) => {}
+            ArgBind::Identifier(n) => match self.vars.get(n) {
+                Some(bound) => {
+                    let bound = *bound;
+                    self.emit(Op::Load(slot));
+                    fails.push(self.emit(Op::MatchBound(bound, 0)));
+                }
+                None => {
+                    self.vars.insert(n.clone(), slot);
+                }
+            },
+            ArgBind::Emptylist => {
+                self.emit(Op::Load(slot));
+                fails.push(self.emit(Op::MatchEq(Data::Emptylist, 0)));
+            }
+            ArgBind::ConstPattern(d) => {
+                self.emit(Op::Load(slot));
+                fails.push(self.emit(Op::MatchEq(d.clone(), 0)));
+            }
+            ArgBind::ListPattern { head, tail } => {
+                self.emit(Op::Load(slot));
+                fails.push(self.emit(Op::MatchCons(0)));
+                let h = self.slots;
+                let t = self.slots + 1;
+                self.slots += 2;
+                self.emit(Op::Store(h));
+                self.emit(Op::Store(t));
+                self.pattern_at(head, h, fails);
+                self.pattern_at(tail, t, fails);
+            }
+        }
+    }
+
+    fn call(&mut self, f: &FunctionName, args: &[Expr]) {
+        // throw(Term) and error(Term) are not calls, they raise
+        if let (FunctionName::Builtin(n), [term]) = (f, args) {
+            if function::raises(n) {
+                self.emit(Op::Const(Data::Atom(n.clone())));
+                self.expr(term);
+                self.emit(Op::Raise);
+                return;
+            }
+        }
+        args.iter().for_each(|a| self.expr(a));
+        match f {
+            FunctionName::Static(_) => match self.index.get(f) {
+                Some(i) => {
+                    let i = *i;
+                    self.emit(Op::Call(i, args.len()));
+                }
+                None => {
+                    self.emit(Op::Undefined(f.clone(), args.len()));
+                }
+            },
+            FunctionName::Dynamic(n) => match self.vars.get(n) {
+                Some(slot) => {
+                    let slot = *slot;
+                    self.emit(Op::CallDynamic(slot, args.len()));
+                }
+                None => {
+                    let op = match function::unbound_call(n.clone(), args.len()) {
+                        RuntimeErrorKind::Undefined(f, argc) => Op::Undefined(f, argc),
+                        _ => Op::Unbound(n.clone()),
+                    };
+                    self.emit(op);
+                }
+            },
+            FunctionName::Rust(_) | FunctionName::Builtin(_) => {
+                self.emit(Op::CallHost(f.clone(), args.len()));
+            }
+        }
+    }
+
+    fn numeric(&mut self, e: &NumericExpr) {
+        match e {
+            NumericExpr::Const(n) => {
+                self.emit(Op::Const(Data::Number(n.clone())));
+            }
+            NumericExpr::Identifier(n) => {
+                self.variable(n);
+                self.emit(Op::CheckNumber);
+            }
+            NumericExpr::Negate(x) => {
+                self.numeric(x);
+                self.emit(Op::Neg);
+            }
+            NumericExpr::ArrayOperator { op, v } => {
+                v.iter().for_each(|x| self.numeric(x));
+                match op {
+                    ArrayNumOp::ADD => self.emit(Op::Add(v.len())),
+                    ArrayNumOp::MUL => self.emit(Op::Mul(v.len())),
+                };
+            }
+            NumericExpr::OrderedOperator { op, left, right } => {
+                self.numeric(left);
+                self.numeric(right);
+                match op {
+                    OrderedNumOp::SUB => self.emit(Op::Sub),
+                    OrderedNumOp::DIV => self.emit(Op::Div),
+                };
+            }
+            NumericExpr::Call(f, args) => {
+                self.call(f, args);
+                self.emit(Op::CheckNumber);
+            }
+        }
+    }
+
+    fn logic(&mut self, e: &LogicExpr) {
+        match e {
+            LogicExpr::True => {
+                self.emit(Op::Const(Data::Boolean(true)));
+            }
+            LogicExpr::False => {
+                self.emit(Op::Const(Data::Boolean(false)));
+            }
+            LogicExpr::Identifier(n) => {
+                self.variable(n);
+                self.emit(Op::CheckBoolean);
+            }
+            LogicExpr::XOR(v) => {
+                v.iter().for_each(|x| self.logic(x));
+                self.emit(Op::Xor(v.len()));
+            }
+            LogicExpr::AND(v) | LogicExpr::ANDALSO(v) => self.short_circuit(v, false),
+            LogicExpr::OR(v) | LogicExpr::ORELSE(v) => self.short_circuit(v, true),
+            LogicExpr::NOT(x) => {
+                self.logic(x);
+                self.emit(Op::Not);
+            }
+            LogicExpr::EQ(v) => {
+                v.iter().for_each(|x| self.expr(x));
+                self.emit(Op::Eq(v.len()));
+            }
+            LogicExpr::NEQ(l, r) => {
+                self.expr(l);
+                self.expr(r);
+                self.emit(Op::Neq);
+            }
+            LogicExpr::Compare { op, left, right } => {
+                self.expr(left);
+                self.expr(right);
+                self.emit(Op::Compare(op.clone()));
+            }
+            LogicExpr::Call(f, args) => {
+                self.call(f, args);
+                self.emit(Op::CheckBoolean);
+            }
+        }
+    }
+
+    // && and andalso stop at the first false, || and orelse at the first true
+    fn short_circuit(&mut self, v: &[LogicExpr], stop_on: bool) {
+        let mut exits = Vec::new();
+        for x in v.iter() {
+            self.logic(x);
+            if stop_on {
+                exits.push(self.emit(Op::JumpIfTrue(0)));
+            } else {
+                exits.push(self.emit(Op::JumpIfFalse(0)));
+            }
+        }
+        self.emit(Op::Const(Data::Boolean(!stop_on)));
+        let end = self.emit(Op::Jump(0));
+        let stopped = self.code.len();
+        self.patch(&exits, stopped);
+        self.emit(Op::Const(Data::Boolean(stop_on)));
+        let after = self.code.len();
+        self.patch(&[end], after);
+    }
+}
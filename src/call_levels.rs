@@ -1,10 +1,29 @@
+use std::cell::RefCell;
+
 use crate::{
-    function, program,
-    segments::{self, Segment},
-    tokens::{self, is_operator_token, Token},
+    capability::Capabilities,
+    function, program, segments,
+    tokens::{self, Token},
 };
 
-use std::{borrow::Borrow, collections::VecDeque, task::Context};
+// what a name resolves to while a bind is lowered, a function of the program
+// or a rust:: function, which the script has to be granted
+pub struct Scope {
+    pub funs: program::Functions,
+    pub caps: Capabilities,
+    // the rust:: calls resolved that caps does not grant, for the loader to report
+    pub denied: RefCell<Vec<function::FunctionName>>,
+}
+
+impl Scope {
+    pub fn new(funs: program::Functions, caps: Capabilities) -> Scope {
+        return Scope {
+            funs: funs,
+            caps: caps,
+            denied: RefCell::new(Vec::new()),
+        };
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum CallLevel {
@@ -18,439 +37,481 @@ pub enum CallLevel {
         right: Box<CallLevel>,
         rest: Box<CallLevel>,
     },
+    // log(a) a + 1 -> Then(log(a), a + 1), first is evaluated for its effect
+    Then {
+        first: Box<CallLevel>,
+        rest: Box<CallLevel>,
+    },
     Identifier(Vec<char>),
+    // apply(pos, X) -> FunRef(pos), a function of the program named without calling it
+    FunRef(function::FunctionName),
     // [a,b,c] -> ListBuild([a,b,c,[]])
     // [a|b] -> ListBuild([a,b])
     ListBuild(Vec<CallLevel>),
     Emptylist,
+    // try a catch error:E -> b; c end
+    Try {
+        body: Box<CallLevel>,
+        catches: Vec<CatchLevel>,
+    },
+    // case a of [] / b \ [h|t] { c } / d \ end
+    Case {
+        arg: Box<CallLevel>,
+        clauses: Vec<CaseLevel>,
+    },
+    // if { a > 0 } / b \ / c \ end
+    If(Vec<FilterLevel>),
 }
 
-struct IntOpLevel {
-    pub t: tokens::Token,
-    pub v: Vec<CallLevel>,
+// Class:Pattern -> Body, a clause without a class catches throws
+#[derive(Debug, Clone)]
+pub struct CatchLevel {
+    pub class: Option<CallLevel>, // None when not written
+    pub pattern: CallLevel,
+    pub body: CallLevel,
 }
 
-//I'll need something like "parse_with_separator" for dealing with listbuilds and funargs
-
-pub fn segments_to_call_level(
-    mut s: Vec<segments::Segment>,
-    context: program::Functions,
-) -> CallLevel {
-    match s.len() {
-        1 => segment_to_call_level(s[0].clone(), context),
-        i if i > 1 => match (s[0].clone(), s[1].clone()) {
-            (
-                segments::Segment::UnMatched(mut v),
-                segments::Segment::Clause {
-                    head: tokens::Token::LeftP,
-                    body,
-                    ..
-                },
-            ) => {
-                match v.pop() {
-                    // 1 + f(a,b) etc
-                    Some(tokens::Token::Identifier(fname)) => {
-                        let (fun, mut rest) =
-                            make_fun(v, fname.clone(), body.clone(), context.clone());
-                        match rest.pop() {
-                            Some(optoken) if is_operator_token(optoken.clone()) => {
-                                let IntOpLevel {
-                                    t: optoken,
-                                    v: mut rest,
-                                } = build_level_int(rest, optoken, vec![]);
-                                rest.push(fun);
-                                return CallLevel::OpLevel(optoken, rest);
-                            }
-                            None => {
-                                return fun;
-                            }
-                            Some(tokens::Token::Assign) => {
-                                let left = tokens_to_call_level(rest);
-                                let rest =
-                                    segments_to_call_level(s[2..s.len()].to_vec(), context.clone());
-                                return CallLevel::Assign {
-                                    left: Box::new(left),
-                                    right: Box::new(fun),
-                                    rest: Box::new(rest),
-                                };
-                            }
-                            token => {
-                                println!("SEG LEN CONFUSION{:#?}{:#?}{:#?}\n", s, s.len(), token);
-                                unimplemented!()
-                            }
-                        }
-                    }
-                    token => {
-                        println!("SEG LEN CONFUSION{:#?}{:#?}{:#?}\n", s, s.len(), token);
-                        unimplemented!()
-                    }
-                }
-            }
-            (
-                segments::Segment::Clause {
-                    head: tokens::Token::LeftP,
-                    body,
-                    ..
-                },
-                segments::Segment::UnMatched(mut tv),
-            ) => match tv.get(0) {
-                Some(optoken) if is_operator_token(optoken.clone()) => {
-                    let firstsub = segments_to_call_level(body, context);
-                    return int_oplevel_to_oplevel(build_level_int(
-                        tv[1..tv.len()].to_vec(),
-                        optoken.clone(),
-                        vec![firstsub],
-                    ));
-                }
-                token => {
-                    println!("SEG LEN CONFUSION{:#?}{:#?}{:#?}\n", s, s.len(), token);
-                    unimplemented!()
-                }
-            },
-            a => {
-                println!("SEG LEN CONFUSION{:#?}{:#?}\n", s, a);
-                unimplemented!()
-            }
-        },
-        _ => {
-            println!("SEG LEN CONFUSION{:#?}{:#?}\n", s, s.len());
-            unimplemented!()
-        }
+// a pattern and its filters, tried in order like the filters of a bind
+#[derive(Debug, Clone)]
+pub struct CaseLevel {
+    pub pattern: CallLevel,
+    pub filters: Vec<FilterLevel>,
+}
+
+// { guard } / body \, the guard is None when left out
+#[derive(Debug, Clone)]
+pub struct FilterLevel {
+    pub guard: Option<CallLevel>,
+    pub body: CallLevel,
+}
+
+pub fn segments_to_call_level(s: Vec<segments::Segment>, context: &Scope) -> CallLevel {
+    let tv = segments::flatten(s);
+    return body_level(&tv, context);
+}
+
+// (a, [h|t], -1) of a function head
+pub fn segments_to_call_args(s: Vec<segments::Segment>, context: &Scope) -> Vec<CallLevel> {
+    let tv = segments::flatten(s);
+    if tv.is_empty() {
+        return Vec::new();
+    }
+    let (args, rest) = separated(&tv, context);
+    if !rest.is_empty() {
+        println!("TRAILING TOKENS{:#?}{:#?}\n", args, rest);
+        unimplemented!()
     }
+    return args;
 }
 
-fn segment_to_call_level(seg: segments::Segment, context: program::Functions) -> CallLevel {
-    match seg.clone() {
-        segments::Segment::UnMatched(tv) => return tokens_to_call_level(tv.clone()),
-        segments::Segment::Clause {
-            head: Token::LeftB,
-            body,
-            ..
-        } => listbuild(body, context),
-        _ => {
-            println!("ALONE3{:#?}\n", seg);
-            unimplemented!()
-        }
+// a = f(1) b = g(a) log(b) a + b
+// any number of assignments and statements followed by a single expression
+fn body_level(tv: &[Token], context: &Scope) -> CallLevel {
+    let (body, rest) = sequence(tv, context);
+    if !rest.is_empty() {
+        println!("TRAILING TOKENS{:#?}{:#?}\n", body, rest);
+        unimplemented!()
     }
+    return body;
 }
 
-fn tokens_to_call_level(tv: Vec<Token>) -> CallLevel {
-    match tv.len() {
-        1 => match tv[0].clone() {
-            Token::Identifier(l) => return CallLevel::Identifier(l),
-            _ => {
-                println!("1{:#?}\n", tv);
-                unimplemented!()
-            }
-        },
-        I if I > 2 => match (tv[0].clone(), tv[1].clone()) {
-            (Token::Identifier(l), token) if is_operator_token(token.clone()) => {
-                let mut first = Vec::new();
-                first.push(CallLevel::Identifier(l));
-                return int_oplevel_to_oplevel(build_level_int(
-                    tv[2..tv.len()].to_vec(),
-                    token,
-                    first,
-                ));
-            }
-            _ => {
-                println!("3{:#?}\n", tv);
-                unimplemented!()
-            }
-        },
-        _ => {
-            println!("{:#?}\n", tv);
-            unimplemented!()
+// a body that stops at the first token that can not continue it,
+// the catch of a try, the end of a catch clause or the \ of a case clause
+fn sequence<'a>(tv: &'a [Token], context: &Scope) -> (CallLevel, &'a [Token]) {
+    let (first, rest) = op_level(tv, 0, context);
+    match rest.split_first() {
+        Some((Token::Assign, rest)) => {
+            let (right, rest) = op_level(rest, 0, context);
+            // a = f(1), b = g(a) is allowed as well
+            let rest = match rest.split_first() {
+                Some((Token::ArgTerm, rest)) => rest,
+                _ => rest,
+            };
+            let (rest, after) = sequence(rest, context);
+            let assign = CallLevel::Assign {
+                left: Box::new(first),
+                right: Box::new(right),
+                rest: Box::new(rest),
+            };
+            return (assign, after);
         }
+        _ if starts_statement(rest) => {
+            let rest = match rest.split_first() {
+                Some((Token::ArgTerm, rest)) => rest,
+                _ => rest,
+            };
+            let (rest, after) = sequence(rest, context);
+            let then = CallLevel::Then {
+                first: Box::new(first),
+                rest: Box::new(rest),
+            };
+            return (then, after);
+        }
+        _ => (first, rest),
     }
 }
 
-// 1 + 2 + 3 + 4 ... etc
-fn build_level_int(tv: Vec<Token>, leveltoken: Token, mut done: Vec<CallLevel>) -> IntOpLevel {
-    assert!(
-        tokens::is_operator_token(leveltoken.clone()),
-        "{:#?}{:#?}{:#?}",
-        tv,
-        leveltoken,
-        done
-    );
-    match tv.len() {
-        1 => match tv[0].clone() {
-            Token::Identifier(l) => {
-                done.push(CallLevel::Identifier(l));
-                return IntOpLevel {
-                    t: leveltoken,
-                    v: done,
-                };
-            }
-            _ => {
-                println!("{:#?}\n", tv);
-                unimplemented!()
-            }
-        },
-        I if I > 2 => match (tv[0].clone(), tv[1].clone()) {
-            (Token::Identifier(l), leveltoken) if is_operator_token(leveltoken.clone()) => {
-                done.push(CallLevel::Identifier(l));
-                build_level_int(tv[2..tv.len()].to_vec(), leveltoken, done)
+// after a whole expression, a `,` or anything an expression can start with
+// means the expression was a statement and more of the body follows
+fn starts_statement(tv: &[Token]) -> bool {
+    match tv.first() {
+        Some(Token::ArgTerm)
+        | Some(Token::Identifier(_))
+        | Some(Token::LeftB)
+        | Some(Token::LeftP)
+        | Some(Token::NOT)
+        | Some(Token::SUB)
+        | Some(Token::Try)
+        | Some(Token::Case)
+        | Some(Token::If) => true,
+        _ => false,
+    }
+}
+
+// gets tokens *after* try
+fn try_level<'a>(tv: &'a [Token], context: &Scope) -> (CallLevel, &'a [Token]) {
+    let (tried, rest) = sequence(tv, context);
+    let mut rest = expect(Token::Catch, rest);
+    let mut catches = Vec::new();
+    loop {
+        let (class, after) = match rest {
+            [Token::Identifier(class), Token::FunStart, after @ ..] => {
+                (Some(CallLevel::Identifier(class.clone())), after)
             }
+            _ => (None, rest),
+        };
+        let (pattern, after) = op_level(after, 0, context);
+        let (body, after) = sequence(expect(Token::Arrow, after), context);
+        catches.push(CatchLevel {
+            class: class,
+            pattern: pattern,
+            body: body,
+        });
+        match after.split_first() {
+            Some((Token::Semicolon, after)) => rest = after,
             _ => {
-                println!("{:#?}\n", tv);
-                unimplemented!()
+                let level = CallLevel::Try {
+                    body: Box::new(tried),
+                    catches: catches,
+                };
+                return (level, expect(Token::END, after));
             }
-        },
-        _ => {
-            println!("{:#?}\n", tv);
-            unimplemented!()
         }
     }
 }
 
-fn listbuild(body: Vec<Segment>, context: program::Functions) -> CallLevel {
-    match (body.get(0), body.get(1), body.get(2)) {
-        (Some(segments::Segment::UnMatched(tv)), None, None) => {
-            return tokens_to_list(tv.clone());
-        }
-        (None, None, None) => {
-            return CallLevel::ListBuild(Vec::new());
-        }
-        (
-            Some(segments::Segment::UnMatched(v)),
-            Some(Segment::Clause {
-                head: tokens::Token::LeftP,
-                body,
-                ..
-            }),
-            None,
-        ) => {
-            let mut tv = v.clone();
-            match tv.pop() {
-                // 1 + f(a,b) etc
-                Some(tokens::Token::Identifier(fname)) => {
-                    let (fun, mut rest) =
-                        make_fun(tv.to_vec(), fname.clone(), body.clone(), context.clone());
-                    match rest.pop() {
-                        Some(optoken) if is_operator_token(optoken.clone()) => {
-                            // [a|b + f(1)]
-                            let IntOpLevel {
-                                t: optoken,
-                                v: mut rest,
-                            } = build_level_int(rest, optoken, vec![]);
-                            rest.push(fun);
-                            println!("SEG LEN CONFUSION{:#?}{:#?}\n", tv, tv.len());
-                            unimplemented!();
-                            return CallLevel::OpLevel(optoken, rest);
-                        }
-                        Some(tokens::Token::Pipe) => {
-                            println!("SEG LEN CONFUSION{:#?}{:#?}\n", tv, tv.len());
-                            unimplemented!()
-                        }
-                        Some(tokens::Token::ArgTerm) => {
-                            println!("SEG LEN CONFUSION{:#?}{:#?}\n", tv, tv.len());
-                            unimplemented!()
-                        }
-                        token => {
-                            println!("SEG LEN CONFUSION{:#?}{:#?}{:#?}\n", tv, tv.len(), token);
-                            unimplemented!()
-                        }
-                    }
-                }
-                token => {
-                    println!("SEG LEN CONFUSION{:#?}{:#?}{:#?}\n", tv, tv.len(), token);
-                    unimplemented!()
-                }
-            }
+// gets tokens *after* case
+fn case_level<'a>(tv: &'a [Token], context: &Scope) -> (CallLevel, &'a [Token]) {
+    let (arg, rest) = op_level(tv, 0, context);
+    let mut rest = expect(Token::Of, rest);
+    let mut clauses = Vec::new();
+    loop {
+        if let Some((Token::END, after)) = rest.split_first() {
+            let level = CallLevel::Case {
+                arg: Box::new(arg),
+                clauses: clauses,
+            };
+            return (level, after);
         }
-        (a, b, c) => {
-            println!("LISTBUILD{:#?}{:#?}{:#?}{:#?}\n", body, a, b, c);
+        let (pattern, after) = op_level(rest, 0, context);
+        let (filters, after) = filter_levels(after, context);
+        if filters.is_empty() {
+            println!("CASE CLAUSE WITHOUT BODY{:#?}{:#?}\n", pattern, after);
             unimplemented!()
         }
+        clauses.push(CaseLevel {
+            pattern: pattern,
+            filters: filters,
+        });
+        rest = after;
     }
 }
 
-// gets tokens *between* the brackets as arg
-fn tokens_to_list(mut tv: Vec<Token>) -> CallLevel {
-    if tv.len() == 3 {
-        let last = tv.pop().unwrap();
-        let middle = tv.pop().unwrap();
-        let first = tv.pop().unwrap();
-        match (first, middle, last) {
-            (Token::Identifier(f), Token::ArgTerm, Token::Identifier(s)) => {
-                let first = CallLevel::Identifier(f);
-                let second = CallLevel::Identifier(s);
-                let mut items = Vec::new();
-                items.push(first);
-                items.push(second);
-                items.push(CallLevel::Emptylist);
-                let list = CallLevel::ListBuild(items);
-                return list;
-            }
-            (Token::Identifier(f), Token::Pipe, Token::Identifier(s)) => {
-                let first = CallLevel::Identifier(f);
-                let mut items = Vec::new();
-                let second = CallLevel::Identifier(s);
-                items.push(first);
-                items.push(second);
-                let list = CallLevel::ListBuild(items);
-                return list;
+// { a > 0 } / b \ / c \, as many filters as follow
+fn filter_levels<'a>(tv: &'a [Token], context: &Scope) -> (Vec<FilterLevel>, &'a [Token]) {
+    let mut res = Vec::new();
+    let mut rest = tv;
+    loop {
+        let (guard, after) = match rest.split_first() {
+            Some((Token::LeftW, after)) => {
+                let (guard, after) = op_level(after, 0, context);
+                (Some(guard), expect(Token::RightW, after))
             }
-            a => {
-                println!("tokens_to_list{:#?}{:#?}\n", a, tv);
-                unimplemented!()
-            }
-        }
+            _ => (None, rest),
+        };
+        let after = match (after.split_first(), &guard) {
+            (Some((Token::CodeStart, after)), _) => after,
+            (_, None) => return (res, rest),
+            (_, Some(_)) => expect(Token::CodeStart, after),
+        };
+        let (body, after) = sequence(after, context);
+        res.push(FilterLevel {
+            guard: guard,
+            body: body,
+        });
+        rest = expect(Token::CodeEnd, after);
     }
-    println!("tokens_to_list{:#?}\n", tv);
-    unimplemented!()
 }
 
-fn make_fun(
-    mut v: Vec<Token>,
-    fname: Vec<char>,
-    args: Vec<segments::Segment>,
-    context: program::Functions,
-) -> (CallLevel, Vec<Token>) {
-    let orgrest = v.clone();
-    let args = segments_to_call_args(args, context.clone());
-    match (v.pop(), v.pop()) {
-        (Some(Token::Qualify), Some(Token::Identifier(id))) => {
-            match (id.get(0), id.get(1), id.get(2), id.get(3), id.get(4)) {
-                (Some('r'), Some('u'), Some('s'), Some('t'), None) => {
-                    return (
-                        CallLevel::Call(function::FunctionName::Rust(fname), args),
-                        v,
-                    );
-                }
-                token => {
-                    println!(
-                        "SEG LEN CONFUSION{:#?}{:#?}{:#?}\n",
-                        orgrest,
-                        orgrest.len(),
-                        token
-                    );
-                    unimplemented!()
-                }
+// binding strength of infix operators, higher binds tighter
+// && is below == so `A =/= 0 && B =/= 0` reads like in notes.md
+pub fn precedence(t: &Token) -> Option<u8> {
+    match t {
+        Token::ORELSE => Some(1),
+        Token::ANDALSO => Some(2),
+        Token::OR | Token::XOR => Some(3),
+        Token::AND => Some(4),
+        Token::Eq | Token::NEQ | Token::LT | Token::GT | Token::LTE | Token::GTE => Some(5),
+        Token::Add | Token::SUB => Some(6),
+        Token::MUL => Some(7),
+        _ => None,
+    }
+}
+
+// operators that collect a chain into one level, a + b + c -> level(+, [a,b,c])
+pub fn is_chain_token(t: &Token) -> bool {
+    match t {
+        Token::Add
+        | Token::MUL
+        | Token::AND
+        | Token::OR
+        | Token::XOR
+        | Token::ANDALSO
+        | Token::ORELSE
+        | Token::Eq => true,
+        _ => false,
+    }
+}
+
+// precedence climbing, everything binding at least as tight as min_prec
+fn op_level<'a>(tv: &'a [Token], min_prec: u8, context: &Scope) -> (CallLevel, &'a [Token]) {
+    let (mut left, mut rest) = single_level(tv, context);
+    // a parenthesised left side must not be merged into the chain
+    let mut chained = false;
+    loop {
+        let (optoken, after) = match rest.split_first() {
+            Some((t, after)) if tokens::is_operator_token(t.clone()) => (t.clone(), after),
+            _ => return (left, rest),
+        };
+        let prec = match precedence(&optoken) {
+            Some(p) if p >= min_prec => p,
+            _ => return (left, rest),
+        };
+        let (right, after) = op_level(after, prec + 1, context);
+        left = match left {
+            CallLevel::OpLevel(t, mut v) if chained && t == optoken && is_chain_token(&t) => {
+                v.push(right);
+                CallLevel::OpLevel(t, v)
             }
+            l => CallLevel::OpLevel(optoken, vec![l, right]),
+        };
+        chained = true;
+        rest = after;
+    }
+}
+
+// a single operand, f(..), rust::f(..), [..], (..), not .. or an identifier
+fn single_level<'a>(tv: &'a [Token], context: &Scope) -> (CallLevel, &'a [Token]) {
+    match tv {
+        // not a -> level(not, [a])
+        [Token::NOT, rest @ ..] => {
+            let (operand, rest) = single_level(rest, context);
+            return (CallLevel::OpLevel(Token::NOT, vec![operand]), rest);
+        }
+        // -5 is a literal, -a -> level(-, [a])
+        [Token::SUB, Token::Identifier(n), rest @ ..] if is_number(n) => {
+            let mut literal = vec!['-'];
+            literal.extend(n);
+            return (CallLevel::Identifier(literal), rest);
+        }
+        [Token::SUB, rest @ ..] => {
+            let (operand, rest) = single_level(rest, context);
+            return (CallLevel::OpLevel(Token::SUB, vec![operand]), rest);
+        }
+        [Token::LeftP, rest @ ..] => {
+            let (inner, rest) = op_level(rest, 0, context);
+            return (inner, expect(Token::RightP, rest));
         }
-        token => {
-            let call = if context.contains_key(&fname) {
+        [Token::LeftB, rest @ ..] => listbuild(rest, context),
+        [Token::Try, rest @ ..] => try_level(rest, context),
+        [Token::Case, rest @ ..] => case_level(rest, context),
+        [Token::If, rest @ ..] => {
+            let (branches, rest) = filter_levels(rest, context);
+            return (CallLevel::If(branches), expect(Token::END, rest));
+        }
+        [Token::Identifier(module), Token::Qualify, Token::Identifier(fname), Token::LeftP, rest @ ..] =>
+        {
+            let (args, rest) = call_args(rest, context);
+            return (
+                CallLevel::Call(make_fun(module, fname, context), args),
+                rest,
+            );
+        }
+        [Token::Identifier(fname), Token::LeftP, rest @ ..] => {
+            let (args, rest) = call_args(rest, context);
+            let fname = fname.clone();
+            let overload = program::arity_name(&fname, args.len());
+            let call = if context.funs.contains_key(&fname) {
                 CallLevel::Call(function::FunctionName::Static(fname), args)
+            } else if context.funs.contains_key(&overload) {
+                CallLevel::Call(function::FunctionName::Static(overload), args)
+            } else if function::is_builtin(&fname) {
+                CallLevel::Call(function::FunctionName::Builtin(fname), args)
             } else {
                 CallLevel::Call(function::FunctionName::Dynamic(fname), args)
             };
-            return (call, orgrest);
+            return (call, rest);
+        }
+        [Token::Identifier(l), rest @ ..] if context.funs.contains_key(l) => {
+            let f = function::FunctionName::Static(l.clone());
+            return (CallLevel::FunRef(f), rest);
+        }
+        [Token::Identifier(l), rest @ ..] => (CallLevel::Identifier(l.clone()), rest),
+        _ => {
+            println!("SEG LEN CONFUSION{:#?}{:#?}\n", tv, tv.len());
+            unimplemented!()
         }
-    };
+    }
 }
 
-fn segments_to_call_args(
-    mut seg: Vec<segments::Segment>,
-    context: program::Functions,
-) -> Vec<CallLevel> {
-    if seg.len() == 0 {
-        return Vec::new();
+// gets tokens *after* the opening bracket
+// [] -> Emptylist
+// [a, b] -> ListBuild([a, b, []])
+// [a, b | c] -> ListBuild([a, b, c])
+fn listbuild<'a>(tv: &'a [Token], context: &Scope) -> (CallLevel, &'a [Token]) {
+    if let Some((Token::RightB, rest)) = tv.split_first() {
+        return (CallLevel::Emptylist, rest);
     }
-    if seg.len() == 1 {
-        match seg[0].clone() {
-            // a, b, c etc
-            segments::Segment::UnMatched(tv) => {
-                return tokens_to_call_args(tv);
-            }
-            // [a, b], [a|b], etc
-            a => {
-                let mut res = Vec::new();
-                res.push(segment_to_call_level(a, context));
-                return res;
-            }
+    let (mut items, rest) = separated(tv, context);
+    match rest.split_first() {
+        Some((Token::Pipe, rest)) => {
+            let (tail, rest) = op_level(rest, 0, context);
+            items.push(tail);
+            return (CallLevel::ListBuild(items), expect(Token::RightB, rest));
+        }
+        _ => {
+            items.push(CallLevel::Emptylist);
+            return (CallLevel::ListBuild(items), expect(Token::RightB, rest));
         }
     }
-    if seg.len() == 2 {
-        match (seg[0].clone(), seg[1].clone()) {
-            (segments::Segment::UnMatched(tv), tailseg) => {
-                if let Some((tokens::Token::ArgTerm, tail)) = tv.split_last() {
-                    let mut res = tokens_to_call_args(tail.to_vec());
-                    res.push(segment_to_call_level(tailseg, context));
-                    return res;
-                } else {
-                    println!("missing comma {:#?}\n", seg);
-                    unimplemented!()
-                }
-            }
-            a => {
-                println!("2args{:#?}{:#?}\n", seg, a);
-                unimplemented!()
-            }
+}
+
+// gets tokens *after* the opening paren, (a, b, c)
+fn call_args<'a>(tv: &'a [Token], context: &Scope) -> (Vec<CallLevel>, &'a [Token]) {
+    if let Some((Token::RightP, rest)) = tv.split_first() {
+        return (Vec::new(), rest);
+    }
+    let (args, rest) = separated(tv, context);
+    return (args, expect(Token::RightP, rest));
+}
+
+// a, b, c
+fn separated<'a>(tv: &'a [Token], context: &Scope) -> (Vec<CallLevel>, &'a [Token]) {
+    let mut res = Vec::new();
+    let mut rest = tv;
+    loop {
+        let (item, after) = op_level(rest, 0, context);
+        res.push(item);
+        match after.split_first() {
+            Some((Token::ArgTerm, after)) => rest = after,
+            _ => return (res, after),
         }
     }
-    // at least 3 arg function
-    if seg.len() >= 3 {
-        let last = seg.pop();
-        let secondlast = seg.pop();
-        match (secondlast, last) {
-            (Some(segments::Segment::UnMatched(mut tv)), Some(tailseg)) => {
-                let lasttoken = tv.pop();
-                match (tv.split_first(), lasttoken) {
-                    (Some((Token::ArgTerm, middletokens)), Some(Token::ArgTerm)) => {
-                        let mut res = segments_to_call_args(seg, context.clone());
-                        let tailarg = segment_to_call_level(tailseg, context.clone());
-                        let mut middleargs = tokens_to_call_args(middletokens.to_vec());
-                        res.append(&mut middleargs);
-                        res.push(tailarg);
-                        return res;
-                    }
-                    a => {
-                        println!("3 int args{:#?}{:#?}\n", seg, a);
-                        unimplemented!()
-                    }
-                }
-            }
-            a => {
-                println!("3args{:#?}{:#?}\n", seg, a);
-                unimplemented!()
-            }
+}
+
+fn is_number(chars: &[char]) -> bool {
+    return !chars.is_empty() && chars.iter().all(|c| c.is_ascii_digit());
+}
+
+fn expect(t: Token, tv: &[Token]) -> &[Token] {
+    match tv.split_first() {
+        Some((head, rest)) if *head == t => rest,
+        _ => {
+            println!("EXPECTED{:#?}{:#?}\n", t, tv);
+            unimplemented!()
         }
     }
-    println!("args{:#?}\n", seg);
-    unimplemented!()
 }
 
-fn tokens_to_call_args(mut tv: Vec<Token>) -> Vec<CallLevel> {
-    if tv.len() == 1 {
-        match tv[0].clone() {
-            tokens::Token::Identifier(l) => {
-                let mut res = Vec::new();
-                res.push(CallLevel::Identifier(l));
-                return res;
-            }
-            _ => {
-                println!("args 1{:#?}\n", tv);
-                unimplemented!()
+fn make_fun(module: &[char], fname: &[char], context: &Scope) -> function::FunctionName {
+    match module {
+        ['r', 'u', 's', 't'] => {
+            let f = function::FunctionName::Rust(fname.to_vec());
+            // resolved all the same, the loader reports it with the line of the bind
+            if !context.caps.allows(&f) {
+                context.denied.borrow_mut().push(f.clone());
             }
+            return f;
+        }
+        _ => {
+            println!("UNKNOWN MODULE{:#?}{:#?}\n", module, fname);
+            unimplemented!()
         }
     }
-    if tv.len() >= 3 {
-        let fulltv = tv.clone();
-        let last = tv.pop();
-        let secondlast = tv.pop();
-        match (last, secondlast) {
-            (Some(tokens::Token::Identifier(l)), Some(tokens::Token::ArgTerm)) => {
-                let mut res = tokens_to_call_args(tv);
-                res.push(CallLevel::Identifier(l));
-                return res;
-            }
-            // cases like add(1+1) where call-args
-            // are not single tokens
-            // TODO handle add(a, 1+1, a)
-            _a => {
-                let mut res = Vec::new();
-                res.push(tokens_to_call_level(fulltv));
-                return res;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::{self, Data};
+    use crate::numeric_expr::NumericData;
+    use std::{collections::HashMap, sync::Arc};
+
+    fn level(src: &str) -> CallLevel {
+        let scope = Scope::new(HashMap::new(), Capabilities::All);
+        let s = segments::matched_terminators(tokens::string_to(src.to_string()));
+        return segments_to_call_level(s, &scope);
+    }
+
+    fn name(l: &CallLevel) -> String {
+        match l {
+            CallLevel::Identifier(n) => n.iter().collect(),
+            other => panic!("not an identifier: {:#?}", other),
+        }
+    }
+
+    #[test]
+    fn a_minus_before_a_number_is_a_literal() {
+        assert_eq!(name(&level("-5")), "-5");
+    }
+
+    #[test]
+    fn a_minus_before_anything_else_negates() {
+        match level("A * -B") {
+            CallLevel::OpLevel(Token::MUL, v) => match v.as_slice() {
+                [a, CallLevel::OpLevel(Token::SUB, neg)] if neg.len() == 1 => {
+                    assert_eq!(name(a), "A");
+                    assert_eq!(name(&neg[0]), "B");
+                }
+                other => panic!("not A * -B: {:#?}", other),
+            },
+            other => panic!("not a product: {:#?}", other),
+        }
+    }
+
+    #[test]
+    fn a_minus_between_operands_subtracts() {
+        match level("3 -1") {
+            CallLevel::OpLevel(Token::SUB, v) => {
+                let names: Vec<String> = v.iter().map(name).collect();
+                assert_eq!(names, vec!["3", "1"]);
             }
+            other => panic!("not a difference: {:#?}", other),
         }
     }
-    println!("args len{:#?}\n", tv);
-    unimplemented!()
-}
 
-fn int_oplevel_to_oplevel(IntOpLevel { t, v }: IntOpLevel) -> CallLevel {
-    return CallLevel::OpLevel(t, v);
+    #[test]
+    fn negative_numbers_run_and_match() {
+        let src = "sign:\n    (-1)\n    / minus \\\n    (_)\n    / other \\\nend\n\
+                   main:\n    ()\n    / A = 4 [sign(-1), sign(1), 2 * -A, -(A - 6)] \\\nend\n";
+        let int = |i| Data::Number(NumericData::Int(i));
+        let atom = |s: &str| Data::Atom(s.chars().collect());
+        let expected = vec![atom("minus"), atom("other"), int(-8), int(2)]
+            .into_iter()
+            .rev()
+            .fold(Data::Emptylist, |tail, d| {
+                Data::List(Arc::new(d), Arc::new(tail))
+            });
+        assert_eq!(eval::run_main(&program::from_source(src)), Ok(expected));
+    }
 }
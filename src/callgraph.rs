@@ -0,0 +1,339 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    eval,
+    expr::{self, Expr},
+    function::FunctionName,
+    logic_expr, program,
+};
+
+// Who calls whom, from the Call nodes of every guard and body.
+// Calls through variables are left out since their target is only known at runtime.
+//
+// A cycle is a strongly connected group of functions that call each other.
+// It has a base when at least one filter of its functions, or a case or if branch
+// in its body, calls nothing in the group, without one every call into the group
+// recurses forever.
+
+#[derive(Debug, Clone)]
+pub struct CallGraph {
+    pub nodes: Vec<FunctionName>,
+    pub edges: Vec<(FunctionName, FunctionName)>,
+    pub cycles: Vec<Cycle>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cycle {
+    pub functions: Vec<FunctionName>,
+    pub has_base: bool,
+}
+
+pub fn build(code: &eval::Program) -> CallGraph {
+    let mut nodes: Vec<FunctionName> = code.keys().cloned().collect();
+    let mut edges = Vec::new();
+    for (name, fun) in code.iter() {
+        for callee in program::fun_calls(fun) {
+            if matches!(callee, FunctionName::Dynamic(_)) {
+                continue;
+            }
+            if !nodes.contains(&callee) {
+                nodes.push(callee.clone());
+            }
+            edges.push((name.clone(), callee));
+        }
+    }
+    nodes.sort();
+    edges.sort();
+    edges.dedup();
+    let cycles = components(&nodes, &edges)
+        .into_iter()
+        .filter(|group| group.len() > 1 || edges.contains(&(group[0].clone(), group[0].clone())))
+        .map(|group| Cycle {
+            has_base: has_base(code, &group),
+            functions: group,
+        })
+        .collect();
+    return CallGraph {
+        nodes: nodes,
+        edges: edges,
+        cycles: cycles,
+    };
+}
+
+// a filter whose guard calls nothing in the group and whose body has a way
+// through that calls nothing in the group ends the recursion
+fn has_base(code: &eval::Program, group: &[FunctionName]) -> bool {
+    return group
+        .iter()
+        .filter_map(|name| code.get(name))
+        .any(|fun| fun.binds.iter().any(|bind| leaves(&bind.filters, group)));
+}
+
+fn leaves(filters: &[program::Filter], group: &[FunctionName]) -> bool {
+    return filters.iter().any(|filter| {
+        let mut calls = Vec::new();
+        logic_expr::calls(&filter.head, &mut calls);
+        !calls.iter().any(|c| group.contains(c)) && returns(&filter.code, group)
+    });
+}
+
+// some way through e calls nothing in the group, a case or an if
+// only needs one such branch
+fn returns(e: &Expr, group: &[FunctionName]) -> bool {
+    match e {
+        Expr::Constant(_) | Expr::Identifier(_) => true,
+        Expr::Call(f, args) => !group.contains(f) && args.iter().all(|a| returns(a, group)),
+        Expr::ListBuild(h, t) => returns(h, group) && returns(t, group),
+        Expr::Assign { arg, rest, .. } => returns(arg, group) && returns(rest, group),
+        Expr::Try { body, catches } => {
+            returns(body, group) || catches.iter().any(|c| returns(&c.code, group))
+        }
+        Expr::Case { arg, clauses } => {
+            returns(arg, group) && clauses.iter().any(|c| leaves(&c.filters, group))
+        }
+        Expr::If(branches) => leaves(branches, group),
+        Expr::NumericExpr(_) | Expr::LogicExpr(_) => {
+            let mut calls = Vec::new();
+            expr::calls(e, &mut calls);
+            !calls.iter().any(|c| group.contains(c))
+        }
+    }
+}
+
+// strongly connected components, Tarjan's algorithm,
+// a group comes after every group it calls into
+pub fn components(
+    nodes: &[FunctionName],
+    edges: &[(FunctionName, FunctionName)],
+) -> Vec<Vec<FunctionName>> {
+    let mut state = Tarjan {
+        index: HashMap::new(),
+        low: HashMap::new(),
+        stack: Vec::new(),
+        on_stack: HashSet::new(),
+        next: 0,
+        res: Vec::new(),
+    };
+    for n in nodes.iter() {
+        if !state.index.contains_key(n) {
+            connect(n, edges, &mut state);
+        }
+    }
+    return state.res;
+}
+
+struct Tarjan {
+    index: HashMap<FunctionName, usize>,
+    low: HashMap<FunctionName, usize>,
+    stack: Vec<FunctionName>,
+    on_stack: HashSet<FunctionName>,
+    next: usize,
+    res: Vec<Vec<FunctionName>>,
+}
+
+fn connect(n: &FunctionName, edges: &[(FunctionName, FunctionName)], s: &mut Tarjan) {
+    s.index.insert(n.clone(), s.next);
+    s.low.insert(n.clone(), s.next);
+    s.next += 1;
+    s.stack.push(n.clone());
+    s.on_stack.insert(n.clone());
+    for (_, m) in edges.iter().filter(|(from, _)| from == n) {
+        if !s.index.contains_key(m) {
+            connect(m, edges, s);
+            let low = s.low[n].min(s.low[m]);
+            s.low.insert(n.clone(), low);
+        } else if s.on_stack.contains(m) {
+            let low = s.low[n].min(s.index[m]);
+            s.low.insert(n.clone(), low);
+        }
+    }
+    if s.low[n] == s.index[n] {
+        let mut group = Vec::new();
+        while let Some(m) = s.stack.pop() {
+            s.on_stack.remove(&m);
+            let done = m == *n;
+            group.push(m);
+            if done {
+                break;
+            }
+        }
+        group.sort();
+        s.res.push(group);
+    }
+}
+
+pub fn to_dot(g: &CallGraph) -> String {
+    let looping: HashSet<&FunctionName> = g
+        .cycles
+        .iter()
+        .filter(|c| !c.has_base)
+        .flat_map(|c| c.functions.iter())
+        .collect();
+    let mut out = String::from("digraph calls {\n");
+    for n in g.nodes.iter() {
+        let mut attrs = Vec::new();
+        if !matches!(n, FunctionName::Static(_)) {
+            attrs.push("shape=box");
+        }
+        if looping.contains(n) {
+            attrs.push("color=red");
+        }
+        if attrs.is_empty() {
+            out.push_str(&format!("    \"{}\";\n", node_name(n)));
+        } else {
+            out.push_str(&format!(
+                "    \"{}\" [{}];\n",
+                node_name(n),
+                attrs.join(", ")
+            ));
+        }
+    }
+    for (from, to) in g.edges.iter() {
+        let (from_name, to_name) = (node_name(from), node_name(to));
+        if looping.contains(from) && looping.contains(to) {
+            out.push_str(&format!(
+                "    \"{}\" -> \"{}\" [color=red];\n",
+                from_name, to_name
+            ));
+        } else {
+            out.push_str(&format!("    \"{}\" -> \"{}\";\n", from_name, to_name));
+        }
+    }
+    out.push_str("}\n");
+    return out;
+}
+
+pub fn to_json(g: &CallGraph) -> String {
+    let nodes: Vec<String> = g.nodes.iter().map(json_name).collect();
+    let edges: Vec<String> = g
+        .edges
+        .iter()
+        .map(|(from, to)| format!("[{}, {}]", json_name(from), json_name(to)))
+        .collect();
+    let cycles: Vec<String> = g
+        .cycles
+        .iter()
+        .map(|c| {
+            let names: Vec<String> = c.functions.iter().map(json_name).collect();
+            format!(
+                "{{\"functions\": [{}], \"has_base\": {}}}",
+                names.join(", "),
+                c.has_base
+            )
+        })
+        .collect();
+    return format!(
+        "{{\n  \"nodes\": [{}],\n  \"edges\": [{}],\n  \"cycles\": [{}]\n}}\n",
+        nodes.join(", "),
+        edges.join(", "),
+        cycles.join(", ")
+    );
+}
+
+// names only hold identifier characters, so quoting is enough
+fn json_name(n: &FunctionName) -> String {
+    return format!("\"{}\"", node_name(n));
+}
+
+// functions of the program by their key, so f/1 and f/2 of a .erl file are two nodes
+fn node_name(n: &FunctionName) -> String {
+    match n {
+        FunctionName::Static(key) => return key.iter().collect(),
+        _ => return n.to_string(),
+    }
+}
+
+pub fn describe_cycle(c: &Cycle) -> String {
+    let names: Vec<String> = c.functions.iter().map(node_name).collect();
+    if names.len() == 1 {
+        return format!(
+            "{} calls itself in every clause and never returns",
+            names[0]
+        );
+    }
+    return format!(
+        "{} call each other in every clause and never return",
+        names.join(", ")
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bases(src: &str) -> Vec<bool> {
+        return build(&program::from_source(src))
+            .cycles
+            .iter()
+            .map(|c| c.has_base)
+            .collect();
+    }
+
+    #[test]
+    fn a_branch_that_returns_is_a_base() {
+        let src = "down:\n    (N)\n    / if { N == 0 } / 0 \\ / down(N - 1) \\ end \\\nend\n";
+        assert_eq!(bases(src), vec![true]);
+        let src = "down:\n    (N)\n    / case N of 0 / 0 \\ _ / down(N - 1) \\ end \\\nend\n";
+        assert_eq!(bases(src), vec![true]);
+    }
+
+    #[test]
+    fn functions_of_one_name_are_told_apart_by_arity() {
+        let code =
+            program::from_erlang_source("f(X) -> f(X, 0).\nf(X, A) -> X + A.\nmain() -> f(1).\n");
+        let g = build(&code);
+        let dot = to_dot(&g);
+        assert!(dot.contains("\"f/1\" -> \"f/2\";"), "{}", dot);
+        assert!(!dot.contains("\"f\""), "{}", dot);
+        assert!(g.cycles.is_empty());
+        let json = to_json(&g);
+        assert!(json.contains("[\"f/1\", \"f/2\"]"), "{}", json);
+    }
+
+    const PING_PONG: &str = "ping:\n    (N)\n    / pong(N + 1) \\\nend\n\
+                             pong:\n    (N)\n    / ping(N - 1) \\\nend\n\
+                             main:\n    (F)\n    / _ = F(1) rust::log(ping(0)) \\\nend\n";
+
+    #[test]
+    fn mutual_recursion_is_one_cycle() {
+        let src = "even:\n    (0)\n    / true \\\n    (N)\n    / odd(N - 1) \\\nend\n\
+                   odd:\n    (0)\n    / false \\\n    (N)\n    / even(N - 1) \\\nend\n";
+        let g = build(&program::from_source(src));
+        assert_eq!(g.cycles.len(), 1);
+        let names: Vec<String> = g.cycles[0].functions.iter().map(node_name).collect();
+        assert_eq!(names, vec!["even", "odd"]);
+        assert!(g.cycles[0].has_base);
+    }
+
+    #[test]
+    fn a_cycle_without_a_base_is_reported_and_drawn_red() {
+        let g = build(&program::from_source(PING_PONG));
+        assert_eq!(g.cycles.len(), 1);
+        assert_eq!(
+            describe_cycle(&g.cycles[0]),
+            "ping, pong call each other in every clause and never return"
+        );
+        let dot = to_dot(&g);
+        assert!(dot.contains("\"ping\" [color=red];"), "{}", dot);
+        assert!(dot.contains("\"ping\" -> \"pong\" [color=red];"), "{}", dot);
+        assert!(dot.contains("\"main\" -> \"ping\";"), "{}", dot);
+    }
+
+    #[test]
+    fn json_lists_nodes_edges_and_cycles() {
+        let json = to_json(&build(&program::from_source(PING_PONG)));
+        assert_eq!(
+            json,
+            "{\n  \"nodes\": [\"main\", \"ping\", \"pong\", \"rust::log\"],\n  \
+             \"edges\": [[\"main\", \"ping\"], [\"main\", \"rust::log\"], \
+             [\"ping\", \"pong\"], [\"pong\", \"ping\"]],\n  \
+             \"cycles\": [{\"functions\": [\"ping\", \"pong\"], \"has_base\": false}]\n}\n"
+        );
+    }
+
+    #[test]
+    fn every_branch_recursing_is_no_base() {
+        let src = "spin:\n    (N)\n    / if { N == 0 } / spin(N) \\ / spin(N - 1) \\ end \\\nend\n";
+        assert_eq!(bases(src), vec![false]);
+    }
+}
@@ -0,0 +1,144 @@
+use std::{collections::HashSet, fmt};
+
+use crate::{
+    bytecode::{Compiled, Op},
+    function::{self, FunctionName},
+};
+
+// Which rust:: host functions a script may call
+//
+// The embedder grants them by name, rust::log, or a whole module, rust::*,
+// a script is granted none unless it is given some.
+// Calls are checked when the program is loaded, call_levels::make_fun records
+// the ones it resolves without a grant, so a script that names a function it
+// was not granted does not start. The vm and the tree walker check again when
+// they make the call, for functions reached through a variable.
+
+#[derive(Debug, Clone)]
+pub enum Capabilities {
+    All,
+    Only(HashSet<Vec<char>>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Denied {
+    pub function: FunctionName,
+    // the script function making the call and the line of its bind
+    pub caller: Vec<char>,
+    pub line: usize,
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} calls {}, which this script is not allowed to use",
+            self.line,
+            self.caller.iter().collect::<String>(),
+            self.function
+        )
+    }
+}
+
+impl Capabilities {
+    pub fn allows(&self, f: &FunctionName) -> bool {
+        match (self, f) {
+            (Capabilities::Only(granted), FunctionName::Rust(name)) => granted.contains(name),
+            _ => true,
+        }
+    }
+}
+
+// rust::log,rust::other or rust::* for the whole module
+pub fn parse(list: &str) -> Result<Capabilities, String> {
+    let mut granted = HashSet::new();
+    for entry in list.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+        match entry.strip_prefix("rust::") {
+            Some("*") => return Ok(Capabilities::All),
+            Some(name) => {
+                let name: Vec<char> = name.chars().collect();
+                if !function::is_rust_function(&name) {
+                    return Err(format!("there is no host function {}", entry));
+                }
+                granted.insert(name);
+            }
+            None => return Err(format!("unknown module in {}", entry)),
+        }
+    }
+    return Ok(Capabilities::Only(granted));
+}
+
+// every host call of a compiled program the capabilities do not allow, in the order of the source
+pub fn check_compiled(code: &Compiled, caps: &Capabilities) -> Vec<Denied> {
+    let mut denied = Vec::new();
+    for fun in code.funs.iter() {
+        for (pc, op) in fun.code.iter().enumerate() {
+            match op {
+                Op::CallHost(f, _) if !caps.allows(f) => denied.push(Denied {
+                    function: f.clone(),
+                    caller: fun.name.to_string().chars().collect(),
+                    line: fun.line_at(pc),
+                }),
+                _ => {}
+            }
+        }
+    }
+    denied.sort_by_key(|d| d.line);
+    return denied;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{eval, program, segments, tokens, vm};
+
+    const LOGS: &str = "main:\n    ()\n    / rust::log(1) \\\nend\n";
+
+    fn funs(src: &str) -> program::Functions {
+        let tokens = tokens::string_to_located(src.to_string());
+        let segs = segments::matched_terminators_located(tokens);
+        return program::fun_list_to_funs(program::to_fun_list(segments::prune_clauses(segs)));
+    }
+
+    fn none() -> Capabilities {
+        return Capabilities::Only(HashSet::new());
+    }
+
+    #[test]
+    fn loading_reports_calls_not_granted() {
+        match program::to_granted_program(funs(LOGS), &none()) {
+            Err(denied) => {
+                assert_eq!(denied.len(), 1);
+                assert_eq!(
+                    denied[0].function,
+                    FunctionName::Rust("log".chars().collect())
+                );
+                assert_eq!(denied[0].caller, "main".chars().collect::<Vec<char>>());
+                assert_eq!(denied[0].line, 2);
+            }
+            Ok(_) => panic!("rust::log is not granted"),
+        }
+        let granted = parse("rust::log").expect("parses");
+        assert!(program::to_granted_program(funs(LOGS), &granted).is_ok());
+    }
+
+    // a program loaded without the check still can not make the call
+    #[test]
+    fn the_tree_walker_denies_calls_not_granted() {
+        let code = program::to_program(funs(LOGS));
+        let main = code[&FunctionName::Static("main".chars().collect())].clone();
+        let walked = eval::limited(&vm::Limits::default(), &none(), || {
+            eval::call(code.clone(), main.clone(), Vec::new())
+        });
+        match walked {
+            Err(vm::RunError::Denied(d)) => {
+                assert_eq!(d.function, FunctionName::Rust("log".chars().collect()))
+            }
+            other => panic!("expected rust::log to be denied, got {:?}", other),
+        }
+        let walked = eval::limited(&vm::Limits::default(), &Capabilities::All, || {
+            eval::call(code.clone(), main, Vec::new())
+        });
+        assert_eq!(walked.ok(), Some(eval::Data::Emptylist));
+    }
+}
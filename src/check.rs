@@ -0,0 +1,207 @@
+use std::{collections::HashSet, fmt};
+
+use crate::{
+    eval,
+    expr::Expr,
+    function::{self, FunctionName},
+    pattern_match::{self, ArgBind},
+    program,
+    visit::{self, Visitor},
+};
+
+// Static checks over a lowered program, finds what would otherwise
+// only fail once it runs: calls to functions that do not exist,
+// calls with the wrong number of arguments, calls through variables that are not bound,
+// and unbound variables.
+//
+// A variable is bound by the head pattern of its bind,
+// or by an assignment earlier in the same body.
+// Names that start with an upper case letter or _ are variables,
+// so f(X) with an unknown f is a missing function while F(X) is a dynamic call.
+
+#[derive(Debug, Clone)]
+pub struct CheckError {
+    pub function: Vec<char>,
+    pub clause: usize,
+    pub kind: CheckErrorKind,
+}
+
+#[derive(Debug, Clone)]
+pub enum CheckErrorKind {
+    UnknownFunction(FunctionName, usize),
+    WrongArity(FunctionName, usize, usize), // called with, takes
+    UnboundDynamicCall(Vec<char>),
+    UnboundVariable(Vec<char>),
+}
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name: String = self.function.iter().collect();
+        write!(f, "{} clause {}: ", name, self.clause + 1)?;
+        match &self.kind {
+            CheckErrorKind::UnknownFunction(fname, arity) => {
+                write!(f, "call to undefined function {}/{}", fname, arity)
+            }
+            CheckErrorKind::WrongArity(fname, arity, takes) => write!(
+                f,
+                "call to {}/{}, but {} takes {} arguments",
+                fname, arity, fname, takes
+            ),
+            CheckErrorKind::UnboundDynamicCall(v) => write!(
+                f,
+                "call through variable {} which is not bound",
+                v.iter().collect::<String>()
+            ),
+            CheckErrorKind::UnboundVariable(v) => write!(
+                f,
+                "variable {} is used before it is bound",
+                v.iter().collect::<String>()
+            ),
+        }
+    }
+}
+
+pub fn check_program(code: &eval::Program) -> Result<(), Vec<CheckError>> {
+    let mut names: Vec<&FunctionName> = code.keys().collect();
+    names.sort();
+    let mut errors = Vec::new();
+    for name in names {
+        let fun = &code[name];
+        for (i, bind) in fun.binds.iter().enumerate() {
+            let mut found = Vec::new();
+            check_bind(bind, code, &mut found);
+            errors.extend(found.into_iter().map(|kind| CheckError {
+                function: fun.name.clone(),
+                clause: i,
+                kind: kind,
+            }));
+        }
+    }
+    if errors.is_empty() {
+        return Ok(());
+    } else {
+        return Err(errors);
+    }
+}
+
+fn check_bind(b: &program::Bind, code: &eval::Program, errors: &mut Vec<CheckErrorKind>) {
+    let mut names = Vec::new();
+    b.pattern
+        .iter()
+        .for_each(|p| pattern_match::bound_names(p, &mut names));
+    let mut checker = Checker {
+        code: code,
+        bound: names.into_iter().collect(),
+        errors: errors,
+    };
+    visit::walk_filters(&mut checker, &b.filters);
+}
+
+struct Checker<'a> {
+    code: &'a eval::Program,
+    bound: HashSet<Vec<char>>,
+    errors: &'a mut Vec<CheckErrorKind>,
+}
+
+impl<'a> Checker<'a> {
+    // walk with the variables of the patterns bound, as they were before afterwards
+    fn scoped(&mut self, patterns: &[&ArgBind], walk: impl FnOnce(&mut Self)) {
+        let outer = self.bound.clone();
+        let mut names = Vec::new();
+        patterns
+            .iter()
+            .for_each(|p| pattern_match::bound_names(p, &mut names));
+        self.bound.extend(names);
+        walk(self);
+        self.bound = outer;
+    }
+}
+
+impl<'a> Visitor for Checker<'a> {
+    fn expr(&mut self, e: &Expr) {
+        match e {
+            Expr::Assign { pattern, arg, rest } => {
+                self.expr(arg);
+                self.scoped(&[pattern], |c| c.expr(rest));
+            }
+            Expr::Try { body, catches } => {
+                self.expr(body);
+                for catch in catches.iter() {
+                    self.scoped(&[&catch.class, &catch.pattern], |c| c.expr(&catch.code));
+                }
+            }
+            Expr::Case { arg, clauses } => {
+                self.expr(arg);
+                for clause in clauses.iter() {
+                    self.scoped(&[&clause.pattern], |c| {
+                        visit::walk_filters(c, &clause.filters)
+                    });
+                }
+            }
+            _ => visit::walk_expr(self, e),
+        }
+    }
+
+    fn variable(&mut self, name: &[char]) {
+        if !self.bound.contains(name) {
+            self.errors
+                .push(CheckErrorKind::UnboundVariable(name.to_vec()));
+        }
+    }
+
+    fn call(&mut self, f: &FunctionName, args: &[Expr]) {
+        match f {
+            // no clause of the target taking that many arguments is a function_clause error at best
+            FunctionName::Static(_) => match self.code.get(f) {
+                Some(target) if !target.binds.iter().any(|b| b.pattern.len() == args.len()) => {
+                    let takes = target.binds.first().map_or(0, |b| b.pattern.len());
+                    self.errors
+                        .push(CheckErrorKind::WrongArity(f.clone(), args.len(), takes));
+                }
+                _ => {}
+            },
+            FunctionName::Dynamic(name) if !self.bound.contains(name) => {
+                if function::is_variable_name(name) {
+                    self.errors
+                        .push(CheckErrorKind::UnboundDynamicCall(name.clone()));
+                } else {
+                    self.errors
+                        .push(CheckErrorKind::UnknownFunction(f.clone(), args.len()));
+                }
+            }
+            FunctionName::Rust(name) if !function::is_rust_function(name) => {
+                self.errors
+                    .push(CheckErrorKind::UnknownFunction(f.clone(), args.len()));
+            }
+            _ => {}
+        }
+        args.iter().for_each(|x| self.expr(x));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(src: &str) -> Vec<String> {
+        match check_program(&program::from_source(src)) {
+            Ok(()) => Vec::new(),
+            Err(errors) => errors.iter().map(|e| e.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn static_call_with_wrong_arity() {
+        let src = "f:\n    (X)\n    / X \\\nend\nmain:\n    ()\n    / f(1, 2) \\\nend\n";
+        assert_eq!(
+            errors(src),
+            vec!["main clause 1: call to f/2, but f takes 1 arguments"]
+        );
+    }
+
+    #[test]
+    fn static_call_with_right_arity() {
+        let src = "f:\n    (X)\n    / X \\\nend\nmain:\n    ()\n    / f(1) \\\nend\n";
+        assert!(errors(src).is_empty());
+    }
+}
@@ -0,0 +1,497 @@
+use std::{collections::HashMap, sync::Arc};
+
+use itertools::Itertools;
+
+use crate::{
+    eval::Data,
+    numeric_expr::NumericData,
+    pattern_match::{self, ArgBind},
+    program,
+};
+
+// Compiles the heads of a function into a decision tree, after
+// Maranget, "Compiling pattern matching to good decision trees"
+//
+// Instead of trying each bind's patterns from the start, the tree tests one
+// position of the arguments at a time and each test narrows down the binds
+// that can still match, so no position is looked at twice:
+//
+//     len([], N)      switch arg 1
+//     len([H|T], N)     [] -> bind 1
+//                       [_|_] -> load head, tail -> bind 2
+//
+// A leaf is the first bind whose patterns all match, its guards are tried
+// there and when they all fail the fallback tree goes on with the binds after it.
+// A position is a path from an argument through heads and tails.
+
+pub type Occurrence = Vec<usize>;
+
+const HEAD: usize = 0;
+const TAIL: usize = 1;
+
+// heads with repeated variables or different arities keep the clause by clause
+// code, so do functions whose tree would copy the binds too often
+const MAX_LEAVES: usize = 256;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ctor {
+    Const(Data),
+    Cons,
+}
+
+#[derive(Debug, Clone)]
+pub enum Tree {
+    Fail,
+    Leaf {
+        bind: usize,
+        fallback: Box<Tree>,
+    },
+    Switch {
+        at: Occurrence,
+        cases: Vec<(Ctor, Tree)>,
+        default: Box<Tree>,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum Pat {
+    Wild,
+    Const(Data),
+    Cons(Box<Pat>, Box<Pat>),
+}
+
+// the tests of a bind still to be done
+#[derive(Debug, Clone)]
+struct Row {
+    tests: Vec<(Occurrence, Pat)>,
+    bind: usize,
+}
+
+pub fn build(binds: &[program::Bind]) -> Option<Tree> {
+    let arity = binds.first().map(|b| b.pattern.len()).unwrap_or(0);
+    if binds
+        .iter()
+        .any(|b| b.pattern.len() != arity || pattern_match::has_repeats(&b.pattern))
+    {
+        return None;
+    }
+    let rows = binds
+        .iter()
+        .enumerate()
+        .map(|(i, b)| {
+            let mut tests = Vec::new();
+            for (arg, p) in b.pattern.iter().enumerate() {
+                push_test(&mut tests, vec![arg], to_pat(p));
+            }
+            Row {
+                tests: tests,
+                bind: i,
+            }
+        })
+        .collect();
+    let tree = compile(rows);
+    if leaves(&tree) > MAX_LEAVES {
+        return None;
+    }
+    return Some(tree);
+}
+
+// where each variable of the bind is found
+pub fn bindings(b: &program::Bind) -> Vec<(Vec<char>, Occurrence)> {
+    let mut acc = Vec::new();
+    for (arg, p) in b.pattern.iter().enumerate() {
+        collect_bindings(p, vec![arg], &mut acc);
+    }
+    return acc;
+}
+
+fn collect_bindings(p: &ArgBind, at: Occurrence, acc: &mut Vec<(Vec<char>, Occurrence)>) {
+    match p {
+        ArgBind::Identifier(n) if pattern_match::is_wildcard(n) => {}
+        ArgBind::Identifier(n) => acc.push((n.clone(), at)),
+        ArgBind::ListPattern { head, tail } => {
+            collect_bindings(head, child(&at, HEAD), acc);
+            collect_bindings(tail, child(&at, TAIL), acc);
+        }
+        ArgBind::Emptylist | ArgBind::ConstPattern(_) => {}
+    }
+}
+
+fn child(at: &Occurrence, step: usize) -> Occurrence {
+    let mut c = at.clone();
+    c.push(step);
+    return c;
+}
+
+// a constant list is matched element by element, so [1, 2] and [H|T] share the test
+fn to_pat(p: &ArgBind) -> Pat {
+    match p {
+        ArgBind::Identifier(_) => Pat::Wild,
+        ArgBind::Emptylist => Pat::Const(Data::Emptylist),
+        ArgBind::ConstPattern(d) => const_pat(d),
+        ArgBind::ListPattern { head, tail } => {
+            Pat::Cons(Box::new(to_pat(head)), Box::new(to_pat(tail)))
+        }
+    }
+}
+
+fn const_pat(d: &Data) -> Pat {
+    match d {
+        Data::List(h, t) => Pat::Cons(Box::new(const_pat(h)), Box::new(const_pat(t))),
+        d => Pat::Const(d.clone()),
+    }
+}
+
+fn push_test(tests: &mut Vec<(Occurrence, Pat)>, at: Occurrence, p: Pat) {
+    match p {
+        Pat::Wild => {}
+        p => tests.push((at, p)),
+    }
+}
+
+fn compile(rows: Vec<Row>) -> Tree {
+    let first = match rows.first() {
+        None => return Tree::Fail,
+        Some(r) => r,
+    };
+    // the first bind matches whatever is left untested
+    if first.tests.is_empty() {
+        return Tree::Leaf {
+            bind: first.bind,
+            fallback: Box::new(compile(rows[1..].to_vec())),
+        };
+    }
+    let at = first.tests[0].0.clone();
+    let mut ctors: Vec<Ctor> = Vec::new();
+    for row in rows.iter() {
+        if let Some((_, p)) = row.tests.iter().find(|(o, _)| *o == at) {
+            let c = ctor(p);
+            if !ctors.contains(&c) {
+                ctors.push(c);
+            }
+        }
+    }
+    let cases = ctors
+        .into_iter()
+        .map(|c| {
+            let rows = rows.iter().filter_map(|r| specialise(r, &at, &c)).collect();
+            (c, compile(rows))
+        })
+        .collect();
+    let default = rows
+        .iter()
+        .filter(|r| r.tests.iter().all(|(o, _)| *o != at))
+        .cloned()
+        .collect();
+    return Tree::Switch {
+        at: at,
+        cases: cases,
+        default: Box::new(compile(default)),
+    };
+}
+
+fn ctor(p: &Pat) -> Ctor {
+    match p {
+        Pat::Const(d) => Ctor::Const(d.clone()),
+        Pat::Cons(_, _) => Ctor::Cons,
+        Pat::Wild => unreachable!(),
+    }
+}
+
+// the row once the value at `at` is known to be c, None when the row needs something else
+fn specialise(r: &Row, at: &Occurrence, c: &Ctor) -> Option<Row> {
+    let i = match r.tests.iter().position(|(o, _)| o == at) {
+        // the row does not look at this position
+        None => return Some(r.clone()),
+        Some(i) => i,
+    };
+    let mut tests = r.tests.clone();
+    let (_, p) = tests.remove(i);
+    match (p, c) {
+        (Pat::Const(d), Ctor::Const(e)) if d == *e => {}
+        (Pat::Cons(h, t), Ctor::Cons) => {
+            // the parts are tested next, like the tree walker goes head first
+            let mut parts = Vec::new();
+            push_test(&mut parts, child(at, HEAD), *h);
+            push_test(&mut parts, child(at, TAIL), *t);
+            tests.splice(i..i, parts);
+        }
+        _ => return None,
+    }
+    return Some(Row {
+        tests: tests,
+        bind: r.bind,
+    });
+}
+
+fn leaves(t: &Tree) -> usize {
+    match t {
+        Tree::Fail => 0,
+        Tree::Leaf { fallback, .. } => 1 + leaves(fallback),
+        Tree::Switch { cases, default, .. } => {
+            cases.iter().map(|(_, t)| leaves(t)).sum::<usize>() + leaves(default)
+        }
+    }
+}
+
+// the binds whose patterns match the arguments, in the order they are tried
+pub fn select(t: &Tree, args: &[Data]) -> Vec<usize> {
+    match t {
+        Tree::Fail => Vec::new(),
+        Tree::Leaf { bind, fallback } => {
+            let mut res = vec![*bind];
+            res.extend(select(fallback, args));
+            return res;
+        }
+        Tree::Switch { at, cases, default } => {
+            let v = value_at(args, at);
+            let case = cases.iter().find(|(c, _)| match (c, v) {
+                (Ctor::Cons, Data::List(_, _)) => true,
+                (Ctor::Const(d), v) => d == v,
+                _ => false,
+            });
+            match case {
+                Some((_, t)) => select(t, args),
+                None => select(default, args),
+            }
+        }
+    }
+}
+
+fn value_at<'a>(args: &'a [Data], at: &Occurrence) -> &'a Data {
+    let mut v = &args[at[0]];
+    for step in at[1..].iter() {
+        v = match v {
+            Data::List(h, _) if *step == HEAD => h,
+            Data::List(_, t) => t,
+            _ => unreachable!(),
+        };
+    }
+    return v;
+}
+
+// the same, one bind at a time like eval::bind_args
+fn select_in_order(binds: &[program::Bind], args: &[Data]) -> Vec<usize> {
+    return binds
+        .iter()
+        .enumerate()
+        .filter(|(_, b)| {
+            b.pattern
+                .iter()
+                .zip(args.iter())
+                .all(|(p, a)| matches(p, a))
+        })
+        .map(|(i, _)| i)
+        .collect();
+}
+
+fn matches(p: &ArgBind, d: &Data) -> bool {
+    match (p, d) {
+        (ArgBind::Identifier(_), _) => true,
+        (ArgBind::Emptylist, Data::Emptylist) => true,
+        (ArgBind::ConstPattern(c), d) => c == d,
+        (ArgBind::ListPattern { head, tail }, Data::List(h, t)) => {
+            matches(head, h) && matches(tail, t)
+        }
+        _ => false,
+    }
+}
+
+// checks the tree picks the same binds as trying them in order, for arguments
+// made from the constants in the heads, a few other values and lists of them,
+// every combination when there are few enough, a pseudo random sample otherwise
+pub fn verify(binds: &[program::Bind]) -> Result<(), Vec<Data>> {
+    let tree = match build(binds) {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+    let arity = binds.first().map(|b| b.pattern.len()).unwrap_or(0);
+    let values = sample_values(binds);
+    let check = |args: Vec<Data>| {
+        if select(&tree, &args) != select_in_order(binds, &args) {
+            return Err(args);
+        }
+        return Ok(());
+    };
+    if values.len().pow(arity as u32) <= SAMPLES {
+        for args in (0..arity)
+            .map(|_| values.iter().cloned())
+            .multi_cartesian_product()
+        {
+            check(args)?;
+        }
+        if arity == 0 {
+            check(Vec::new())?;
+        }
+    } else {
+        let mut seed: u64 = 0x2545f4914f6cdd1d;
+        for _ in 0..SAMPLES {
+            let args = (0..arity)
+                .map(|_| {
+                    seed = seed
+                        .wrapping_mul(6364136223846793005)
+                        .wrapping_add(1442695040888963407);
+                    values[(seed >> 33) as usize % values.len()].clone()
+                })
+                .collect();
+            check(args)?;
+        }
+    }
+    return Ok(());
+}
+
+const SAMPLES: usize = 5000;
+
+fn sample_values(binds: &[program::Bind]) -> Vec<Data> {
+    let mut atoms = vec![
+        Data::Number(NumericData::Int(0)),
+        Data::Number(NumericData::Int(i64::MAX)),
+        Data::Boolean(true),
+        Data::Boolean(false),
+        Data::Emptylist,
+    ];
+    for b in binds.iter() {
+        b.pattern.iter().for_each(|p| constants(p, &mut atoms));
+    }
+    let mut unique: Vec<Data> = Vec::new();
+    for a in atoms.into_iter() {
+        if !unique.contains(&a) {
+            unique.push(a);
+        }
+    }
+    let atoms = unique;
+    let lists: Vec<Data> = atoms
+        .iter()
+        .map(|a| cons(a.clone(), Data::Emptylist))
+        .chain(
+            atoms
+                .iter()
+                .cartesian_product(atoms.iter())
+                .map(|(a, b)| cons(a.clone(), cons(b.clone(), Data::Emptylist))),
+        )
+        .chain(atoms.iter().map(|a| cons(a.clone(), a.clone())))
+        .collect();
+    let mut values = atoms;
+    values.extend(lists);
+    return values;
+}
+
+fn constants(p: &ArgBind, acc: &mut Vec<Data>) {
+    match p {
+        ArgBind::ConstPattern(d) => {
+            acc.push(d.clone());
+            if let Data::List(h, t) = d {
+                acc.push(Data::clone(h));
+                acc.push(Data::clone(t));
+            }
+        }
+        ArgBind::ListPattern { head, tail } => {
+            constants(head, acc);
+            constants(tail, acc);
+        }
+        ArgBind::Emptylist | ArgBind::Identifier(_) => {}
+    }
+}
+
+fn cons(h: Data, t: Data) -> Data {
+    return Data::List(Arc::new(h), Arc::new(t));
+}
+
+// the slot each position is stored in, arguments keep their own
+pub fn slots(t: &Tree, arity: usize) -> HashMap<Occurrence, usize> {
+    let mut acc: HashMap<Occurrence, usize> = (0..arity).map(|i| (vec![i], i)).collect();
+    add_slots(t, &mut acc);
+    return acc;
+}
+
+fn add_slots(t: &Tree, acc: &mut HashMap<Occurrence, usize>) {
+    match t {
+        Tree::Fail => {}
+        Tree::Leaf { fallback, .. } => add_slots(fallback, acc),
+        Tree::Switch { at, cases, default } => {
+            for (c, sub) in cases.iter() {
+                if *c == Ctor::Cons {
+                    for step in [HEAD, TAIL] {
+                        let n = acc.len();
+                        acc.entry(child(at, step)).or_insert(n);
+                    }
+                }
+                add_slots(sub, acc);
+            }
+            add_slots(default, acc);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heads(src: &str) -> Vec<program::Bind> {
+        let code = program::from_source(src);
+        return code.into_values().next().expect("one function").binds;
+    }
+
+    fn int(i: i64) -> Data {
+        return Data::Number(NumericData::Int(i));
+    }
+
+    #[test]
+    fn samples_pick_the_binds_trying_them_in_order_picks() {
+        for path in program::SAMPLES {
+            for (name, f) in program::from_file(path).iter() {
+                if let Err(args) = verify(&f.binds) {
+                    panic!("{} in {} picks other binds for {:?}", name, path, args);
+                }
+            }
+        }
+    }
+
+    // constants, lists and variables overlapping at every position
+    #[test]
+    fn overlapping_heads_pick_the_binds_trying_them_in_order_picks() {
+        let binds = heads(
+            "f:\n    ([], 0)\n    / 1 \\\n    ([0|T], X)\n    / 2 \\\n\
+             ([1, 2], [])\n    / 3 \\\n    (X, [A|B])\n    / 4 \\\n\
+             ([[A]|_], true)\n    / 5 \\\n    (ok, X)\n    / 6 \\\n\
+             (X, Y)\n    / 7 \\\nend\n",
+        );
+        assert!(build(&binds).is_some());
+        assert_eq!(verify(&binds), Ok(()));
+    }
+
+    // too many combinations to try them all, verify takes a sample
+    #[test]
+    fn wide_heads_pick_the_binds_trying_them_in_order_picks() {
+        let binds = heads(
+            "f:\n    (0, 1, 2, 3)\n    / 1 \\\n    (A, [], B, 3)\n    / 2 \\\n\
+             ([X|Y], 1, [], Z)\n    / 3 \\\n    (A, B, true, false)\n    / 4 \\\n\
+             (0, [1|T], B, C)\n    / 5 \\\nend\n",
+        );
+        let values = sample_values(&binds);
+        assert!(values.len().pow(4) > SAMPLES);
+        assert_eq!(verify(&binds), Ok(()));
+    }
+
+    // every bind that matches is a candidate, the guards pick among them
+    #[test]
+    fn a_leaf_falls_back_to_the_later_binds() {
+        let binds = heads(
+            "f:\n    ([H|T])\n    { H > 0 }\n    / 1 \\\n    ([0|T])\n    / 2 \\\n\
+             (X)\n    / 3 \\\nend\n",
+        );
+        let tree = build(&binds).expect("builds a tree");
+        assert_eq!(
+            select(&tree, &[cons(int(0), Data::Emptylist)]),
+            vec![0, 1, 2]
+        );
+        assert_eq!(select(&tree, &[cons(int(5), Data::Emptylist)]), vec![0, 2]);
+        assert_eq!(select(&tree, &[Data::Emptylist]), vec![2]);
+    }
+
+    #[test]
+    fn repeated_variables_keep_the_clause_by_clause_code() {
+        let binds = heads("f:\n    (X, X)\n    / 1 \\\n    (X, Y)\n    / 2 \\\nend\n");
+        assert!(build(&binds).is_none());
+    }
+}
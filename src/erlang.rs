@@ -0,0 +1,258 @@
+use std::collections::HashSet;
+
+use crate::{
+    pattern_match,
+    program::{self, FunBind, FunFilter, FunFun, Functions},
+    segments,
+    tokens::{self, Located, Token},
+    types,
+};
+
+// Front end for the Erlang flavoured syntax
+//
+// map([], F, Acc) -> Acc;
+// map([H|T], F, Acc) when is_list(T) -> map(T, F, [Acc|F(H)]).
+//
+// Clauses are separated by `;`, a function ends with `.`.
+// In a guard `,` means andalso and `;` means orelse, like in Erlang.
+// `%` starts a comment running to the end of the line.
+// A function is its name and arity, f/1 and f/2 are two functions,
+// see program::arity_name for how calls tell them apart.
+// The result is the same Functions the `name: .. end` syntax gives,
+// so program::to_program and everything after it is shared.
+
+// if function names are duplicated
+// the last version of a function will win
+pub fn to_funs(t: Vec<Located>) -> Functions {
+    return program::fun_list_to_funs(to_fun_list(t));
+}
+
+// functions in the order they are written
+pub fn to_fun_list(t: Vec<Located>) -> Vec<FunFun> {
+    let tv = strip_comments(t);
+    return by_arity(funs_int(&tv, Vec::new()));
+}
+
+// % comments are cut before tokenizing, % is no token of its own
+// the newline stays so the lines of the tokens after it do not move
+pub fn strip_line_comments(s: &str) -> String {
+    return s
+        .split_inclusive('\n')
+        .map(|line| match line.find('%') {
+            Some(i) if line.ends_with('\n') => format!("{}\n", &line[0..i]),
+            Some(i) => line[0..i].to_string(),
+            None => line.to_string(),
+        })
+        .collect();
+}
+
+// a name written with more than one arity becomes name/arity for each of them
+// so f/1 and f/2 do not replace each other in program::fun_list_to_funs
+fn by_arity(funs: Vec<FunFun>) -> Vec<FunFun> {
+    let arities = |name: &Vec<char>| -> HashSet<usize> {
+        funs.iter()
+            .filter(|f| f.name == *name)
+            .map(|f| f.binds[0].pattern.len())
+            .collect()
+    };
+    let overloaded: HashSet<Vec<char>> = funs
+        .iter()
+        .map(|f| f.name.clone())
+        .filter(|n| arities(n).len() > 1)
+        .collect();
+    return funs
+        .into_iter()
+        .map(|mut f| {
+            if overloaded.contains(&f.name) {
+                f.name = program::arity_name(&f.name, f.binds[0].pattern.len());
+            }
+            f
+        })
+        .collect();
+}
+
+fn strip_comments(t: Vec<Located>) -> Vec<Located> {
+    let mut depth = 0;
+    let mut ret = Vec::new();
+    for (token, line) in t {
+        match token {
+            Token::CommentStart => depth += 1,
+            Token::CommentEnd => depth -= 1,
+            token if depth == 0 => ret.push((token, line)),
+            _ => {}
+        }
+    }
+    return ret;
+}
+
+fn funs_int(tv: &[Located], mut done: Vec<FunFun>) -> Vec<FunFun> {
+    if tv.is_empty() {
+        return done;
+    }
+    if let [(Token::SUB, _), (Token::Identifier(s), _), rest @ ..] = tv {
+        if *s == ['s', 'p', 'e', 'c'] {
+            return spec(rest, done);
+        }
+    }
+    let (fun, rest) = function(tv);
+    done.push(fun);
+    return funs_int(rest, done);
+}
+
+// -spec name(T, ..) -> T. belongs to the function right after it
+fn spec(tv: &[Located], mut done: Vec<FunFun>) -> Vec<FunFun> {
+    let dot = match tv.iter().position(|(t, _)| *t == Token::Dot) {
+        Some(d) => d,
+        None => {
+            println!("SPEC WITHOUT DOT{:#?}\n", tv);
+            unimplemented!()
+        }
+    };
+    let spec_tokens: Vec<Token> = tv[0..dot].iter().map(|(t, _)| t.clone()).collect();
+    let (name, ty) = types::parse_spec(&spec_tokens);
+    let (mut fun, rest) = function(&tv[dot + 1..tv.len()]);
+    if fun.name != name {
+        println!("SPEC NAME MISSMATCH{:#?}{:#?}\n", name, fun.name);
+        unimplemented!()
+    }
+    fun.spec = Some(ty);
+    done.push(fun);
+    return funs_int(rest, done);
+}
+
+// clauses until the ending `.`
+fn function(tv: &[Located]) -> (FunFun, &[Located]) {
+    let mut binds: Vec<FunBind> = Vec::new();
+    let mut rest = tv;
+    let mut name = Vec::new();
+    loop {
+        let (cname, bind, (end, after)) = clause(rest);
+        if !binds.is_empty() && cname != name {
+            println!("CLAUSE NAME MISSMATCH{:#?}{:#?}\n", name, cname);
+            unimplemented!()
+        }
+        // f(X) -> ..; f(X, Y) -> .. is a head mismatch in Erlang too
+        if !binds.is_empty() && bind.pattern.len() != binds[0].pattern.len() {
+            println!("CLAUSE ARITY MISSMATCH{:#?}{:#?}\n", name, bind.line);
+            unimplemented!()
+        }
+        name = cname;
+        binds.push(bind);
+        rest = after;
+        match end {
+            Token::Semicolon => continue,
+            _ => {
+                return (
+                    FunFun {
+                        name: name,
+                        line: binds[0].line,
+                        end: binds[binds.len() - 1].end,
+                        binds: binds,
+                        spec: None,
+                    },
+                    rest,
+                )
+            }
+        }
+    }
+}
+
+// name(Args) when Guard -> Body
+// gives the token ending the clause, `;` or `.`, with the tokens after it
+fn clause(tv: &[Located]) -> (Vec<char>, FunBind, (Token, &[Located])) {
+    let (name, line, rest) = match tv {
+        [(Token::Identifier(name), line), (Token::LeftP, _), rest @ ..] => {
+            (name.clone(), *line, rest)
+        }
+        _ => {
+            println!("BAD CLAUSE HEAD{:#?}\n", tv);
+            unimplemented!()
+        }
+    };
+    let (args, rest) = until(rest, |t| *t == Token::RightP);
+    let (guard, rest) = match rest {
+        [(Token::RightP, _), (Token::When, _), rest @ ..] => until(rest, |t| *t == Token::Arrow),
+        [(Token::RightP, _), rest @ ..] => (Vec::new(), rest),
+        _ => {
+            println!("BAD CLAUSE HEAD{:#?}\n", tv);
+            unimplemented!()
+        }
+    };
+    let rest = match rest.split_first() {
+        Some(((Token::Arrow, _), rest)) => rest,
+        _ => {
+            println!("MISSING ->{:#?}\n", tv);
+            unimplemented!()
+        }
+    };
+    let (body, rest) = until(rest, |t| *t == Token::Semicolon || *t == Token::Dot);
+    let (end, end_line) = match rest.split_first() {
+        Some(((end, l), rest)) => ((end.clone(), rest), *l),
+        None => {
+            println!("MISSING .{:#?}\n", tv);
+            unimplemented!()
+        }
+    };
+    let filter = FunFilter {
+        head: to_segments(guard_tokens(guard)),
+        code: to_segments(body),
+    };
+    let bind = FunBind {
+        pattern: pattern_match::bind_segment_to_bindpattern(to_segments(args)),
+        filters: vec![filter],
+        line: line,
+        end: end_line,
+    };
+    return (name, bind, end);
+}
+
+// splits at the first token matching stop that is not nested in any brackets
+fn until(tv: &[Located], stop: impl Fn(&Token) -> bool) -> (Vec<Located>, &[Located]) {
+    let mut depth = 0;
+    for (i, (t, _)) in tv.iter().enumerate() {
+        if depth == 0 && stop(t) {
+            return (tv[0..i].to_vec(), &tv[i..tv.len()]);
+        }
+        if opens(t) {
+            depth += 1;
+        } else if is_closing(t) {
+            depth -= 1;
+        }
+    }
+    return (tv.to_vec(), &tv[tv.len()..tv.len()]);
+}
+
+// `:` never starts a function here, in a catch clause it comes before the pattern
+fn opens(t: &Token) -> bool {
+    return *t != Token::FunStart && tokens::has_pair(t.clone()).is_some();
+}
+
+fn is_closing(t: &Token) -> bool {
+    match t {
+        Token::RightP | Token::RightB | Token::RightW | Token::CodeEnd | Token::END => true,
+        _ => false,
+    }
+}
+
+// X > 0, Y > 0; Z -> X > 0 andalso Y > 0 orelse Z
+fn guard_tokens(tv: Vec<Located>) -> Vec<Located> {
+    let mut depth = 0;
+    let mut ret = Vec::new();
+    for (t, line) in tv {
+        if opens(&t) {
+            depth += 1;
+        } else if is_closing(&t) {
+            depth -= 1;
+        }
+        match t {
+            Token::ArgTerm if depth == 0 => ret.push((Token::ANDALSO, line)),
+            Token::Semicolon if depth == 0 => ret.push((Token::ORELSE, line)),
+            t => ret.push((t, line)),
+        }
+    }
+    return ret;
+}
+
+fn to_segments(tv: Vec<Located>) -> Vec<segments::Segment> {
+    return segments::prune_clauses(segments::matched_terminators_located(tv));
+}
@@ -1,9 +1,16 @@
-use std::{borrow::Borrow, collections::HashMap};
+use std::{
+    borrow::Borrow, cell::RefCell, cmp::Ordering, collections::HashMap, fmt, panic, sync::Arc,
+    thread,
+};
 
 use crate::{
+    capability::{Capabilities, Denied},
     expr,
     function::{self, FunctionName},
-    logic_expr, numeric_expr, pattern_match, program,
+    logic_expr,
+    numeric_expr::{self, NumericData},
+    pattern_match, program, types,
+    vm::{LimitExceeded, Limits, RunError, RuntimeErrorKind, Usage},
 };
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -11,52 +18,301 @@ pub enum Data {
     Number(numeric_expr::NumericData),
     Boolean(bool),
     Emptylist,
-    List(Box<Data>, Box<Data>),
+    List(Arc<Data>, Arc<Data>),
     FunctionPointer(FunctionName),
+    // a lowercase name used as a value, ok, badmatch
+    Atom(Vec<char>),
+}
+
+// throw(Term) is for the caller to catch, error(Term) and the runtime errors
+// are for failures, a catch clause names the class it takes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    Throw,
+    Error,
+}
+
+// what the tree walker unwinds with until a try catches it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Raised {
+    pub class: Class,
+    pub term: Data,
 }
 
 pub type Program = HashMap<function::FunctionName, program::Fun>;
 pub type ProgramState = HashMap<Vec<char>, Data>;
 
-pub fn call(code: Program, program::Fun { binds, .. }: program::Fun, args: Vec<Data>) -> Data {
-    let (state0, filters) = bind_args(binds.clone(), args.clone());
-    let body = get_callpath(code.clone(), state0.clone(), filters.clone());
+impl Class {
+    pub fn atom(&self) -> Data {
+        return Data::Atom(self.to_string().chars().collect());
+    }
+
+    // anything but throw is raised as an error
+    pub fn from_atom(d: &Data) -> Class {
+        match d {
+            Data::Atom(a) if *a == ['t', 'h', 'r', 'o', 'w'] => Class::Throw,
+            _ => Class::Error,
+        }
+    }
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Class::Throw => write!(f, "throw"),
+            Class::Error => write!(f, "error"),
+        }
+    }
+}
+
+// unwinds without running the panic hook, so nothing is printed for a caught raise
+pub fn raise(class: Class, term: Data) -> ! {
+    panic::resume_unwind(Box::new(Raised {
+        class: class,
+        term: term,
+    }));
+}
+
+// the runtime errors of the vm, raised as the same error terms
+pub fn fail(kind: RuntimeErrorKind) -> ! {
+    raise(kind.class(), kind.term());
+}
+
+// runs f, giving back what it raised, any other panic keeps unwinding
+pub fn catch<T>(f: impl FnOnce() -> T) -> Result<T, Raised> {
+    match panic::catch_unwind(panic::AssertUnwindSafe(f)) {
+        Ok(v) => return Ok(v),
+        Err(payload) => match payload.downcast::<Raised>() {
+            Ok(raised) => return Err(*raised),
+            Err(payload) => panic::resume_unwind(payload),
+        },
+    }
+}
+
+// The tree walker runs with the Limits and the Capabilities of the vm. It has
+// no tail calls and keeps every call on the stack of the host, so it runs on a
+// thread of its own with a stack that holds WALKER_DEPTH calls and stops there
+// whatever the limits say.
+
+const WALKER_DEPTH: usize = 10_000;
+const WALKER_STACK: usize = 512 << 20;
+
+// what the tree walker has used of its limits, None outside of limited
+struct Budget {
+    limits: Limits,
+    caps: Capabilities,
+    usage: Usage,
+    // the calls waiting for a result and the line of their bind, innermost last
+    running: Vec<(FunctionName, usize)>,
+}
+
+impl Budget {
+    fn running(&self) -> (FunctionName, usize) {
+        return self
+            .running
+            .last()
+            .cloned()
+            .unwrap_or((FunctionName::Static("main".chars().collect()), 0));
+    }
+}
+
+thread_local! {
+    static BUDGET: RefCell<Option<Budget>> = RefCell::new(None);
+}
+
+// runs f in the tree walker within the limits and the capabilities,
+// a limit exceeded or a host call denied stops it, any other panic keeps unwinding
+pub fn limited<T: Send>(
+    limits: &Limits,
+    caps: &Capabilities,
+    f: impl FnOnce() -> T + Send,
+) -> Result<T, RunError> {
+    let walker = Limits {
+        depth: Some(limits.depth.map_or(WALKER_DEPTH, |d| d.min(WALKER_DEPTH))),
+        ..limits.clone()
+    };
+    let res = thread::scope(|s| {
+        let run = thread::Builder::new()
+            .stack_size(WALKER_STACK)
+            .spawn_scoped(s, || {
+                BUDGET.with(|b| {
+                    *b.borrow_mut() = Some(Budget {
+                        usage: Usage::start(&walker),
+                        limits: walker,
+                        caps: caps.clone(),
+                        running: Vec::new(),
+                    })
+                });
+                return panic::catch_unwind(panic::AssertUnwindSafe(f));
+            });
+        return run.expect("Cant start the tree walker").join();
+    });
+    match res {
+        Ok(Ok(v)) => return Ok(v),
+        Ok(Err(payload)) | Err(payload) => match payload.downcast::<RunError>() {
+            Ok(e) => return Err(*e),
+            Err(payload) => panic::resume_unwind(payload),
+        },
+    }
+}
+
+// counts what the tree walker did against its limits, stopping the walk
+// in the function running when one is exceeded
+fn charge(count: impl FnOnce(&mut Usage)) {
+    let exceeded = BUDGET.with(|b| match b.borrow_mut().as_mut() {
+        Some(budget) => {
+            count(&mut budget.usage);
+            budget.usage.steps += 1;
+            let depth = budget.running.len();
+            return budget
+                .limits
+                .exceeded(&budget.usage, depth)
+                .map(|limit| LimitExceeded {
+                    limit: limit,
+                    function: budget.running().0,
+                });
+        }
+        None => None,
+    });
+    if let Some(e) = exceeded {
+        panic::resume_unwind(Box::new(RunError::LimitExceeded(e)));
+    }
+}
+
+// a call of a function of the program once its bind is chosen,
+// it waits for its result until Nested is dropped
+pub fn reduce(callee: &FunctionName, line: usize) -> Nested {
+    charge(|u| u.reductions += 1);
+    BUDGET.with(|b| {
+        if let Some(budget) = b.borrow_mut().as_mut() {
+            budget.running.push((callee.clone(), line));
+        }
+    });
+    return Nested;
+}
+
+// a call of a host function, which the script has to be granted
+pub fn reduce_host(f: &FunctionName) {
+    charge(|u| u.reductions += 1);
+    let denied = BUDGET.with(|b| match b.borrow().as_ref() {
+        Some(budget) if !budget.caps.allows(f) => {
+            let (caller, line) = budget.running();
+            return Some(Denied {
+                function: f.clone(),
+                caller: caller.to_string().chars().collect(),
+                line: line,
+            });
+        }
+        _ => None,
+    });
+    if let Some(d) = denied {
+        panic::resume_unwind(Box::new(RunError::Denied(d)));
+    }
+}
+
+// a list cell built by the program
+pub fn cons_cell() {
+    charge(|u| u.heap += 1);
+}
+
+pub struct Nested;
+
+// on return and when unwinding through a try
+impl Drop for Nested {
+    fn drop(&mut self) {
+        BUDGET.with(|b| {
+            if let Some(budget) = b.borrow_mut().as_mut() {
+                budget.running.pop();
+            }
+        });
+    }
+}
+
+// main() of a whole program, what it raised when it fails, for tests
+#[cfg(test)]
+pub fn run_main(code: &Program) -> Result<Data, Raised> {
+    let main = code[&FunctionName::Static("main".chars().collect())].clone();
+    return catch(|| call(code.clone(), main, Vec::new()));
+}
+
+// a function with a spec has its arguments and result checked against it,
+// run drops the specs unless it is asked to check them
+pub fn call(
+    code: Program,
+    program::Fun {
+        binds, spec, name, ..
+    }: program::Fun,
+    args: Vec<Data>,
+) -> Data {
+    let name = FunctionName::Static(name);
+    // like the vm, a call through a variable can pass the wrong number of arguments
+    if let Some(b) = binds.first() {
+        if b.pattern.len() != args.len() {
+            fail(RuntimeErrorKind::BadArity(name, args.len()));
+        }
+    }
+    if let Some(types::Type::Fun(arg_types, _)) = &spec {
+        for (a, t) in args.iter().zip(arg_types.iter()) {
+            if !types::data_has_type(a, t) {
+                fail(RuntimeErrorKind::Spec(name.clone(), a.clone(), t.clone()));
+            }
+        }
+    }
+    let (state0, body, line) = bind_args(code.clone(), &name, binds.clone(), args.clone());
+    let _nested = reduce(&name, line);
     let res = expr::eval(code, state0.clone(), body.clone());
-    //println!("B{:#?}{:#?}{:#?}{:#?}\n", body, state0, filters, res);
+    //println!("B{:#?}{:#?}{:#?}\n", body, state0, res);
+    if let Some(types::Type::Fun(_, ret)) = &spec {
+        if !types::data_has_type(&res, ret) {
+            fail(RuntimeErrorKind::Spec(name, res, (**ret).clone()));
+        }
+    }
     return res;
 }
 
-fn get_callpath(c: Program, p: ProgramState, fv: Vec<program::Filter>) -> expr::Expr {
+// the code of the first filter whose guard passes
+pub fn get_callpath(c: Program, p: ProgramState, fv: Vec<program::Filter>) -> Option<expr::Expr> {
     match fv.split_first() {
         Some((program::Filter { head, code }, t)) => {
             let passed_filter = logic_expr::eval(c.clone(), p.clone(), head.clone());
             if passed_filter {
-                return code.clone();
+                return Some(code.clone());
             } else {
                 return get_callpath(c, p, t.to_vec());
             }
         }
-        None => {
-            println!("No Pattern:{:#?}{:#?}\n", p, fv);
-            unimplemented!();
-        }
+        None => None,
     }
 }
 
-fn bind_args(binds: Vec<program::Bind>, args: Vec<Data>) -> (ProgramState, Vec<program::Filter>) {
+// first bind whose pattern matches and has a passing filter, with its line,
+// when all filters of a bind fail the next bind is tried like the next clause in Erlang,
+// see Clause selection in notes.md
+fn bind_args(
+    c: Program,
+    name: &FunctionName,
+    binds: Vec<program::Bind>,
+    args: Vec<Data>,
+) -> (ProgramState, expr::Expr, usize) {
     match binds.split_first() {
-        Some((program::Bind { pattern, filters }, t)) => {
-            match try_bind(pattern.to_vec(), args.clone()) {
-                Some(state) => {
-                    return (state, filters.clone());
+        Some((
+            program::Bind {
+                pattern,
+                filters,
+                line,
+                ..
+            },
+            t,
+        )) => match try_bind(pattern.to_vec(), args.clone()) {
+            Some(state) => match get_callpath(c.clone(), state.clone(), filters.clone()) {
+                Some(body) => {
+                    return (state, body, *line);
                 }
-                _ => bind_args(t.to_vec(), args),
-            }
-        }
-        None => {
-            println!("No Pattern:{:#?}{:#?}\n", binds, args);
-            unimplemented!();
-        }
+                None => bind_args(c, name, t.to_vec(), args),
+            },
+            _ => bind_args(c, name, t.to_vec(), args),
+        },
+        None => fail(RuntimeErrorKind::FunctionClause(name.clone(), args)),
     }
 }
 
@@ -70,8 +326,7 @@ pub fn try_bind_with_state(
     args: Vec<Data>,
 ) -> Option<ProgramState> {
     if pattern.len() != args.len() {
-        println!("bind len missmatch{:#?}{:#?}\n", pattern, args);
-        unimplemented!();
+        return None;
     }
     if pattern.len() == 0 {
         return Some(state);
@@ -108,21 +363,23 @@ fn try_bind_single(
                 return None;
             }
         }
+        (pattern_match::ArgBind::Identifier(ph), _) if pattern_match::is_wildcard(&ph) => {
+            return Some(state);
+        }
         (pattern_match::ArgBind::Identifier(ph), _) => match state.get(&ph) {
             None => {
                 state.insert(ph.to_vec(), arg);
                 return Some(state);
             }
-            _ => {
-                println!("pattern matching{:#?}{:#?}{:#?}\n", state, pattern, arg);
-                unimplemented!();
-            }
+            // a bound variable matches the value it holds, as in erlang
+            Some(v) if *v == arg => return Some(state),
+            Some(_) => return None,
         },
         (pattern_match::ArgBind::ListPattern { head: ah, tail: at }, Data::List(dh, dt)) => {
-            let dh1 = *dh.clone();
+            let dh1 = Arc::unwrap_or_clone(dh);
             let ah1 = *ah.clone();
             if let Some(state1) = try_bind_single(state, ah1, dh1) {
-                let dt1 = *dt.clone();
+                let dt1 = Arc::unwrap_or_clone(dt);
                 let at1 = *at.clone();
                 if let Some(state2) = try_bind_single(state1, at1, dt1) {
                     return Some(state2);
@@ -140,3 +397,167 @@ fn try_bind_single(
         }
     }
 }
+
+// Erlang style total order over all Data
+// number < atom < function < [] < list
+// true and false are the atoms they are named by, so abc < false < true < zebra,
+// lists compare element by element, head first
+pub fn term_order(a: &Data, b: &Data) -> Ordering {
+    match (a, b) {
+        (Data::Number(NumericData::Int(x)), Data::Number(NumericData::Int(y))) => x.cmp(y),
+        (Data::Boolean(x), Data::Boolean(y)) => x.cmp(y),
+        (Data::Atom(_) | Data::Boolean(_), Data::Atom(_) | Data::Boolean(_)) => {
+            atom_name(a).cmp(&atom_name(b))
+        }
+        (Data::FunctionPointer(x), Data::FunctionPointer(y)) => x.cmp(y),
+        (Data::Emptylist, Data::Emptylist) => Ordering::Equal,
+        (Data::List(ah, at), Data::List(bh, bt)) => match term_order(ah, bh) {
+            Ordering::Equal => term_order(at, bt),
+            o => o,
+        },
+        _ => type_rank(a).cmp(&type_rank(b)),
+    }
+}
+
+fn type_rank(d: &Data) -> u8 {
+    match d {
+        Data::Number(_) => 0,
+        Data::Boolean(_) | Data::Atom(_) => 1,
+        Data::FunctionPointer(_) => 2,
+        Data::Emptylist => 3,
+        Data::List(_, _) => 4,
+    }
+}
+
+fn atom_name(d: &Data) -> Vec<char> {
+    match d {
+        Data::Boolean(true) => return "true".chars().collect(),
+        Data::Boolean(false) => return "false".chars().collect(),
+        Data::Atom(a) => return a.clone(),
+        _ => return Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bytecode, vm};
+
+    // main() in the tree walker and in the vm, the value or the term raised
+    fn in_both(src: &str) -> Result<Data, Data> {
+        let code = program::from_source(src);
+        let walked = run_main(&code).map_err(|r| r.term);
+        let main = FunctionName::Static("main".chars().collect());
+        let compiled = bytecode::compile_program(&code);
+        let ran = vm::call(
+            &compiled,
+            &main,
+            Vec::new(),
+            &Limits::default(),
+            &Capabilities::All,
+        );
+        match ran {
+            Ok(d) => assert_eq!(Ok(d), walked, "the vm gives another value"),
+            Err(RunError::Runtime(e)) => {
+                assert_eq!(Err(e.kind.term()), walked, "the vm raises another term")
+            }
+            Err(e) => panic!("the vm failed: {}", e),
+        }
+        return walked;
+    }
+
+    fn int(i: i64) -> Data {
+        return Data::Number(NumericData::Int(i));
+    }
+
+    const SAME: &str = "same:\n    (X, X)\n    / X \\\n    (_, _)\n    / none \\\nend\n";
+
+    #[test]
+    fn a_repeated_variable_takes_equal_arguments() {
+        let src = format!(
+            "{}main:\n    ()\n    / [same(1, 1), same([a], [a])] \\\nend\n",
+            SAME
+        );
+        let expected = Data::List(
+            Arc::new(int(1)),
+            Arc::new(Data::List(
+                Arc::new(Data::List(
+                    Arc::new(Data::Atom(vec!['a'])),
+                    Arc::new(Data::Emptylist),
+                )),
+                Arc::new(Data::Emptylist),
+            )),
+        );
+        assert_eq!(in_both(&src), Ok(expected));
+    }
+
+    #[test]
+    fn unequal_arguments_fall_through_to_the_next_bind() {
+        let src = format!("{}main:\n    ()\n    / same(1, 2) \\\nend\n", SAME);
+        assert_eq!(in_both(&src), Ok(Data::Atom("none".chars().collect())));
+        // inside a list as well, [X, X] does not take [1, 2]
+        let src = "pair:\n    ([X, X])\n    / X \\\n    (_)\n    / none \\\nend\n\
+                   main:\n    ()\n    / [pair([3, 3])|pair([1, 2])] \\\nend\n";
+        assert_eq!(
+            in_both(src),
+            Ok(Data::List(
+                Arc::new(int(3)),
+                Arc::new(Data::Atom("none".chars().collect()))
+            ))
+        );
+    }
+
+    #[test]
+    fn no_bind_left_is_a_function_clause() {
+        let src = "only:\n    (X, X)\n    / X \\\nend\nmain:\n    ()\n    / only(1, 2) \\\nend\n";
+        let expected = RuntimeErrorKind::FunctionClause(
+            FunctionName::Static("only".chars().collect()),
+            vec![int(1), int(2)],
+        )
+        .term();
+        assert_eq!(in_both(src), Err(expected));
+    }
+
+    fn truths(src: &str) -> Vec<bool> {
+        let mut res = Vec::new();
+        let mut rest = in_both(src).expect("main returns");
+        while let Data::List(h, t) = rest {
+            match Arc::unwrap_or_clone(h) {
+                Data::Boolean(b) => res.push(b),
+                other => panic!("not a boolean {:?}", other),
+            }
+            rest = Arc::unwrap_or_clone(t);
+        }
+        return res;
+    }
+
+    #[test]
+    fn values_of_different_types_compare_in_erlang_term_order() {
+        let body = "[1 < abc, abc < aa, aa < [], [] < [1], 100 < [], abc < [1]]";
+        let src = format!(
+            "aa:\n    ()\n    / 1 \\\nend\nmain:\n    ()\n    / {} \\\nend\n",
+            body
+        );
+        assert_eq!(truths(&src), vec![true; 6]);
+    }
+
+    #[test]
+    fn booleans_are_compared_as_atoms() {
+        let body = "[abc < false, false < true, true < zebra, false > abc, 1 < false, true < []]";
+        let src = format!("main:\n    ()\n    / {} \\\nend\n", body);
+        assert_eq!(truths(&src), vec![true; 6]);
+    }
+
+    #[test]
+    fn values_of_different_types_are_never_equal() {
+        let body = "[1 == abc, true == 1, [] == [[]], abc =/= abc, 1 == 1, [a] =/= [b]]";
+        let src = format!("main:\n    ()\n    / {} \\\nend\n", body);
+        assert_eq!(truths(&src), vec![false, false, false, false, true, true]);
+    }
+
+    #[test]
+    fn an_assignment_to_a_bound_variable_compares() {
+        let src = "main:\n    ()\n    /\n    A = 1\n    A = 1\n    A = 2\n    A\n    \\\nend\n";
+        assert_eq!(in_both(src), Err(RuntimeErrorKind::BadMatch(int(2)).term()));
+    }
+}
@@ -0,0 +1,392 @@
+use itertools::Itertools;
+
+use crate::{
+    eval::Data,
+    logic_expr::LogicExpr,
+    numeric_expr::NumericData,
+    pattern_match::{self, ArgBind},
+    program,
+};
+
+// Pattern matrix analysis of function heads, after
+// Maranget, "Warnings for pattern matching"
+//
+// Each bind is a row of its head patterns. A bind only counts as a row when
+// one of its filters has no guard, since any guard may fail at runtime, and
+// a head naming a variable twice is like a guard that the values are equal.
+// Values are untyped, so the constructors in a column are never complete:
+// a function taking [] and [H|T] still misses every non-list argument.
+
+#[derive(Debug, Clone, PartialEq)]
+enum Pat {
+    Wild,
+    Int(i64),
+    Bool(bool),
+    Atom(Vec<char>),
+    Nil,
+    Cons(Box<Pat>, Box<Pat>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Ctor {
+    Int(i64),
+    Bool(bool),
+    Atom(Vec<char>),
+    Nil,
+    Cons,
+}
+
+// a value no bind takes
+#[derive(Debug, Clone)]
+pub enum Witness {
+    Any,
+    Int(i64),
+    Bool(bool),
+    Atom(Vec<char>),
+    Nil,
+    Cons(Box<Witness>, Box<Witness>),
+    // a value outside all the constructors a column mentions, like "a non-list"
+    Other(String),
+}
+
+// arguments that no bind is guaranteed to take
+pub fn missing_case(binds: &[program::Bind]) -> Option<Vec<Witness>> {
+    let arity = match binds.first() {
+        Some(b) => b.pattern.len(),
+        None => return None,
+    };
+    let rows: Vec<Vec<Pat>> = binds
+        .iter()
+        .filter(|b| is_unguarded(b) && b.pattern.len() == arity)
+        .map(|b| b.pattern.iter().map(to_pat).collect())
+        .collect();
+    return missing(&rows, arity);
+}
+
+// indexes of binds earlier unguarded binds always match first
+pub fn unreachable_clauses(binds: &[program::Bind]) -> Vec<usize> {
+    let mut res = Vec::new();
+    let mut rows: Vec<Vec<Pat>> = Vec::new();
+    for (i, b) in binds.iter().enumerate() {
+        let row: Vec<Pat> = b.pattern.iter().map(to_pat).collect();
+        let earlier: Vec<Vec<Pat>> = rows
+            .iter()
+            .filter(|r| r.len() == row.len())
+            .cloned()
+            .collect();
+        if !useful(&earlier, &row) {
+            res.push(i);
+        }
+        if is_unguarded(b) {
+            rows.push(row);
+        }
+    }
+    return res;
+}
+
+fn is_unguarded(b: &program::Bind) -> bool {
+    return b.filters.iter().any(|f| matches!(f.head, LogicExpr::True))
+        && !pattern_match::has_repeats(&b.pattern);
+}
+
+fn to_pat(b: &ArgBind) -> Pat {
+    match b {
+        ArgBind::Identifier(_) => Pat::Wild,
+        ArgBind::Emptylist => Pat::Nil,
+        ArgBind::ConstPattern(d) => data_to_pat(d),
+        ArgBind::ListPattern { head, tail } => {
+            Pat::Cons(Box::new(to_pat(head)), Box::new(to_pat(tail)))
+        }
+    }
+}
+
+fn data_to_pat(d: &Data) -> Pat {
+    match d {
+        Data::Number(NumericData::Int(i)) => Pat::Int(*i),
+        Data::Boolean(b) => Pat::Bool(*b),
+        Data::Atom(a) => Pat::Atom(a.clone()),
+        Data::Emptylist => Pat::Nil,
+        Data::List(h, t) => Pat::Cons(Box::new(data_to_pat(h)), Box::new(data_to_pat(t))),
+        // only one function is equal to a function constant
+        Data::FunctionPointer(_) => Pat::Wild,
+    }
+}
+
+fn head_ctor(p: &Pat) -> Option<Ctor> {
+    match p {
+        Pat::Wild => None,
+        Pat::Int(i) => Some(Ctor::Int(*i)),
+        Pat::Bool(b) => Some(Ctor::Bool(*b)),
+        Pat::Atom(a) => Some(Ctor::Atom(a.clone())),
+        Pat::Nil => Some(Ctor::Nil),
+        Pat::Cons(_, _) => Some(Ctor::Cons),
+    }
+}
+
+fn ctor_arity(c: &Ctor) -> usize {
+    match c {
+        Ctor::Cons => 2,
+        _ => 0,
+    }
+}
+
+// rows that can match a value built with c, with c's fields in front
+fn specialize(rows: &[Vec<Pat>], c: &Ctor) -> Vec<Vec<Pat>> {
+    return rows
+        .iter()
+        .filter_map(|row| {
+            let rest = &row[1..row.len()];
+            let mut fields = match &row[0] {
+                Pat::Wild => vec![Pat::Wild; ctor_arity(c)],
+                Pat::Cons(h, t) if *c == Ctor::Cons => vec![*h.clone(), *t.clone()],
+                p if head_ctor(p).as_ref() == Some(c) => Vec::new(),
+                _ => return None,
+            };
+            fields.extend_from_slice(rest);
+            Some(fields)
+        })
+        .collect();
+}
+
+// rows that match anything in the first column, without that column
+fn default_rows(rows: &[Vec<Pat>]) -> Vec<Vec<Pat>> {
+    return rows
+        .iter()
+        .filter(|row| row[0] == Pat::Wild)
+        .map(|row| row[1..row.len()].to_vec())
+        .collect();
+}
+
+// can a value matching q get past every row
+fn useful(rows: &[Vec<Pat>], q: &[Pat]) -> bool {
+    if q.is_empty() {
+        return rows.is_empty();
+    }
+    match head_ctor(&q[0]) {
+        Some(c) => {
+            let mut q1 = match &q[0] {
+                Pat::Cons(h, t) => vec![*h.clone(), *t.clone()],
+                _ => Vec::new(),
+            };
+            q1.extend_from_slice(&q[1..q.len()]);
+            return useful(&specialize(rows, &c), &q1);
+        }
+        // the constructors of a column are never complete,
+        // so only rows matching anything can stop a wildcard
+        None => return useful(&default_rows(rows), &q[1..q.len()]),
+    }
+}
+
+// a list of arguments no row matches, trying the constructors in use first
+// since "[] as first argument" says more than "a non-list first argument"
+fn missing(rows: &[Vec<Pat>], n: usize) -> Option<Vec<Witness>> {
+    if n == 0 {
+        if rows.is_empty() {
+            return Some(Vec::new());
+        } else {
+            return None;
+        }
+    }
+    let sigma: Vec<Ctor> = rows.iter().filter_map(|r| head_ctor(&r[0])).collect();
+    for c in ctors_to_try(&sigma) {
+        let arity = ctor_arity(&c);
+        if let Some(mut w) = missing(&specialize(rows, &c), arity + n - 1) {
+            let rest = w.split_off(arity);
+            let head = match c {
+                Ctor::Int(i) => Witness::Int(i),
+                Ctor::Bool(b) => Witness::Bool(b),
+                Ctor::Atom(a) => Witness::Atom(a),
+                Ctor::Nil => Witness::Nil,
+                Ctor::Cons => {
+                    let t = w.pop().unwrap();
+                    let h = w.pop().unwrap();
+                    Witness::Cons(Box::new(h), Box::new(t))
+                }
+            };
+            let mut res = vec![head];
+            res.extend(rest);
+            return Some(res);
+        }
+    }
+    if let Some(w) = missing(&default_rows(rows), n - 1) {
+        let mut res = vec![other(&sigma)];
+        res.extend(w);
+        return Some(res);
+    }
+    return None;
+}
+
+// the constructors used, plus their siblings, [] brings [_|_] and true brings false
+fn ctors_to_try(sigma: &[Ctor]) -> Vec<Ctor> {
+    let mut res: Vec<Ctor> = Vec::new();
+    for c in sigma.iter() {
+        let family = match c {
+            Ctor::Nil | Ctor::Cons => vec![Ctor::Nil, Ctor::Cons],
+            Ctor::Bool(_) => vec![Ctor::Bool(true), Ctor::Bool(false)],
+            c => vec![c.clone()],
+        };
+        for f in family {
+            if !res.contains(&f) {
+                res.push(f);
+            }
+        }
+    }
+    return res;
+}
+
+fn other(sigma: &[Ctor]) -> Witness {
+    if sigma.is_empty() {
+        return Witness::Any;
+    }
+    let ints: Vec<String> = sigma
+        .iter()
+        .filter_map(|c| match c {
+            Ctor::Int(i) => Some(i.to_string()),
+            _ => None,
+        })
+        .unique()
+        .collect();
+    if !ints.is_empty() {
+        return Witness::Other(format!("a number other than {}", ints.join(", ")));
+    }
+    let atoms: Vec<String> = sigma
+        .iter()
+        .filter_map(|c| match c {
+            Ctor::Atom(a) => Some(a.iter().collect()),
+            _ => None,
+        })
+        .unique()
+        .collect();
+    if !atoms.is_empty() {
+        return Witness::Other(format!("an atom other than {}", atoms.join(", ")));
+    }
+    let lists = sigma.iter().any(|c| *c == Ctor::Nil || *c == Ctor::Cons);
+    let bools = sigma.iter().any(|c| matches!(c, Ctor::Bool(_)));
+    match (lists, bools) {
+        (true, false) => Witness::Other("a non-list".to_string()),
+        (false, true) => Witness::Other("a non-boolean".to_string()),
+        _ => Witness::Other("a value that is neither a list nor a boolean".to_string()),
+    }
+}
+
+pub fn format_witness(w: &Witness) -> String {
+    match w {
+        Witness::Any => "_".to_string(),
+        Witness::Int(i) => i.to_string(),
+        Witness::Bool(b) => b.to_string(),
+        Witness::Atom(a) => a.iter().collect(),
+        Witness::Nil => "[]".to_string(),
+        Witness::Cons(h, t) => format!("[{}|{}]", format_witness(h), format_witness(t)),
+        Witness::Other(desc) => desc.clone(),
+    }
+}
+
+// "a non-list first argument", or the whole argument list when more than one matters
+pub fn describe_missing(name: &str, w: &[Witness]) -> String {
+    let set: Vec<usize> = (0..w.len())
+        .filter(|i| !matches!(w[*i], Witness::Any))
+        .collect();
+    match set.as_slice() {
+        [] => "missing case: any arguments when every guard fails".to_string(),
+        [i] => match &w[*i] {
+            Witness::Other(desc) => format!("missing case: {} {} argument", desc, ordinal(*i)),
+            x => format!(
+                "missing case: {} as {} argument",
+                format_witness(x),
+                ordinal(*i)
+            ),
+        },
+        _ => format!(
+            "missing case: {}({})",
+            name,
+            w.iter().map(format_witness).join(", ")
+        ),
+    }
+}
+
+fn ordinal(i: usize) -> String {
+    match i {
+        0 => "first".to_string(),
+        1 => "second".to_string(),
+        2 => "third".to_string(),
+        n => format!("{}th", n + 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::function::FunctionName;
+
+    // the binds of f, each head returns ok
+    fn binds(heads: &[&str]) -> Vec<program::Bind> {
+        let clauses: String = heads
+            .iter()
+            .map(|h| format!("    {}\n    / ok \\\n", h))
+            .collect();
+        let src = format!("f:\n{}end\n", clauses);
+        let code = program::from_source(&src);
+        return code[&FunctionName::Static(vec!['f'])].binds.clone();
+    }
+
+    fn missing(heads: &[&str]) -> Option<String> {
+        return missing_case(&binds(heads)).map(|w| describe_missing("f", &w));
+    }
+
+    #[test]
+    fn a_variable_covers_everything() {
+        assert_eq!(missing(&["([])", "(X)"]), None);
+        assert_eq!(missing(&["(_, [])", "(_, [_|_])", "(_, _)"]), None);
+    }
+
+    #[test]
+    fn lists_still_miss_non_lists() {
+        assert_eq!(
+            missing(&["([])", "([H|T])"]),
+            Some("missing case: a non-list first argument".to_string())
+        );
+    }
+
+    #[test]
+    fn a_missing_constructor_is_named() {
+        assert_eq!(
+            missing(&["([])"]),
+            Some("missing case: [_|_] as first argument".to_string())
+        );
+        assert_eq!(
+            missing(&["(0)", "(1)"]),
+            Some("missing case: a number other than 0, 1 first argument".to_string())
+        );
+    }
+
+    #[test]
+    fn more_than_one_argument_prints_the_call() {
+        assert_eq!(
+            missing(&["([], _)", "(_, [])"]),
+            Some("missing case: f([_|_], [_|_])".to_string())
+        );
+    }
+
+    #[test]
+    fn guarded_binds_cover_nothing() {
+        assert_eq!(
+            missing(&["(X)\n    { X > 0 }"]),
+            Some("missing case: any arguments when every guard fails".to_string())
+        );
+    }
+
+    #[test]
+    fn binds_behind_a_wider_one_are_unreachable() {
+        assert_eq!(unreachable_clauses(&binds(&["(X)", "(0)"])), vec![1]);
+        assert_eq!(
+            unreachable_clauses(&binds(&["([H|T], _)", "([1|_], [])", "([], X)"])),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn guards_and_repeated_variables_hide_nothing() {
+        assert!(unreachable_clauses(&binds(&["(X)\n    { X > 0 }", "(0)"])).is_empty());
+        assert!(unreachable_clauses(&binds(&["(X, X)", "(1, 1)"])).is_empty());
+    }
+}
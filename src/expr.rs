@@ -1,10 +1,12 @@
-use std::iter::FromIterator;
+use std::{iter::FromIterator, sync::Arc};
 
 use crate::eval::{self, Data, ProgramState};
 use crate::logic_expr::{self, LogicExpr};
 use crate::numeric_expr::{NumericData, NumericExpr};
 use crate::segments::{self, Segment};
 use crate::tokens::{self, Token};
+use crate::visit::{self, Visitor};
+use crate::vm::RuntimeErrorKind;
 use crate::{call_levels, function, numeric_expr, pattern_match, program};
 
 //Un-typed Expressions
@@ -21,6 +23,34 @@ pub enum Expr {
         arg: Box<Expr>,
         rest: Box<Expr>,
     },
+    // the value of body, or of the first catch clause taking what it raised
+    Try {
+        body: Box<Expr>,
+        catches: Vec<Catch>,
+    },
+    // the value of the first clause whose pattern and guard take arg
+    Case {
+        arg: Box<Expr>,
+        clauses: Vec<Clause>,
+    },
+    // the value of the first branch whose guard holds, only that branch is evaluated
+    If(Vec<program::Filter>),
+}
+
+// Class:Pattern -> code
+#[derive(Debug, Clone)]
+pub struct Catch {
+    pub class: pattern_match::ArgBind,
+    pub pattern: pattern_match::ArgBind,
+    pub code: Expr,
+}
+
+// Pattern { guard } / code \, a pattern whose guards all fail falls through
+// to the next clause like a bind of a function does
+#[derive(Debug, Clone)]
+pub struct Clause {
+    pub pattern: pattern_match::ArgBind,
+    pub filters: Vec<program::Filter>,
 }
 
 pub fn eval(c: eval::Program, p: eval::ProgramState, expr: Expr) -> eval::Data {
@@ -33,7 +63,8 @@ pub fn eval(c: eval::Program, p: eval::ProgramState, expr: Expr) -> eval::Data {
         Expr::ListBuild(h, t) => {
             let h1 = eval(c.clone(), p.clone(), *h);
             let t1 = eval(c.clone(), p.clone(), *t);
-            return eval::Data::List(Box::new(h1), Box::new(t1));
+            eval::cons_cell();
+            return eval::Data::List(Arc::new(h1), Arc::new(t1));
         }
         Expr::NumericExpr(nexpr) => {
             println!("NEXPR{:#?}\n", nexpr);
@@ -47,23 +78,53 @@ pub fn eval(c: eval::Program, p: eval::ProgramState, expr: Expr) -> eval::Data {
         } => {
             let vals = vec![eval(c.clone(), p.clone(), *arg)];
             let binds = vec![*bind];
-            if let Some(p1) = eval::try_bind_with_state(p.clone(), binds, vals) {
+            if let Some(p1) = eval::try_bind_with_state(p.clone(), binds, vals.clone()) {
                 return eval(c, p1, *rest);
             } else {
-                println!("Bind Failed{:#?}{:#?}\n", expr, p);
-                unimplemented!()
+                eval::fail(RuntimeErrorKind::BadMatch(vals[0].clone()))
+            }
+        }
+        Expr::Try { body, catches } => match eval::catch(|| eval(c.clone(), p.clone(), *body)) {
+            Ok(v) => return v,
+            Err(raised) => {
+                for catch in catches.into_iter() {
+                    let binds = vec![catch.class, catch.pattern];
+                    let vals = vec![raised.class.atom(), raised.term.clone()];
+                    if let Some(p1) = eval::try_bind_with_state(p.clone(), binds, vals) {
+                        return eval(c, p1, catch.code);
+                    }
+                }
+                eval::raise(raised.class, raised.term)
+            }
+        },
+        Expr::Case { arg, clauses } => {
+            let v = eval(c.clone(), p.clone(), *arg);
+            for clause in clauses.into_iter() {
+                let binds = vec![clause.pattern];
+                if let Some(p1) = eval::try_bind_with_state(p.clone(), binds, vec![v.clone()]) {
+                    if let Some(code) = eval::get_callpath(c.clone(), p1.clone(), clause.filters) {
+                        return eval(c, p1, code);
+                    }
+                }
             }
+            eval::fail(RuntimeErrorKind::CaseClause(v))
         }
+        Expr::If(branches) => match eval::get_callpath(c.clone(), p.clone(), branches) {
+            Some(code) => return eval(c, p, code),
+            None => eval::fail(RuntimeErrorKind::IfClause),
+        },
     }
 }
 
+// every function called anywhere inside the expression
+pub fn calls(expr: &Expr, acc: &mut Vec<function::FunctionName>) {
+    visit::Calls(acc).expr(expr);
+}
+
 pub fn var_lookup(name: Vec<char>, p: eval::ProgramState) -> eval::Data {
     match p.get(&name) {
         Some(v) => return v.clone(),
-        _ => {
-            println!("UNBOUND VAR{:#?}\n{:#?}\n", p, name);
-            unimplemented!()
-        }
+        _ => eval::fail(RuntimeErrorKind::Unbound(name)),
     }
 }
 
@@ -105,6 +166,19 @@ pub fn call_levels_to_expr(level: call_levels::CallLevel) -> Expr {
                     .collect();
                 return Expr::LogicExpr(LogicExpr::EQ(subs));
             }
+            Token::LT
+            | Token::GT
+            | Token::LTE
+            | Token::GTE
+            | Token::NEQ
+            | Token::NOT
+            | Token::AND
+            | Token::OR
+            | Token::XOR
+            | Token::ANDALSO
+            | Token::ORELSE => {
+                return Expr::LogicExpr(logic_expr::call_levels_to_logic_expr(level));
+            }
             Token::Add | Token::SUB | Token::MUL => {
                 return Expr::NumericExpr(numeric_expr::call_levels_to_num_expr(level));
             }
@@ -114,6 +188,7 @@ pub fn call_levels_to_expr(level: call_levels::CallLevel) -> Expr {
             }
         },
         call_levels::CallLevel::Identifier(v) => return string_token_to_expr(v),
+        call_levels::CallLevel::FunRef(f) => return Expr::Constant(eval::Data::FunctionPointer(f)),
         call_levels::CallLevel::Call(fname, sublevels) => {
             let subs = sublevels
                 .into_iter()
@@ -131,6 +206,14 @@ pub fn call_levels_to_expr(level: call_levels::CallLevel) -> Expr {
                 rest: Box::new(rest1),
             };
         }
+        // a statement is an assignment to _, its value is dropped
+        call_levels::CallLevel::Then { first, rest } => {
+            return Expr::Assign {
+                pattern: Box::new(pattern_match::ArgBind::Identifier(vec!['_'])),
+                arg: Box::new(call_levels_to_expr(*first)),
+                rest: Box::new(call_levels_to_expr(*rest)),
+            };
+        }
         call_levels::CallLevel::ListBuild(sublevels) => {
             let subs: Vec<_> = sublevels
                 .into_iter()
@@ -138,18 +221,57 @@ pub fn call_levels_to_expr(level: call_levels::CallLevel) -> Expr {
                 .collect();
             return expr_vec_to_list(&subs);
         }
-        _ => {
-            println!("call_levels_to_expr {:#?}\n", level);
-            unimplemented!()
+        call_levels::CallLevel::Emptylist => return Expr::Constant(eval::Data::Emptylist),
+        call_levels::CallLevel::Try { body, catches } => {
+            let catches = catches
+                .into_iter()
+                .map(|x| Catch {
+                    class: pattern_match::call_level_to_argbind(x.class.unwrap_or(
+                        call_levels::CallLevel::Identifier("throw".chars().collect()),
+                    )),
+                    pattern: pattern_match::call_level_to_argbind(x.pattern),
+                    code: call_levels_to_expr(x.body),
+                })
+                .collect();
+            return Expr::Try {
+                body: Box::new(call_levels_to_expr(*body)),
+                catches: catches,
+            };
+        }
+        call_levels::CallLevel::Case { arg, clauses } => {
+            let clauses = clauses
+                .into_iter()
+                .map(|x| Clause {
+                    pattern: pattern_match::call_level_to_argbind(x.pattern),
+                    filters: x.filters.into_iter().map(filter_level_to_filter).collect(),
+                })
+                .collect();
+            return Expr::Case {
+                arg: Box::new(call_levels_to_expr(*arg)),
+                clauses: clauses,
+            };
+        }
+        call_levels::CallLevel::If(branches) => {
+            return Expr::If(branches.into_iter().map(filter_level_to_filter).collect());
         }
     }
 }
 
+// no guard -> always true guard, like a filter of a bind
+fn filter_level_to_filter(f: call_levels::FilterLevel) -> program::Filter {
+    let head = match f.guard {
+        Some(g) => logic_expr::call_levels_to_logic_expr(g),
+        None => LogicExpr::True,
+    };
+    return program::Filter {
+        head: head,
+        code: call_levels_to_expr(f.body),
+    };
+}
+
 fn expr_vec_to_list(v: &[Expr]) -> Expr {
-    println!("listbuild {:#?}\n", v);
     match (v.get(0), v.get(1)) {
         (Some(x), Some(_)) => {
-            println!("listbuild {:#?}\n", x);
             return Expr::ListBuild(
                 Box::new(x.clone()),
                 Box::new(expr_vec_to_list(&v[1..v.len()])),
@@ -171,6 +293,8 @@ pub fn string_token_to_expr(chars: Vec<char>) -> Expr {
         return Expr::Constant(eval::Data::Number(NumericData::Int(n)));
     } else if let Ok(n) = text.parse::<bool>() {
         return Expr::Constant(eval::Data::Boolean(n));
+    } else if chars.first().map_or(false, |c| c.is_lowercase()) {
+        return Expr::Constant(eval::Data::Atom(chars));
     } else {
         return Expr::Identifier(chars);
     }
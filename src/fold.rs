@@ -0,0 +1,399 @@
+use std::{cmp::Ordering, sync::Arc};
+
+use itertools::Itertools;
+
+use crate::{
+    eval::{self, Data},
+    expr::Expr,
+    logic_expr::{CompareOp, LogicExpr},
+    numeric_expr::{ArrayNumOp, NumericData, NumericExpr, OrderedNumOp},
+    program,
+    visit::{self, Rewriter},
+};
+
+// Constant folding and algebraic simplification, run once after program::to_program
+//
+// 1 + 2 * 3 -> 7, X + 0 -> X, X * 1 -> X, true && X -> X, 5 == 5 -> true
+// X + 0 stays a numeric X and true && X a logic X, they still fail when X
+// is not a number or not a boolean, the tests below run both versions.
+// A filter whose guard folds to false is dropped, so are the filters after
+// one whose guard folds to true, since they can never be picked.
+//
+// Nothing that is evaluated at runtime is dropped unless its value can not
+// matter: false xor f(X) keeps f(X) since xor evaluates every operand,
+// while false && f(X) becomes false. Arithmetic that would overflow
+// or divide by zero is left for the evaluator to fail on.
+
+pub fn fold_program(code: eval::Program) -> eval::Program {
+    return code
+        .into_iter()
+        .map(|(name, fun)| (name, fold_fun(fun)))
+        .collect();
+}
+
+fn fold_fun(f: program::Fun) -> program::Fun {
+    let f = visit::rewrite_fun(&mut Fold, f);
+    return program::Fun {
+        binds: f
+            .binds
+            .into_iter()
+            .filter(|b| !b.filters.is_empty())
+            .collect(),
+        ..f
+    };
+}
+
+// children are folded first, each node is then simplified on its own
+struct Fold;
+
+impl Rewriter for Fold {
+    fn expr(&mut self, e: Expr) -> Expr {
+        return fold_expr(visit::rewrite_expr(self, e));
+    }
+
+    fn numeric(&mut self, e: NumericExpr) -> NumericExpr {
+        return fold_numeric(visit::rewrite_numeric(self, e));
+    }
+
+    fn logic(&mut self, e: LogicExpr) -> LogicExpr {
+        return fold_logic(visit::rewrite_logic(self, e));
+    }
+
+    fn filters(&mut self, v: Vec<program::Filter>) -> Vec<program::Filter> {
+        return fold_filters(visit::rewrite_filters(self, v));
+    }
+}
+
+// the filters of a bind, a case clause or an if that can still be picked
+fn fold_filters(v: Vec<program::Filter>) -> Vec<program::Filter> {
+    let mut filters = Vec::new();
+    for filter in v.into_iter() {
+        match filter.head {
+            LogicExpr::False => continue,
+            LogicExpr::True => {
+                filters.push(filter);
+                break;
+            }
+            _ => filters.push(filter),
+        }
+    }
+    return filters;
+}
+
+fn fold_expr(e: Expr) -> Expr {
+    match e {
+        Expr::ListBuild(h, t) => match (*h, *t) {
+            (Expr::Constant(h), Expr::Constant(t)) => {
+                Expr::Constant(Data::List(Arc::new(h), Arc::new(t)))
+            }
+            (h, t) => Expr::ListBuild(Box::new(h), Box::new(t)),
+        },
+        Expr::NumericExpr(NumericExpr::Const(c)) => Expr::Constant(Data::Number(c)),
+        Expr::LogicExpr(LogicExpr::True) => Expr::Constant(Data::Boolean(true)),
+        Expr::LogicExpr(LogicExpr::False) => Expr::Constant(Data::Boolean(false)),
+        // like a bind, a clause without filters left is dropped
+        Expr::Case { arg, clauses } => Expr::Case {
+            arg: arg,
+            clauses: clauses
+                .into_iter()
+                .filter(|c| !c.filters.is_empty())
+                .collect(),
+        },
+        // an if whose first guard folds to true is that branch
+        Expr::If(branches) => match branches.first() {
+            Some(program::Filter {
+                head: LogicExpr::True,
+                code,
+            }) => code.clone(),
+            _ => Expr::If(branches),
+        },
+        e => e,
+    }
+}
+
+fn fold_numeric(e: NumericExpr) -> NumericExpr {
+    match e {
+        NumericExpr::Negate(x) => match *x {
+            NumericExpr::Const(NumericData::Int(i)) if i.checked_neg().is_some() => {
+                NumericExpr::Const(NumericData::Int(-i))
+            }
+            x => NumericExpr::Negate(Box::new(x)),
+        },
+        NumericExpr::ArrayOperator { op, v } => {
+            let (identity, combine): (i64, fn(i64, i64) -> Option<i64>) = match op {
+                ArrayNumOp::ADD => (0, i64::checked_add),
+                ArrayNumOp::MUL => (1, i64::checked_mul),
+            };
+            let (consts, mut rest): (Vec<NumericExpr>, Vec<NumericExpr>) = v
+                .iter()
+                .cloned()
+                .partition(|x| matches!(x, NumericExpr::Const(_)));
+            let acc = consts.iter().try_fold(identity, |a, x| match x {
+                NumericExpr::Const(NumericData::Int(i)) => combine(a, *i),
+                _ => None,
+            });
+            match acc {
+                Some(a) if a != identity || rest.is_empty() => {
+                    rest.push(NumericExpr::Const(NumericData::Int(a)))
+                }
+                Some(_) => {}
+                // the constants overflow, the evaluator reports it
+                None => return NumericExpr::ArrayOperator { op: op, v: v },
+            }
+            if rest.len() == 1 {
+                return rest.pop().unwrap();
+            }
+            return NumericExpr::ArrayOperator { op: op, v: rest };
+        }
+        NumericExpr::OrderedOperator { op, left, right } => {
+            let folded = match (&op, left.as_ref(), right.as_ref()) {
+                (
+                    OrderedNumOp::SUB,
+                    NumericExpr::Const(NumericData::Int(l)),
+                    NumericExpr::Const(NumericData::Int(r)),
+                ) => l.checked_sub(*r),
+                (
+                    OrderedNumOp::DIV,
+                    NumericExpr::Const(NumericData::Int(l)),
+                    NumericExpr::Const(NumericData::Int(r)),
+                ) => l.checked_div(*r),
+                _ => None,
+            };
+            match (folded, &op, right.as_ref()) {
+                (Some(i), _, _) => NumericExpr::Const(NumericData::Int(i)),
+                (_, OrderedNumOp::SUB, NumericExpr::Const(NumericData::Int(0)))
+                | (_, OrderedNumOp::DIV, NumericExpr::Const(NumericData::Int(1))) => *left,
+                _ => NumericExpr::OrderedOperator {
+                    op: op,
+                    left: left,
+                    right: right,
+                },
+            }
+        }
+        e => e,
+    }
+}
+
+fn fold_logic(e: LogicExpr) -> LogicExpr {
+    match e {
+        LogicExpr::NOT(x) => match *x {
+            LogicExpr::True => LogicExpr::False,
+            LogicExpr::False => LogicExpr::True,
+            x => LogicExpr::NOT(Box::new(x)),
+        },
+        // nothing after the first false is evaluated
+        LogicExpr::AND(v) => short_circuit(v, false, LogicExpr::AND),
+        LogicExpr::ANDALSO(v) => short_circuit(v, false, LogicExpr::ANDALSO),
+        // nothing after the first true
+        LogicExpr::OR(v) => short_circuit(v, true, LogicExpr::OR),
+        LogicExpr::ORELSE(v) => short_circuit(v, true, LogicExpr::ORELSE),
+        LogicExpr::XOR(v) => {
+            if v.iter().all(is_constant) {
+                let odd = v.iter().filter(|x| matches!(x, LogicExpr::True)).count() % 2 == 1;
+                return to_logic(odd);
+            }
+            let v = v
+                .into_iter()
+                .filter(|x| !matches!(x, LogicExpr::False))
+                .collect();
+            return join(v, LogicExpr::XOR, LogicExpr::False);
+        }
+        LogicExpr::EQ(v) => match constants(&v) {
+            Some(c) => to_logic(c.into_iter().all_equal()),
+            None => LogicExpr::EQ(v),
+        },
+        LogicExpr::NEQ(l, r) => match (l.as_ref(), r.as_ref()) {
+            (Expr::Constant(a), Expr::Constant(b)) => to_logic(a != b),
+            _ => LogicExpr::NEQ(l, r),
+        },
+        LogicExpr::Compare { op, left, right } => match (left.as_ref(), right.as_ref()) {
+            (Expr::Constant(l), Expr::Constant(r)) => {
+                let order = eval::term_order(l, r);
+                to_logic(match op {
+                    CompareOp::LT => order == Ordering::Less,
+                    CompareOp::GT => order == Ordering::Greater,
+                    CompareOp::LTE => order != Ordering::Greater,
+                    CompareOp::GTE => order != Ordering::Less,
+                })
+            }
+            _ => LogicExpr::Compare {
+                op: op,
+                left: left,
+                right: right,
+            },
+        },
+        e => e,
+    }
+}
+
+fn is_constant(e: &LogicExpr) -> bool {
+    return matches!(e, LogicExpr::True | LogicExpr::False);
+}
+
+fn to_logic(b: bool) -> LogicExpr {
+    if b {
+        return LogicExpr::True;
+    } else {
+        return LogicExpr::False;
+    }
+}
+
+// drops the operands that can not decide the result and everything after
+// one that always does, stop is the value that ends the evaluation
+fn short_circuit(v: Vec<LogicExpr>, stop: bool, op: fn(Vec<LogicExpr>) -> LogicExpr) -> LogicExpr {
+    let mut kept = Vec::new();
+    for x in v.into_iter() {
+        match x {
+            LogicExpr::True | LogicExpr::False if is_true(&x) != stop => {}
+            LogicExpr::True | LogicExpr::False => {
+                kept.push(x);
+                break;
+            }
+            x => kept.push(x),
+        }
+    }
+    return join(kept, op, to_logic(!stop));
+}
+
+fn is_true(x: &LogicExpr) -> bool {
+    return matches!(x, LogicExpr::True);
+}
+
+// an operator over what is left, its identity when nothing is left
+fn join(
+    mut v: Vec<LogicExpr>,
+    op: fn(Vec<LogicExpr>) -> LogicExpr,
+    identity: LogicExpr,
+) -> LogicExpr {
+    match v.len() {
+        0 => identity,
+        1 => v.pop().unwrap(),
+        _ => op(v),
+    }
+}
+
+fn constants(v: &[Expr]) -> Option<Vec<&Data>> {
+    return v
+        .iter()
+        .map(|x| match x {
+            Expr::Constant(c) => Some(c),
+            _ => None,
+        })
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::function::FunctionName;
+
+    // boom throws so a test can tell whether a call was kept
+    const HELPERS: &str = "id:\n    (X)\n    / X \\\nend\n\
+                           boom:\n    (X)\n    / throw(X) \\\nend\n";
+
+    fn program(body: &str) -> eval::Program {
+        let src = format!("{}main:\n    ()\n    / {} \\\nend\n", HELPERS, body);
+        return program::from_source(&src);
+    }
+
+    // folding never changes what main gives or raises
+    fn same_result(body: &str) -> Result<Data, eval::Raised> {
+        let code = program(body);
+        let plain = eval::run_main(&code);
+        let folded = eval::run_main(&fold_program(code));
+        assert_eq!(plain, folded, "folding changed the result of {}", body);
+        return folded;
+    }
+
+    fn folded_main(body: &str) -> Expr {
+        let code = fold_program(program(body));
+        let main = &code[&FunctionName::Static("main".chars().collect())];
+        return main.binds[0].filters[0].code.clone();
+    }
+
+    fn number(i: i64) -> Data {
+        return Data::Number(NumericData::Int(i));
+    }
+
+    fn error_kind(r: Result<Data, eval::Raised>) -> Data {
+        match r {
+            Err(eval::Raised {
+                term: Data::List(kind, _),
+                ..
+            }) => return Arc::unwrap_or_clone(kind),
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn constants_fold() {
+        assert_eq!(same_result("1 + 2 * 3"), Ok(number(7)));
+        assert!(matches!(folded_main("1 + 2 * 3"), Expr::Constant(d) if d == number(7)));
+        assert_eq!(same_result("-(4 - 6)"), Ok(number(2)));
+        assert_eq!(
+            same_result("[1 < 2, 3 == 3, 1 =/= 1]"),
+            same_result("[true, true, false]")
+        );
+    }
+
+    #[test]
+    fn identities_keep_their_number_check() {
+        for body in ["id(true) + 0", "id(true) * 1", "id(true) - 0", "0 + id([])"] {
+            assert_eq!(
+                error_kind(same_result(body)),
+                Data::Atom("badarith".chars().collect())
+            );
+        }
+        assert_eq!(same_result("id(4) + 0"), Ok(number(4)));
+        // X + 0 is not X, it still fails for anything but a number
+        assert!(matches!(folded_main("id(4) + 0"), Expr::NumericExpr(_)));
+    }
+
+    #[test]
+    fn connectives_keep_their_boolean_check() {
+        for body in ["true && id(5)", "false || id(5)", "false xor id(5)"] {
+            assert_eq!(
+                error_kind(same_result(body)),
+                Data::Atom("badbool".chars().collect())
+            );
+        }
+        assert!(matches!(folded_main("true && id(5)"), Expr::LogicExpr(_)));
+    }
+
+    #[test]
+    fn calls_that_can_not_matter_are_dropped() {
+        assert_eq!(same_result("false && boom(1)"), Ok(Data::Boolean(false)));
+        assert_eq!(same_result("true || boom(1)"), Ok(Data::Boolean(true)));
+        assert!(matches!(
+            folded_main("false && boom(1)"),
+            Expr::Constant(Data::Boolean(false))
+        ));
+    }
+
+    #[test]
+    fn calls_that_run_are_kept() {
+        // xor evaluates every operand, so the throw still happens
+        let thrown = same_result("false xor boom(1)");
+        assert_eq!(thrown.map_err(|r| r.term), Err(number(1)));
+        assert!(matches!(same_result("[boom(2), 1 + 2]"), Err(_)));
+    }
+
+    #[test]
+    fn overflow_is_left_to_the_evaluator() {
+        let body = "9223372036854775807 + 1";
+        assert_eq!(
+            error_kind(same_result(body)),
+            Data::Atom("badarith".chars().collect())
+        );
+        assert!(matches!(folded_main(body), Expr::NumericExpr(_)));
+    }
+
+    #[test]
+    fn branches_that_can_not_be_taken_are_dropped() {
+        let body = "if { 1 > 2 } / boom(1) \\ { true } / id(3) \\ / boom(2) \\ end";
+        assert_eq!(same_result(body), Ok(number(3)));
+        assert!(matches!(folded_main(body), Expr::Call(_, _)));
+        let body = "case id(1) of 1 { 1 == 2 } / a \\ _ / b \\ end";
+        assert_eq!(same_result(body), Ok(Data::Atom(vec!['b'])));
+    }
+}
@@ -0,0 +1,403 @@
+use itertools::Itertools;
+
+use crate::{
+    call_levels::{self, CallLevel},
+    capability::Capabilities,
+    eval::Data,
+    numeric_expr::NumericData,
+    pattern_match::ArgBind,
+    program::{self, FunBind, FunFilter, FunFun, Functions},
+    segments::{self, Segment},
+    tokens::{self, Token},
+    types,
+};
+
+// Renders functions back into the `name: .. end` syntax
+//
+// mathfun:
+//     (A, B)
+//     { (A - B) == 5 }
+//     / 0 \
+//     / 1 + mul(A, B) \
+// end
+//
+// Bodies with assignments or statements get one per line between a lone `/` and `\`.
+// A -spec goes on the line above its function.
+// Comments between functions are kept as written, each right above the function after it.
+// A comment inside a function has no place in the parsed functions, such files are not formatted.
+
+const INDENT: &str = "    ";
+
+pub fn format_funs(funs: &[FunFun], context: &Functions) -> String {
+    return funs.iter().map(|f| format_fun(f, context)).join("\n");
+}
+
+// formats a whole file, keeping its comments
+// Err names the line of the function holding a comment that would be lost
+pub fn format_source(src: &str) -> Result<String, String> {
+    let segs = segments::matched_terminators_located(tokens::string_to_located(src.to_string()));
+    // for each comment, the number of functions before it
+    let mut placed = Vec::new();
+    let mut seen = 0;
+    for seg in segs.iter() {
+        match seg {
+            Segment::Clause {
+                head: Token::CommentStart,
+                ..
+            } => placed.push(seen),
+            Segment::Clause {
+                head: Token::FunStart,
+                body,
+                line,
+                ..
+            } => {
+                if segments::flatten(body.clone()).contains(&Token::CommentStart) {
+                    return Err(format!(
+                        "{}: comment inside a function, fmt would drop it",
+                        line
+                    ));
+                }
+                seen += 1;
+            }
+            _ => {}
+        }
+    }
+    let texts = comments(src);
+    if texts.len() != placed.len() {
+        return Err(" comments do not line up with the functions".to_string());
+    }
+    let funs = program::to_fun_list(segments::prune_clauses(segs));
+    let context = program::fun_list_to_funs(funs.clone());
+    let mut groups: Vec<String> = Vec::new();
+    let mut pending = String::new();
+    let mut next = 0;
+    for (i, f) in funs.iter().enumerate() {
+        while next < texts.len() && placed[next] <= i {
+            pending += &format!("{}\n", texts[next]);
+            next += 1;
+        }
+        groups.push(pending + &format_fun(f, &context));
+        pending = String::new();
+    }
+    let trailing: String = texts[next..].iter().map(|t| format!("{}\n", t)).collect();
+    if !trailing.is_empty() {
+        groups.push(trailing);
+    }
+    return Ok(groups.join("\n"));
+}
+
+// the text of each outermost /* .. */, split the way tokens.rs splits them
+fn comments(src: &str) -> Vec<String> {
+    let text: Vec<char> = src.chars().collect();
+    let mut ret = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    let mut i = 0;
+    while i < text.len() {
+        match (text[i], text.get(i + 1), text.get(i + 2)) {
+            ('=', Some('/'), Some('=')) => i += 3,
+            ('/', Some('*'), _) => {
+                if depth == 0 {
+                    start = i;
+                }
+                depth += 1;
+                i += 2;
+            }
+            ('*', Some('/'), _) if depth > 0 => {
+                depth -= 1;
+                i += 2;
+                if depth == 0 {
+                    ret.push(text[start..i].iter().collect());
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    return ret;
+}
+
+fn format_fun(f: &FunFun, context: &Functions) -> String {
+    let mut lines = Vec::new();
+    if let Some(spec) = &f.spec {
+        lines.push(types::format_spec(&f.name, spec));
+    }
+    lines.push(format!("{}:", f.name.iter().collect::<String>()));
+    for bind in f.binds.iter() {
+        lines.append(&mut format_bind(bind, context));
+    }
+    lines.push("end".to_string());
+    return lines.into_iter().map(|l| l + "\n").collect();
+}
+
+fn format_bind(b: &FunBind, context: &Functions) -> Vec<String> {
+    let mut lines = vec![format!(
+        "{}({})",
+        INDENT,
+        b.pattern.iter().map(format_pattern).join(", ")
+    )];
+    for filter in b.filters.iter() {
+        lines.append(&mut format_filter(filter, context));
+    }
+    return lines;
+}
+
+fn format_filter(f: &FunFilter, context: &Functions) -> Vec<String> {
+    // the layout does not depend on what the script is granted
+    let scope = call_levels::Scope::new(context.clone(), Capabilities::All);
+    let mut lines = Vec::new();
+    if !f.head.is_empty() {
+        let head = call_levels::segments_to_call_level(f.head.clone(), &scope);
+        lines.push(format!("{}{{ {} }}", INDENT, format_level(&head)));
+    }
+    let code = call_levels::segments_to_call_level(f.code.clone(), &scope);
+    match code {
+        CallLevel::Assign { .. } | CallLevel::Then { .. } => {
+            lines.push(format!("{}/", INDENT));
+            for statement in statements(&code) {
+                lines.push(format!("{}{}", INDENT, statement));
+            }
+            lines.push(format!("{}\\", INDENT));
+        }
+        _ => lines.push(format!("{}/ {} \\", INDENT, format_level(&code))),
+    }
+    return lines;
+}
+
+// a = f(1) log(a) a + 1 -> ["a = f(1)", "log(a)", "a + 1"]
+fn statements(level: &CallLevel) -> Vec<String> {
+    match level {
+        CallLevel::Assign { left, right, rest } => {
+            let mut res = vec![format!("{} = {}", format_level(left), format_level(right))];
+            res.append(&mut statements(rest));
+            return res;
+        }
+        CallLevel::Then { first, rest } => {
+            let mut res = vec![format_level(first)];
+            res.append(&mut statements(rest));
+            return res;
+        }
+        l => return vec![format_level(l)],
+    }
+}
+
+pub fn format_level(level: &CallLevel) -> String {
+    match level {
+        CallLevel::Identifier(i) => i.iter().collect(),
+        CallLevel::FunRef(f) => f.to_string(),
+        CallLevel::Emptylist => "[]".to_string(),
+        CallLevel::ListBuild(items) => match items.split_last() {
+            Some((CallLevel::Emptylist, init)) => {
+                format!("[{}]", init.iter().map(format_level).join(", "))
+            }
+            Some((tail, init)) => format!(
+                "[{}|{}]",
+                init.iter().map(format_level).join(", "),
+                format_level(tail)
+            ),
+            None => "[]".to_string(),
+        },
+        CallLevel::Call(fname, args) => {
+            format!("{}({})", fname, args.iter().map(format_level).join(", "))
+        }
+        // not a, -a
+        CallLevel::OpLevel(t, subs) if subs.len() == 1 => {
+            let operand = match &subs[0] {
+                CallLevel::OpLevel(_, v) if v.len() > 1 => format!("({})", format_level(&subs[0])),
+                s => format_level(s),
+            };
+            match t {
+                Token::NOT => format!("not {}", operand),
+                _ => format!("{}{}", token_text(t), operand),
+            }
+        }
+        CallLevel::OpLevel(t, subs) => subs
+            .iter()
+            .enumerate()
+            .map(|(i, s)| format_operand(s, t, i == 0))
+            .join(&format!(" {} ", token_text(t))),
+        CallLevel::Assign { .. } | CallLevel::Then { .. } => statements(level).join(" "),
+        // try f(X) catch error:[badmatch, V] -> V; T -> T end
+        CallLevel::Try { body, catches } => format!(
+            "try {} catch {} end",
+            format_level(body),
+            catches
+                .iter()
+                .map(|c| match &c.class {
+                    Some(class) => format!(
+                        "{}:{} -> {}",
+                        format_level(class),
+                        format_level(&c.pattern),
+                        format_level(&c.body)
+                    ),
+                    None => format!("{} -> {}", format_level(&c.pattern), format_level(&c.body)),
+                })
+                .join("; ")
+        ),
+        // case X of [] / 0 \ [H|_] { H > 0 } / H \ end
+        CallLevel::Case { arg, clauses } => format!(
+            "case {} of {} end",
+            format_level(arg),
+            clauses
+                .iter()
+                .map(|c| format!(
+                    "{} {}",
+                    format_level(&c.pattern),
+                    c.filters.iter().map(format_filter_level).join(" ")
+                ))
+                .join(" ")
+        ),
+        CallLevel::If(branches) => format!(
+            "if {} end",
+            branches.iter().map(format_filter_level).join(" ")
+        ),
+    }
+}
+
+fn format_filter_level(f: &call_levels::FilterLevel) -> String {
+    match &f.guard {
+        Some(g) => format!("{{ {} }} / {} \\", format_level(g), format_level(&f.body)),
+        None => format!("/ {} \\", format_level(&f.body)),
+    }
+}
+
+// parens are needed around looser operators, and around equally tight ones
+// unless they are on the left and would not be merged into the parent chain
+fn format_operand(level: &CallLevel, parent: &Token, first: bool) -> String {
+    let needs_parens = match level {
+        CallLevel::OpLevel(t, v) if v.len() > 1 => {
+            let prec = call_levels::precedence(t);
+            let parent_prec = call_levels::precedence(parent);
+            prec < parent_prec
+                || (prec == parent_prec
+                    && (!first || (t == parent && call_levels::is_chain_token(t))))
+        }
+        _ => false,
+    };
+    if needs_parens {
+        return format!("({})", format_level(level));
+    } else {
+        return format_level(level);
+    }
+}
+
+fn format_pattern(b: &ArgBind) -> String {
+    match b {
+        ArgBind::Identifier(i) => i.iter().collect(),
+        ArgBind::Emptylist => "[]".to_string(),
+        ArgBind::ConstPattern(d) => format_data(d),
+        ArgBind::ListPattern { head, tail } => {
+            let mut items = vec![format_pattern(head)];
+            let mut tail = tail.as_ref();
+            while let ArgBind::ListPattern { head, tail: t } = tail {
+                items.push(format_pattern(head));
+                tail = t.as_ref();
+            }
+            match tail {
+                ArgBind::ConstPattern(Data::Emptylist) | ArgBind::Emptylist => {
+                    format!("[{}]", items.join(", "))
+                }
+                t => format!("[{}|{}]", items.join(", "), format_pattern(t)),
+            }
+        }
+    }
+}
+
+pub fn format_data(d: &Data) -> String {
+    match d {
+        Data::Number(NumericData::Int(i)) => i.to_string(),
+        Data::Boolean(b) => b.to_string(),
+        Data::Emptylist => "[]".to_string(),
+        Data::FunctionPointer(f) => f.to_string(),
+        Data::Atom(a) => a.iter().collect(),
+        Data::List(h, t) => {
+            let mut items = vec![format_data(h)];
+            let mut tail = t.as_ref();
+            while let Data::List(h, t) = tail {
+                items.push(format_data(h));
+                tail = t.as_ref();
+            }
+            match tail {
+                Data::Emptylist => format!("[{}]", items.join(", ")),
+                t => format!("[{}|{}]", items.join(", "), format_data(t)),
+            }
+        }
+    }
+}
+
+fn token_text(t: &Token) -> &'static str {
+    match t {
+        Token::Add => "+",
+        Token::SUB => "-",
+        Token::MUL => "*",
+        Token::Eq => "==",
+        Token::NEQ => "=/=",
+        Token::LT => "<",
+        Token::GT => ">",
+        Token::LTE => "=<",
+        Token::GTE => ">=",
+        Token::AND => "&&",
+        Token::OR => "||",
+        Token::XOR => "xor",
+        Token::ANDALSO => "andalso",
+        Token::ORELSE => "orelse",
+        Token::NOT => "not",
+        t => {
+            println!("NO OPERATOR TEXT{:#?}\n", t);
+            unimplemented!()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn twice(path: &str) -> (String, String) {
+        let src = fs::read_to_string(path).expect("Cant read file");
+        let once = format_source(&src).expect("formats");
+        let again = format_source(&once).expect("formats");
+        return (once, again);
+    }
+
+    #[test]
+    fn program_txt_is_stable() {
+        let (once, again) = twice("program.txt");
+        assert_eq!(once, again, "formatting program.txt is not idempotent");
+    }
+
+    #[test]
+    fn samples_are_stable() {
+        for path in [
+            "program_case.txt",
+            "program_if.txt",
+            "program_seq.txt",
+            "program_try.txt",
+        ] {
+            let (once, again) = twice(path);
+            assert_eq!(once, again, "formatting {} is not idempotent", path);
+        }
+    }
+
+    #[test]
+    fn keeps_comments_between_functions() {
+        let src = "/* head */\nf:\n    (X)\n    / X \\\nend\n/* tail =/= */\n";
+        let once = format_source(src).expect("formats");
+        assert_eq!(
+            once,
+            "/* head */\nf:\n    (X)\n    / X \\\nend\n\n/* tail =/= */\n"
+        );
+    }
+
+    #[test]
+    fn refuses_comments_inside_functions() {
+        let src = "f:\n    (X) /* the argument */\n    / X \\\nend\n";
+        assert!(format_source(src).is_err());
+    }
+
+    #[test]
+    fn catch_without_class_stays_without() {
+        let src = "f:\n    (X)\n    / try X catch T -> T end \\\nend\n";
+        assert_eq!(format_source(src), Ok(src.to_string()));
+    }
+}
@@ -1,17 +1,34 @@
-use std::{borrow::Borrow, collections::HashSet};
+use std::{borrow::Borrow, collections::HashSet, fmt};
 
 use crate::{
     eval::{self, Program},
     expr::Expr,
     logic_expr::LogicExpr,
     program::{self, Fun},
+    vm::RuntimeErrorKind,
 };
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum FunctionName {
     Static(Vec<char>),
     Dynamic(Vec<char>),
     Rust(Vec<char>),
+    Builtin(Vec<char>),
+}
+
+impl fmt::Display for FunctionName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            // f/1 and f/2 print as f, the arity is printed next to the name where it matters
+            FunctionName::Static(n) => {
+                write!(f, "{}", program::base_name(n).iter().collect::<String>())
+            }
+            FunctionName::Dynamic(n) | FunctionName::Builtin(n) => {
+                write!(f, "{}", n.iter().collect::<String>())
+            }
+            FunctionName::Rust(n) => write!(f, "rust::{}", n.iter().collect::<String>()),
+        }
+    }
 }
 
 pub fn call(
@@ -22,40 +39,185 @@ pub fn call(
 ) -> eval::Data {
     match function.clone() {
         FunctionName::Static(key) => match code.get(&FunctionName::Static(key)) {
-            None => {
-                println!(
-                    "No Pattern:{:#?}{:#?}{:#?}{:#?}\n",
-                    code, state, function, args
-                );
-                unimplemented!();
-            }
+            None => eval::fail(RuntimeErrorKind::Undefined(function, args.len())),
             Some(f) => eval::call(code.clone(), f.clone(), args),
         },
         FunctionName::Dynamic(key) => match state.get(&key) {
             Some(eval::Data::FunctionPointer(f)) => call(code, state.clone(), f.clone(), args),
-            bad => {
-                println!(
-                    "No Pattern:{:#?}{:#?}{:#?}{:#?}{:#?}\n",
-                    code, state, function, args, bad
-                );
-                unimplemented!();
-            }
+            Some(bad) => eval::fail(RuntimeErrorKind::BadFun(bad.clone())),
+            None => eval::fail(unbound_call(key, args.len())),
         },
-        FunctionName::Rust(key) => {
-            return rust_call(key, args);
+        FunctionName::Rust(_) => {
+            eval::reduce_host(&function);
+            match host_call(&function, args) {
+                Ok(res) => return res,
+                Err(kind) => eval::fail(kind),
+            }
+        }
+        FunctionName::Builtin(key) => {
+            return builtin_call(key, args);
         }
     }
 }
 
-fn rust_call(name: Vec<char>, args: Vec<eval::Data>) -> eval::Data {
-    match (name.get(0), name.get(1), name.get(2), name.get(3)) {
-        (Some('l'), Some('o'), Some('g'), None) => {
+// a call through a name nothing is bound to, f(1) names a function the program does not have
+pub fn unbound_call(name: Vec<char>, arity: usize) -> RuntimeErrorKind {
+    if is_variable_name(&name) {
+        return RuntimeErrorKind::Unbound(name);
+    }
+    return RuntimeErrorKind::Undefined(FunctionName::Static(name), arity);
+}
+
+// F, _F and _ are variables, f a function
+pub fn is_variable_name(name: &[char]) -> bool {
+    match name.first() {
+        Some(c) => c.is_uppercase() || *c == '_',
+        None => false,
+    }
+}
+
+// functions every script can call without defining them,
+// the type tests never fail so they are safe to use in { .. } guards
+pub fn is_builtin(name: &[char]) -> bool {
+    let text: String = name.iter().collect();
+    match text.as_str() {
+        "is_number" | "is_boolean" | "is_list" | "is_function" => true,
+        _ => raises(name),
+    }
+}
+
+// throw(Term) and error(Term), the name is the class they raise
+pub fn raises(name: &[char]) -> bool {
+    return name == ['t', 'h', 'r', 'o', 'w'] || name == ['e', 'r', 'r', 'o', 'r'];
+}
+
+fn builtin_call(name: Vec<char>, args: Vec<eval::Data>) -> eval::Data {
+    match (name.as_slice(), args.as_slice()) {
+        (['t', 'h', 'r', 'o', 'w'], [term]) => eval::raise(eval::Class::Throw, term.clone()),
+        (['e', 'r', 'r', 'o', 'r'], [term]) => eval::raise(eval::Class::Error, term.clone()),
+        _ => match host_call(&FunctionName::Builtin(name), args) {
+            Ok(res) => return res,
+            Err(kind) => eval::fail(kind),
+        },
+    }
+}
+
+// host functions reachable as rust::name
+pub fn is_rust_function(name: &[char]) -> bool {
+    match name {
+        ['l', 'o', 'g'] => true,
+        _ => false,
+    }
+}
+
+// a builtin or a rust:: function, for the tree walker and the vm,
+// one it does not have or with the wrong number of arguments is undefined
+pub fn host_call(f: &FunctionName, args: Vec<eval::Data>) -> Result<eval::Data, RuntimeErrorKind> {
+    let text = match f {
+        FunctionName::Builtin(n) => n.iter().collect::<String>(),
+        FunctionName::Rust(n) => format!("rust::{}", n.iter().collect::<String>()),
+        _ => return Err(RuntimeErrorKind::Undefined(f.clone(), args.len())),
+    };
+    match (text.as_str(), args.as_slice()) {
+        ("is_number", [eval::Data::Number(_)]) => Ok(eval::Data::Boolean(true)),
+        ("is_boolean", [eval::Data::Boolean(_)]) => Ok(eval::Data::Boolean(true)),
+        ("is_list", [eval::Data::Emptylist]) => Ok(eval::Data::Boolean(true)),
+        ("is_list", [eval::Data::List(_, _)]) => Ok(eval::Data::Boolean(true)),
+        ("is_function", [eval::Data::FunctionPointer(_)]) => Ok(eval::Data::Boolean(true)),
+        ("is_number" | "is_boolean" | "is_list" | "is_function", [_]) => {
+            Ok(eval::Data::Boolean(false))
+        }
+        ("rust::log", _) => {
             println!("LANGLOG{:#?}\n", args);
-            return eval::Data::Emptylist;
+            return Ok(eval::Data::Emptylist);
         }
-        bad => {
-            println!("No Pattern:{:#?}{:#?}{:#?}\n", name, args, bad);
-            unimplemented!();
+        _ => Err(RuntimeErrorKind::Undefined(f.clone(), args.len())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::numeric_expr::NumericData;
+    use std::sync::Arc;
+
+    fn builtin(name: &str) -> FunctionName {
+        return FunctionName::Builtin(name.chars().collect());
+    }
+
+    #[test]
+    fn each_type_test_is_true_for_its_own_values_only() {
+        let values = vec![
+            eval::Data::Number(NumericData::Int(-1)),
+            eval::Data::Boolean(false),
+            eval::Data::Emptylist,
+            eval::Data::List(
+                Arc::new(eval::Data::Emptylist),
+                Arc::new(eval::Data::Emptylist),
+            ),
+            eval::Data::FunctionPointer(FunctionName::Static(vec!['f'])),
+            eval::Data::Atom(vec!['o', 'k']),
+        ];
+        // one row per value, in the order of the tests
+        let tests = ["is_number", "is_boolean", "is_list", "is_function"];
+        let expected = [
+            [true, false, false, false],
+            [false, true, false, false],
+            [false, false, true, false],
+            [false, false, true, false],
+            [false, false, false, true],
+            [false, false, false, false],
+        ];
+        for (v, row) in values.iter().zip(expected.iter()) {
+            for (t, want) in tests.iter().zip(row.iter()) {
+                let got = host_call(&builtin(t), vec![v.clone()]);
+                assert_eq!(got.ok(), Some(eval::Data::Boolean(*want)), "{}({:?})", t, v);
+            }
         }
     }
+
+    #[test]
+    fn guards_dispatch_on_the_type() {
+        let src = concat!(
+            "kind:\n",
+            "    (X)\n    { is_number(X) }\n    / number \\\n",
+            "    (X)\n    { is_list(X) }\n    / list \\\n",
+            "    (X)\n    { is_function(X) }\n    / function \\\n",
+            "    (X)\n    { is_boolean(X) }\n    / boolean \\\n",
+            "    (_)\n    / other \\\n",
+            "end\n",
+            "main:\n    ()\n    / [kind(1), kind([]), kind(kind), kind(true), kind(ok)] \\\nend\n"
+        );
+        let kinds = ["number", "list", "function", "boolean", "other"];
+        let expected = kinds.iter().rev().fold(eval::Data::Emptylist, |tail, k| {
+            eval::Data::List(
+                Arc::new(eval::Data::Atom(k.chars().collect())),
+                Arc::new(tail),
+            )
+        });
+        assert_eq!(eval::run_main(&program::from_source(src)), Ok(expected));
+    }
+
+    #[test]
+    fn host_calls_it_does_not_have_are_undefined() {
+        let message = |f: FunctionName, args: Vec<eval::Data>| match host_call(&f, args) {
+            Ok(d) => panic!("{} gave {:?}", f, d),
+            Err(kind) => kind.to_string(),
+        };
+        let one = || vec![eval::Data::Emptylist];
+        assert_eq!(
+            message(builtin("is_number"), vec![]),
+            "undefined function is_number/0"
+        );
+        assert_eq!(
+            message(FunctionName::Rust("nosuch".chars().collect()), one()),
+            "undefined function rust::nosuch/1"
+        );
+        assert_eq!(
+            message(FunctionName::Static("f".chars().collect()), one()),
+            "undefined function f/1"
+        );
+        let log = FunctionName::Rust("log".chars().collect());
+        assert_eq!(host_call(&log, one()).ok(), Some(eval::Data::Emptylist));
+    }
 }
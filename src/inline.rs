@@ -0,0 +1,427 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::{
+    callgraph,
+    eval::{self, Data},
+    expr::Expr,
+    function::FunctionName,
+    logic_expr::LogicExpr,
+    numeric_expr::NumericExpr,
+    pattern_match::ArgBind,
+    program,
+    visit::{self, Rewriter, Visitor},
+};
+
+// Inlining of small helpers and removal of functions nothing calls
+//
+// mul:
+//     (A, B)
+//     / A * B \
+// end
+//
+// turns 1 + mul(X, 2) into 1 + X * 2. A function is inlined when it has one
+// bind with one unguarded filter, only plain variables in its head, no spec,
+// no assignments, at most MAX_SIZE nodes in its body, and is not part of a
+// call graph cycle. A call is only replaced when every argument is a variable
+// or a constant, so nothing is evaluated more or fewer times than before.
+
+const MAX_SIZE: usize = 12;
+
+#[derive(Debug, Clone)]
+pub struct Inlined {
+    pub callee: FunctionName,
+    pub caller: FunctionName,
+}
+
+// the optimised program, the calls that were inlined and the functions removed
+pub fn inline_program(
+    code: eval::Program,
+    entry: &FunctionName,
+) -> (eval::Program, Vec<Inlined>, Vec<FunctionName>) {
+    let graph = callgraph::build(&code);
+    let recursive: HashSet<FunctionName> = graph
+        .cycles
+        .iter()
+        .flat_map(|c| c.functions.iter().cloned())
+        .collect();
+    let small: HashMap<FunctionName, (Vec<Vec<char>>, Expr)> = code
+        .iter()
+        .filter(|(name, _)| !recursive.contains(*name))
+        .filter_map(|(name, fun)| inlinable(fun).map(|x| (name.clone(), x)))
+        .collect();
+    let mut report = Vec::new();
+    let mut res: eval::Program = HashMap::new();
+    for (name, fun) in code.into_iter() {
+        let mut inliner = Inliner {
+            small: &small,
+            caller: name.clone(),
+            report: &mut report,
+        };
+        let fun = visit::rewrite_fun(&mut inliner, fun);
+        res.insert(name, fun);
+    }
+    let reachable = reachable_from(&res, entry);
+    let mut removed: Vec<FunctionName> = res
+        .keys()
+        .filter(|name| !reachable.contains(*name))
+        .cloned()
+        .collect();
+    removed.sort();
+    res.retain(|name, _| reachable.contains(name));
+    report.sort_by(|a, b| (&a.caller, &a.callee).cmp(&(&b.caller, &b.callee)));
+    report.dedup_by(|a, b| a.caller == b.caller && a.callee == b.callee);
+    return (res, report, removed);
+}
+
+// parameter names and body of a function simple enough to inline
+fn inlinable(fun: &program::Fun) -> Option<(Vec<Vec<char>>, Expr)> {
+    if fun.spec.is_some() {
+        return None;
+    }
+    let bind = match fun.binds.as_slice() {
+        [bind] => bind,
+        _ => return None,
+    };
+    let filter = match bind.filters.as_slice() {
+        [filter] if matches!(filter.head, LogicExpr::True) => filter,
+        _ => return None,
+    };
+    let mut params = Vec::new();
+    for p in bind.pattern.iter() {
+        match p {
+            ArgBind::Identifier(n) if !params.contains(n) => params.push(n.clone()),
+            _ => return None,
+        }
+    }
+    if size(&filter.code) > MAX_SIZE {
+        return None;
+    }
+    return Some((params, filter.code.clone()));
+}
+
+struct Inliner<'a> {
+    small: &'a HashMap<FunctionName, (Vec<Vec<char>>, Expr)>,
+    caller: FunctionName,
+    report: &'a mut Vec<Inlined>,
+}
+
+impl<'a> Inliner<'a> {
+    // the body of f with the arguments put in, when f can be inlined here
+    fn body(&mut self, f: &FunctionName, args: &[Expr]) -> Option<Expr> {
+        let (params, body) = self.small.get(f)?;
+        if params.len() != args.len() || !args.iter().all(is_simple) {
+            return None;
+        }
+        let bound: HashMap<Vec<char>, Expr> =
+            params.iter().cloned().zip(args.iter().cloned()).collect();
+        let res = subst(body, &bound)?;
+        // the inlined body may call small functions in turn
+        return Some(self.expr(res));
+    }
+
+    fn inlined<T>(&mut self, f: &FunctionName, res: T) -> T {
+        self.report.push(Inlined {
+            callee: f.clone(),
+            caller: self.caller.clone(),
+        });
+        return res;
+    }
+}
+
+// calls are replaced after their arguments are inlined into
+impl<'a> Rewriter for Inliner<'a> {
+    fn expr(&mut self, e: Expr) -> Expr {
+        match visit::rewrite_expr(self, e) {
+            Expr::Call(f, args) => match self.body(&f, &args) {
+                Some(body) => self.inlined(&f, body),
+                None => Expr::Call(f, args),
+            },
+            e => e,
+        }
+    }
+
+    fn numeric(&mut self, e: NumericExpr) -> NumericExpr {
+        match visit::rewrite_numeric(self, e) {
+            NumericExpr::Call(f, args) => match self.body(&f, &args).and_then(expr_to_numeric) {
+                Some(n) => self.inlined(&f, n),
+                None => NumericExpr::Call(f, args),
+            },
+            e => e,
+        }
+    }
+
+    fn logic(&mut self, e: LogicExpr) -> LogicExpr {
+        match visit::rewrite_logic(self, e) {
+            LogicExpr::Call(f, args) => match self.body(&f, &args).and_then(expr_to_logic) {
+                Some(l) => self.inlined(&f, l),
+                None => LogicExpr::Call(f, args),
+            },
+            e => e,
+        }
+    }
+}
+
+// a call in arithmetic fails on anything but a number, so the body
+// may only replace it when it keeps that check
+fn expr_to_numeric(e: Expr) -> Option<NumericExpr> {
+    match e {
+        Expr::NumericExpr(n) => Some(n),
+        Expr::Identifier(i) => Some(NumericExpr::Identifier(i)),
+        Expr::Constant(Data::Number(n)) => Some(NumericExpr::Const(n)),
+        Expr::Call(f, args) => Some(NumericExpr::Call(f, args)),
+        _ => None,
+    }
+}
+
+fn expr_to_logic(e: Expr) -> Option<LogicExpr> {
+    match e {
+        Expr::LogicExpr(l) => Some(l),
+        Expr::Identifier(i) => Some(LogicExpr::Identifier(i)),
+        Expr::Constant(Data::Boolean(true)) => Some(LogicExpr::True),
+        Expr::Constant(Data::Boolean(false)) => Some(LogicExpr::False),
+        Expr::Call(f, args) => Some(LogicExpr::Call(f, args)),
+        _ => None,
+    }
+}
+
+fn is_simple(e: &Expr) -> bool {
+    return matches!(e, Expr::Identifier(_) | Expr::Constant(_));
+}
+
+// the parameters of the body replaced by the arguments, None when an argument
+// can not stand where its parameter is used, or the body uses a name that is not
+// a parameter, which could otherwise pick up a variable of the caller
+fn subst(e: &Expr, bound: &HashMap<Vec<char>, Expr>) -> Option<Expr> {
+    let mut s = Subst {
+        bound: bound,
+        failed: false,
+    };
+    let res = s.expr(e.clone());
+    if s.failed {
+        return None;
+    }
+    return Some(res);
+}
+
+struct Subst<'a> {
+    bound: &'a HashMap<Vec<char>, Expr>,
+    failed: bool,
+}
+
+impl<'a> Subst<'a> {
+    // the argument of a parameter, as whatever kind of node it is used as
+    fn arg<T>(&mut self, name: &[char], convert: impl Fn(&Expr) -> Option<T>) -> Option<T> {
+        let res = self.bound.get(name).and_then(|a| convert(a));
+        self.failed = self.failed || res.is_none();
+        return res;
+    }
+
+    // F(X) with F a parameter calls whatever F was given
+    fn name(&mut self, f: FunctionName) -> FunctionName {
+        match &f {
+            FunctionName::Dynamic(n) => self
+                .arg(n, |a| match a {
+                    Expr::Identifier(i) => Some(FunctionName::Dynamic(i.clone())),
+                    Expr::Constant(Data::FunctionPointer(g)) => Some(g.clone()),
+                    _ => None,
+                })
+                .unwrap_or(f),
+            _ => f,
+        }
+    }
+}
+
+impl<'a> Rewriter for Subst<'a> {
+    fn expr(&mut self, e: Expr) -> Expr {
+        match e {
+            Expr::Identifier(i) => self
+                .arg(&i, |a| Some(a.clone()))
+                .unwrap_or(Expr::Identifier(i)),
+            Expr::Call(f, args) => Expr::Call(self.name(f), visit::rewrite_all(self, args)),
+            // inlinable bodies have no assignments, the patterns of a catch or a case bind too
+            Expr::Assign { .. } | Expr::Try { .. } | Expr::Case { .. } => {
+                self.failed = true;
+                return e;
+            }
+            e => visit::rewrite_expr(self, e),
+        }
+    }
+
+    fn numeric(&mut self, e: NumericExpr) -> NumericExpr {
+        match e {
+            NumericExpr::Identifier(i) => self
+                .arg(&i, |a| match a {
+                    Expr::Identifier(x) => Some(NumericExpr::Identifier(x.clone())),
+                    Expr::Constant(Data::Number(n)) => Some(NumericExpr::Const(n.clone())),
+                    _ => None,
+                })
+                .unwrap_or(NumericExpr::Identifier(i)),
+            NumericExpr::Call(f, args) => {
+                NumericExpr::Call(self.name(f), visit::rewrite_all(self, args))
+            }
+            e => visit::rewrite_numeric(self, e),
+        }
+    }
+
+    fn logic(&mut self, e: LogicExpr) -> LogicExpr {
+        match e {
+            LogicExpr::Identifier(i) => self
+                .arg(&i, |a| match a {
+                    Expr::Identifier(x) => Some(LogicExpr::Identifier(x.clone())),
+                    Expr::Constant(Data::Boolean(true)) => Some(LogicExpr::True),
+                    Expr::Constant(Data::Boolean(false)) => Some(LogicExpr::False),
+                    _ => None,
+                })
+                .unwrap_or(LogicExpr::Identifier(i)),
+            LogicExpr::Call(f, args) => {
+                LogicExpr::Call(self.name(f), visit::rewrite_all(self, args))
+            }
+            e => visit::rewrite_logic(self, e),
+        }
+    }
+}
+
+// nodes of the expression, operands of arithmetic and logic count as one each
+fn size(e: &Expr) -> usize {
+    let mut s = Size(0);
+    s.expr(e);
+    return s.0;
+}
+
+struct Size(usize);
+
+impl Visitor for Size {
+    // the NumericExpr and LogicExpr wrappers are not nodes of their own
+    fn expr(&mut self, e: &Expr) {
+        if !matches!(e, Expr::NumericExpr(_) | Expr::LogicExpr(_)) {
+            self.0 += 1;
+        }
+        visit::walk_expr(self, e);
+    }
+
+    fn logic(&mut self, e: &LogicExpr) {
+        self.0 += 1;
+        visit::walk_logic(self, e);
+    }
+
+    fn numeric(&mut self, e: &NumericExpr) {
+        self.0 += 1;
+        visit::walk_numeric(self, e);
+    }
+}
+
+// functions reachable from entry by calls or by naming them as a value
+pub fn reachable_from(code: &eval::Program, entry: &FunctionName) -> HashSet<FunctionName> {
+    let mut seen = HashSet::new();
+    let mut todo = VecDeque::new();
+    todo.push_back(entry.clone());
+    while let Some(name) = todo.pop_front() {
+        if !seen.insert(name.clone()) {
+            continue;
+        }
+        if let Some(fun) = code.get(&name) {
+            for c in program::fun_calls(fun) {
+                match c {
+                    FunctionName::Static(n) | FunctionName::Dynamic(n) => {
+                        todo.push_back(FunctionName::Static(n))
+                    }
+                    _ => {}
+                }
+            }
+            fun_refs(fun).into_iter().for_each(|f| todo.push_back(f));
+        }
+    }
+    return seen;
+}
+
+// functions named as a value anywhere in f, apply(pos, X) names pos
+pub fn fun_refs(f: &program::Fun) -> Vec<FunctionName> {
+    let mut refs = Refs(Vec::new());
+    visit::walk_fun(&mut refs, f);
+    return refs.0;
+}
+
+struct Refs(Vec<FunctionName>);
+
+impl Visitor for Refs {
+    fn constant(&mut self, d: &Data) {
+        match d {
+            Data::FunctionPointer(f @ FunctionName::Static(_)) => self.0.push(f.clone()),
+            Data::List(h, t) => {
+                self.constant(h);
+                self.constant(t);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MUL: &str = "mul:\n    (A, B)\n    / A * B \\\nend\n";
+
+    // "callee into caller" for every call inlined and the functions removed,
+    // checking that the optimised main gives what the written one gives
+    fn optimise(src: &str) -> (Vec<String>, Vec<String>) {
+        let code = program::from_source(src);
+        let main = FunctionName::Static("main".chars().collect());
+        let (optimised, inlined, removed) = inline_program(code.clone(), &main);
+        assert_eq!(eval::run_main(&code), eval::run_main(&optimised));
+        let inlined = inlined
+            .iter()
+            .map(|i| format!("{} into {}", i.callee, i.caller))
+            .collect();
+        return (inlined, removed.iter().map(|n| n.to_string()).collect());
+    }
+
+    fn with_main(helpers: &str, body: &str) -> String {
+        return format!("{}main:\n    ()\n    / X = 3 {} \\\nend\n", helpers, body);
+    }
+
+    #[test]
+    fn a_small_helper_is_inlined_and_removed() {
+        let src = with_main(MUL, "1 + mul(X, 2)");
+        assert_eq!(
+            optimise(&src),
+            (vec!["mul into main".to_string()], vec!["mul".to_string()])
+        );
+    }
+
+    #[test]
+    fn functions_nothing_calls_are_removed() {
+        let src = with_main("unused:\n    ()\n    / 1 \\\nend\n", "X");
+        assert_eq!(optimise(&src), (vec![], vec!["unused".to_string()]));
+    }
+
+    #[test]
+    fn a_function_with_a_spec_is_kept() {
+        let helpers = format!("-spec mul(number, number) -> number.\n{}", MUL);
+        let src = with_main(&helpers, "mul(X, 2)");
+        assert_eq!(optimise(&src), (vec![], vec![]));
+    }
+
+    #[test]
+    fn a_recursive_function_is_kept() {
+        let helpers = "count:\n    (N)\n    / case N of 0 / 0 \\ _ / count(N - 1) \\ end \\\nend\n";
+        let src = with_main(helpers, "count(X)");
+        assert_eq!(optimise(&src), (vec![], vec![]));
+    }
+
+    #[test]
+    fn calls_with_an_argument_to_evaluate_are_kept() {
+        let src = with_main(MUL, "mul(X + 1, 2) + mul(X, 2)");
+        assert_eq!(optimise(&src), (vec!["mul into main".to_string()], vec![]));
+        let src = with_main(MUL, "mul(mul(X, 1), 2)");
+        assert_eq!(optimise(&src), (vec!["mul into main".to_string()], vec![]));
+    }
+
+    #[test]
+    fn guards_and_patterns_in_the_head_are_kept() {
+        let pos = "pos:\n    (A)\n    { A > 0 }\n    / A \\\nend\n";
+        assert_eq!(optimise(&with_main(pos, "pos(X)")), (vec![], vec![]));
+        let head = "head:\n    ([H|_])\n    / H \\\nend\n";
+        assert_eq!(optimise(&with_main(head, "head([X])")), (vec![], vec![]));
+    }
+}
@@ -0,0 +1,299 @@
+use std::{collections::HashSet, fmt};
+
+use crate::{
+    eval, exhaustive,
+    expr::Expr,
+    function::FunctionName,
+    inline,
+    pattern_match::{self, ArgBind},
+    program,
+    visit::{self, Visitor},
+};
+
+// Warnings about code that is legal but most likely a mistake
+//
+// W001 a head pattern variable no guard or body of the bind uses, nor the head again
+// W002 an assigned variable that is never read afterwards
+// W003 a function that can not be reached from the entry point
+// W004 a bind that never matches since earlier unguarded binds take all its arguments
+// W005 an assignment to a variable that is already bound, so it compares instead
+// W006 a function with arguments none of its binds is sure to take, see exhaustive.rs
+//
+// Like in Erlang, variables and functions whose name starts with _ are not reported.
+//
+// Expressions do not know their lines, so a warning spans the bind it is about,
+// or the whole function for W003 and W006.
+
+#[derive(Debug, Clone)]
+pub struct Warning {
+    pub code: &'static str,
+    pub span: Span,
+    pub message: String,
+}
+
+// first and last line, 0 when unknown
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    fn bind(b: &program::Bind) -> Span {
+        return Span {
+            start: b.line,
+            end: b.end.max(b.line),
+        };
+    }
+
+    fn fun(f: &program::Fun) -> Span {
+        return Span {
+            start: f.line,
+            end: f.end.max(f.line),
+        };
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.end > self.start {
+            write!(f, "{}-{}", self.start, self.end)
+        } else {
+            write!(f, "{}", self.start)
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} {}", self.span, self.code, self.message)
+    }
+}
+
+pub fn lint_program(code: &eval::Program, entry: &FunctionName) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    let reachable = inline::reachable_from(code, entry);
+    for (name, fun) in code.iter() {
+        if !reachable.contains(name) && !is_silenced(&fun.name) {
+            warnings.push(Warning {
+                code: "W003",
+                span: Span::fun(fun),
+                message: format!("function {} is never called from {}", name, entry),
+            });
+        }
+        for bind in fun.binds.iter() {
+            lint_bind(fun, bind, &mut warnings);
+        }
+        for i in exhaustive::unreachable_clauses(&fun.binds) {
+            warnings.push(Warning {
+                code: "W004",
+                span: Span::bind(&fun.binds[i]),
+                message: format!(
+                    "clause {} of {} never matches, earlier clauses take all its arguments",
+                    i + 1,
+                    name
+                ),
+            });
+        }
+        if let Some(w) = exhaustive::missing_case(&fun.binds) {
+            warnings.push(Warning {
+                code: "W006",
+                span: Span::fun(fun),
+                message: format!(
+                    "{} in {}",
+                    exhaustive::describe_missing(&name.to_string(), &w),
+                    name
+                ),
+            });
+        }
+    }
+    warnings.sort_by_key(|w| (w.span.start, w.code));
+    return warnings;
+}
+
+fn lint_bind(fun: &program::Fun, bind: &program::Bind, warnings: &mut Vec<Warning>) {
+    let name: String = fun.name.iter().collect();
+    let mut head = Vec::new();
+    bind.pattern
+        .iter()
+        .for_each(|p| pattern_match::bound_names(p, &mut head));
+    let mut used = HashSet::new();
+    visit::walk_filters(&mut Reads(&mut used), &bind.filters);
+    // a variable named twice in the head is used by the test that the values are equal
+    let once: Vec<&Vec<char>> = head
+        .iter()
+        .filter(|v| head.iter().filter(|w| w == v).count() == 1)
+        .collect();
+    for var in once.into_iter() {
+        if !used.contains(var) && !is_silenced(var) {
+            warnings.push(Warning {
+                code: "W001",
+                span: Span::bind(bind),
+                message: format!(
+                    "variable {} is never used in {}",
+                    var.iter().collect::<String>(),
+                    name
+                ),
+            });
+        }
+    }
+    let mut assigns = Assigns {
+        bound: head.into_iter().collect(),
+        name: &name,
+        span: Span::bind(bind),
+        warnings: warnings,
+    };
+    bind.filters.iter().for_each(|f| assigns.expr(&f.code));
+}
+
+// W002 and W005 for every assignment, in case, if and try bodies too,
+// bound holds what is bound at the point the walk is at
+struct Assigns<'a> {
+    bound: HashSet<Vec<char>>,
+    name: &'a str,
+    span: Span,
+    warnings: &'a mut Vec<Warning>,
+}
+
+impl<'a> Assigns<'a> {
+    fn assigned(&mut self, pattern: &ArgBind, rest: &Expr) {
+        let mut assigned = Vec::new();
+        pattern_match::bound_names(pattern, &mut assigned);
+        let mut used = HashSet::new();
+        Reads(&mut used).expr(rest);
+        for var in assigned.iter().filter(|v| !is_silenced(v)) {
+            let text: String = var.iter().collect();
+            if self.bound.contains(var) {
+                self.warnings.push(Warning {
+                    code: "W005",
+                    span: self.span,
+                    message: format!(
+                        "variable {} in {} is already bound, the assignment only compares",
+                        text, self.name
+                    ),
+                });
+            } else if !used.contains(var) {
+                self.warnings.push(Warning {
+                    code: "W002",
+                    span: self.span,
+                    message: format!(
+                        "variable {} in {} is assigned but never read",
+                        text, self.name
+                    ),
+                });
+            }
+        }
+    }
+
+    // the variables of the patterns are bound only while f runs
+    fn scoped(&mut self, patterns: &[&ArgBind], f: impl FnOnce(&mut Self)) {
+        let outer = self.bound.clone();
+        for p in patterns.iter() {
+            let mut names = Vec::new();
+            pattern_match::bound_names(p, &mut names);
+            self.bound.extend(names);
+        }
+        f(self);
+        self.bound = outer;
+    }
+}
+
+impl<'a> Visitor for Assigns<'a> {
+    fn expr(&mut self, e: &Expr) {
+        match e {
+            Expr::Assign { pattern, arg, rest } => {
+                self.expr(arg);
+                self.assigned(pattern, rest);
+                self.scoped(&[pattern], |s| s.expr(rest));
+            }
+            Expr::Case { arg, clauses } => {
+                self.expr(arg);
+                for c in clauses.iter() {
+                    self.scoped(&[&c.pattern], |s| visit::walk_filters(s, &c.filters));
+                }
+            }
+            Expr::Try { body, catches } => {
+                self.expr(body);
+                for c in catches.iter() {
+                    self.scoped(&[&c.class, &c.pattern], |s| s.expr(&c.code));
+                }
+            }
+            _ => visit::walk_expr(self, e),
+        }
+    }
+}
+
+fn is_silenced(name: &[char]) -> bool {
+    return name.first() == Some(&'_');
+}
+
+// every variable read anywhere inside the code, a call through F reads F
+struct Reads<'a>(&'a mut HashSet<Vec<char>>);
+
+impl<'a> Visitor for Reads<'a> {
+    fn variable(&mut self, name: &[char]) {
+        self.0.insert(name.to_vec());
+    }
+
+    fn call(&mut self, f: &FunctionName, args: &[Expr]) {
+        if let FunctionName::Dynamic(name) = f {
+            self.0.insert(name.clone());
+        }
+        args.iter().for_each(|x| self.expr(x));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lint(src: &str) -> Vec<String> {
+        let main = FunctionName::Static("main".chars().collect());
+        return lint_program(&program::from_source(src), &main)
+            .iter()
+            .map(|w| w.to_string())
+            .collect();
+    }
+
+    #[test]
+    fn assignments_in_case_if_and_try_bodies_are_checked() {
+        let src = "main:\n    (A)\n    /\n    case A of\n        \
+                   1 / B = 2 A \\\n        \
+                   _ / if { A > 0 } / A = 1 A \\ / try C = 1 A catch E -> E end \\ end \\\n    \
+                   end\n    \\\nend\n";
+        assert_eq!(
+            lint(src),
+            vec![
+                "2-8: W002 variable B in main is assigned but never read",
+                "2-8: W002 variable C in main is assigned but never read",
+                "2-8: W005 variable A in main is already bound, the assignment only compares",
+            ]
+        );
+    }
+
+    #[test]
+    fn a_case_pattern_binds_for_its_clause_only() {
+        let src = "main:\n    (A)\n    /\n    \
+                   case A of\n        X / X = 1 X \\\n    end\n    \
+                   X = 2\n    X\n    \\\nend\n";
+        assert_eq!(
+            lint(src),
+            vec!["2-9: W005 variable X in main is already bound, the assignment only compares"]
+        );
+    }
+
+    #[test]
+    fn warnings_span_the_bind_or_the_function() {
+        let src = "main:\n    ()\n    / 1 \\\nend\n\
+                   unused:\n    (X)\n    / 1 \\\n    (Y)\n    / 2 \\\nend\n";
+        assert_eq!(
+            lint(src),
+            vec![
+                "5-10: W003 function unused is never called from main",
+                "6-7: W001 variable X is never used in unused",
+                "8-9: W001 variable Y is never used in unused",
+                "8-9: W004 clause 2 of unused never matches, earlier clauses take all its arguments",
+            ]
+        );
+    }
+}
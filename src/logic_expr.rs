@@ -2,18 +2,42 @@ use crate::eval::{self, ProgramState};
 use crate::expr::{self, Expr};
 use crate::segments::{self, Segment};
 use crate::tokens::{self, Token};
-use crate::{call_levels, function, program};
+use crate::visit::{self, Visitor};
+use crate::vm::RuntimeErrorKind;
+use crate::{call_levels, function};
 use itertools::Itertools;
+use std::cmp::Ordering;
 
 #[derive(Debug, Clone)]
 pub enum LogicExpr {
     True,
     False,
     Identifier(Vec<char>),
+    // &&, ||, andalso and orelse stop at the first operand that decides
+    // the result, && and andalso only differ in how tightly they bind
     AND(Vec<LogicExpr>),
+    OR(Vec<LogicExpr>),
+    ANDALSO(Vec<LogicExpr>),
+    ORELSE(Vec<LogicExpr>),
+    XOR(Vec<LogicExpr>), // true when an odd number of operands are true
+    NOT(Box<LogicExpr>),
     EQ(Vec<expr::Expr>),                   // A == B == C -> EQ(A,B,C)
     NEQ(Box<expr::Expr>, Box<expr::Expr>), //change to array version? A != B != C -> A != B && B != C
     Call(function::FunctionName, Vec<Expr>),
+    // A < B, ordered by eval::term_order so any two Data can be compared
+    Compare {
+        op: CompareOp,
+        left: Box<expr::Expr>,
+        right: Box<expr::Expr>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum CompareOp {
+    LT,
+    GT,
+    LTE,
+    GTE,
 }
 
 pub fn eval(c: eval::Program, p: eval::ProgramState, expr: LogicExpr) -> bool {
@@ -25,6 +49,17 @@ pub fn eval(c: eval::Program, p: eval::ProgramState, expr: LogicExpr) -> bool {
             let r1 = expr::eval(c, p, *r);
             l1 != r1
         }
+        LogicExpr::Compare { op, left, right } => {
+            let l1 = expr::eval(c.clone(), p.clone(), *left);
+            let r1 = expr::eval(c, p, *right);
+            let order = eval::term_order(&l1, &r1);
+            match op {
+                CompareOp::LT => order == Ordering::Less,
+                CompareOp::GT => order == Ordering::Greater,
+                CompareOp::LTE => order != Ordering::Greater,
+                CompareOp::GTE => order != Ordering::Less,
+            }
+        }
         LogicExpr::EQ(v) => {
             let res = v
                 .into_iter()
@@ -32,30 +67,33 @@ pub fn eval(c: eval::Program, p: eval::ProgramState, expr: LogicExpr) -> bool {
                 .all_equal();
             res
         }
-        LogicExpr::AND(v) => {
-            let res = v
-                .into_iter()
-                .map(|x| eval(c.clone(), p.clone(), x))
-                .all(|x| x);
-            res
+        LogicExpr::AND(v) | LogicExpr::ANDALSO(v) => {
+            v.into_iter().all(|x| eval(c.clone(), p.clone(), x))
+        }
+        LogicExpr::OR(v) | LogicExpr::ORELSE(v) => {
+            v.into_iter().any(|x| eval(c.clone(), p.clone(), x))
         }
+        LogicExpr::XOR(v) => v
+            .into_iter()
+            .map(|x| eval(c.clone(), p.clone(), x))
+            .fold(false, |a, b| a != b),
+        LogicExpr::NOT(x) => !eval(c, p, *x),
         LogicExpr::Call(f, args) => match expr::eval_and_call(c, f, args, p) {
             eval::Data::Boolean(a) => a,
-            a => {
-                println!("BAD NEXPR{:#?}\n", a);
-                unimplemented!()
-            }
+            a => eval::fail(RuntimeErrorKind::BadBoolean(a)),
         },
         LogicExpr::Identifier(name) => match expr::var_lookup(name, p) {
             eval::Data::Boolean(a) => a,
-            a => {
-                println!("BAD NEXPR{:#?}\n", a);
-                unimplemented!()
-            }
+            a => eval::fail(RuntimeErrorKind::BadBoolean(a)),
         },
     }
 }
 
+// every function called anywhere inside the expression
+pub fn calls(expr: &LogicExpr, acc: &mut Vec<function::FunctionName>) {
+    visit::Calls(acc).logic(expr);
+}
+
 pub fn call_levels_to_logic_expr(level: call_levels::CallLevel) -> LogicExpr {
     match level.clone() {
         call_levels::CallLevel::OpLevel(token, sublevels) => match token {
@@ -66,12 +104,66 @@ pub fn call_levels_to_logic_expr(level: call_levels::CallLevel) -> LogicExpr {
                     .collect();
                 return LogicExpr::EQ(subs);
             }
+            Token::AND | Token::OR | Token::ANDALSO | Token::ORELSE | Token::XOR => {
+                let subs = sublevels
+                    .into_iter()
+                    .map(|x| call_levels_to_logic_expr(x))
+                    .collect();
+                match token {
+                    Token::AND => return LogicExpr::AND(subs),
+                    Token::OR => return LogicExpr::OR(subs),
+                    Token::ANDALSO => return LogicExpr::ANDALSO(subs),
+                    Token::ORELSE => return LogicExpr::ORELSE(subs),
+                    _ => return LogicExpr::XOR(subs),
+                }
+            }
+            Token::NOT | Token::NEQ => {
+                match (token, sublevels.get(0), sublevels.get(1), sublevels.get(2)) {
+                    (Token::NOT, Some(x), None, None) => {
+                        return LogicExpr::NOT(Box::new(call_levels_to_logic_expr(x.clone())));
+                    }
+                    (Token::NEQ, Some(left), Some(right), None) => {
+                        return LogicExpr::NEQ(
+                            Box::new(expr::call_levels_to_expr(left.clone())),
+                            Box::new(expr::call_levels_to_expr(right.clone())),
+                        );
+                    }
+                    t => {
+                        println!("call_levels_to_expr {:#?}{:#?}\n", level, t);
+                        unimplemented!()
+                    }
+                }
+            }
+            Token::LT | Token::GT | Token::LTE | Token::GTE => {
+                let op = match token {
+                    Token::LT => CompareOp::LT,
+                    Token::GT => CompareOp::GT,
+                    Token::LTE => CompareOp::LTE,
+                    _ => CompareOp::GTE,
+                };
+                match (sublevels.get(0), sublevels.get(1), sublevels.get(2)) {
+                    (Some(left), Some(right), None) => {
+                        return LogicExpr::Compare {
+                            op: op,
+                            left: Box::new(expr::call_levels_to_expr(left.clone())),
+                            right: Box::new(expr::call_levels_to_expr(right.clone())),
+                        };
+                    }
+                    t => {
+                        println!("call_levels_to_expr {:#?}{:#?}\n", level, t);
+                        unimplemented!()
+                    }
+                }
+            }
             t => {
                 println!("call_levels_to_expr {:#?}{:#?}\n", level, t);
                 unimplemented!()
             }
         },
         call_levels::CallLevel::Identifier(v) => return string_token_to_logic_expr(v),
+        call_levels::CallLevel::FunRef(f) => {
+            return LogicExpr::Identifier(f.to_string().chars().collect())
+        }
         call_levels::CallLevel::Call(fname, sublevels) => {
             let subs = sublevels
                 .into_iter()
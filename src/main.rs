@@ -1,40 +1,372 @@
+pub mod artifact;
+pub mod bytecode;
 pub mod call_levels;
+pub mod callgraph;
+pub mod capability;
+pub mod check;
+pub mod decision;
+pub mod erlang;
 pub mod eval;
+pub mod exhaustive;
 pub mod expr;
+pub mod fold;
+pub mod format;
 pub mod function;
+pub mod inline;
+pub mod lint;
 pub mod logic_expr;
 pub mod numeric_expr;
 pub mod pattern_match;
 pub mod program;
+pub mod purity;
 pub mod segments;
 pub mod tokens;
+pub mod types;
+pub mod visit;
+pub mod vm;
 
-use std::{collections::HashMap, fs, println};
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs, println, process,
+    time::Duration,
+};
 
 use segments::Segment;
 
+// rust-test-1 [run] [--check-specs] [--verify] [--verbose] [--max-reductions=N]
+//     [--max-depth=N] [--max-heap=N] [--timeout-ms=N] [--allow=rust::log,..] [--guard-safe=f,rust::log,..]
+//     [file | file.rbc]
+// rust-test-1 compile [--check-specs] [--verbose] [--allow=rust::log,..] [--guard-safe=f,..] [file]
+// rust-test-1 fmt [--check] [file]
+// rust-test-1 check [file]
+// rust-test-1 lint [file]
+// rust-test-1 callgraph [--json] [file]
+// rust-test-1 types [file]
+//
+// file defaults to ./program.txt. A script may call no rust:: host function unless
+// --allow grants it, a call that is not granted stops run and compile with exit code 3.
 fn main() {
-    let c = fs::read_to_string("./program.txt").expect("Cant read file");
-    let c2 = clean(c);
-    let tokens = tokens::string_to(c2.clone());
-    // Groups of tokes for expressing a single case within the code base
-    let clauses = segments::matched_terminators(tokens.clone());
-    let clauses2 = segments::prune_clauses(clauses.clone());
-    //println!("8{:#?}\n", (clauses2));
-    let funs0 = program::to_funs(clauses2.clone());
-    println!("9{:#?}\n", (funs0));
-    let funs1 = program::to_program(funs0.clone());
-    println!("10{:#?}\n", (funs1));
-    //let funs = function::resolve_lambdas(funs0.clone());
-    let res = function::call(
-        funs1,
-        HashMap::new(),
-        function::FunctionName::Static("main".chars().collect()),
-        Vec::new(),
-    );
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.split_first() {
+        Some((cmd, rest)) if cmd == "run" => run(rest),
+        Some((cmd, rest)) if cmd == "compile" => compile(rest),
+        Some((cmd, rest)) if cmd == "fmt" => fmt(rest),
+        Some((cmd, rest)) if cmd == "check" => check(rest),
+        Some((cmd, rest)) if cmd == "lint" => lint(rest),
+        Some((cmd, rest)) if cmd == "callgraph" => callgraph(rest),
+        Some((cmd, rest)) if cmd == "types" => types(rest),
+        _ => run(&args),
+    }
+}
+
+fn path_arg(args: &[String]) -> String {
+    return args
+        .iter()
+        .find(|a| !a.starts_with("--"))
+        .cloned()
+        .unwrap_or("./program.txt".to_string());
+}
+
+// --name=N
+fn flag_value(args: &[String], name: &str) -> Option<u64> {
+    let prefix = format!("{}=", name);
+    let value = args.iter().find_map(|a| a.strip_prefix(prefix.as_str()))?;
+    match value.parse() {
+        Ok(n) => return Some(n),
+        Err(_) => {
+            println!("{} takes a number, not {}", name, value);
+            process::exit(2);
+        }
+    }
+}
+
+// functions in the order they are written
+fn parse(path: &str) -> Vec<program::FunFun> {
+    let c = fs::read_to_string(path).expect("Cant read file");
+    // .erl files use the Erlang flavoured clause syntax, see erlang.rs
+    if path.ends_with(".erl") {
+        let tokens = tokens::string_to_located(erlang::strip_line_comments(&c));
+        return erlang::to_fun_list(tokens);
+    } else {
+        let tokens = tokens::string_to_located(c.clone());
+        // Groups of tokes for expressing a single case within the code base
+        let clauses = segments::matched_terminators_located(tokens.clone());
+        let clauses2 = segments::prune_clauses(clauses.clone());
+        //println!("8{:#?}\n", (clauses2));
+        return program::to_fun_list(clauses2.clone());
+    }
+}
+
+fn load(path: &str) -> eval::Program {
+    return program::to_program(program::fun_list_to_funs(parse(path)));
+}
+
+fn run(args: &[String]) {
+    let path = path_arg(args);
+    let entry = function::FunctionName::Static("main".chars().collect());
+    if path.ends_with(".rbc") {
+        if args.iter().any(|a| a == "--verify") {
+            println!("--verify needs the source, the compiled program has no tree to walk");
+            process::exit(2);
+        }
+        let code = load_compiled(&path);
+        let denied = capability::check_compiled(&code, &capabilities(args));
+        if !denied.is_empty() {
+            for d in denied.iter() {
+                println!("{}:{}", path, d);
+            }
+            process::exit(3);
+        }
+        let res = run_vm(&code, &entry, args);
+        println!("DONE:{:#?}\n", res);
+        return;
+    }
+    let (funs1, optimised) = match optimise(args) {
+        Some(p) => p,
+        None => return,
+    };
+    let res = run_vm(&bytecode::compile_program(&optimised), &entry, args);
+    if args.iter().any(|a| a == "--verify") {
+        // the tree walker is the reference for the vm,
+        // the program as written the reference for the optimised one
+        for (name, f) in optimised.iter() {
+            if let Err(args) = decision::verify(&f.binds) {
+                panic!(
+                    "the decision tree of {} picks other binds for {:?}",
+                    name, args
+                );
+            }
+        }
+        let walked = call_main(optimised, args);
+        assert_eq!(res, walked, "the vm gives a different result");
+        let reference = call_main(funs1, args);
+        assert_eq!(
+            walked, reference,
+            "the optimised program gives a different result"
+        );
+    }
     println!("DONE:{:#?}\n", res);
 }
 
-fn clean(s: String) -> String {
-    return s.chars().filter(|c| !c.is_whitespace()).collect();
+// the program as written and the optimised one, None when a guard is impure
+fn optimise(args: &[String]) -> Option<(eval::Program, eval::Program)> {
+    // on stderr, so the output of the program stays the same either way
+    let verbose = args.iter().any(|a| a == "--verbose");
+    let funs0 = program::fun_list_to_funs(parse(&path_arg(args)));
+    if verbose {
+        eprintln!("9{:#?}\n", (funs0));
+    }
+    let mut funs1 = match program::to_granted_program(funs0.clone(), &capabilities(args)) {
+        Ok(code) => code,
+        Err(denied) => {
+            for d in denied.iter() {
+                println!("{}:{}", path_arg(args), d);
+            }
+            process::exit(3);
+        }
+    };
+    if !args.iter().any(|a| a == "--check-specs") {
+        funs1.values_mut().for_each(|f| f.spec = None);
+    }
+    if verbose {
+        eprintln!("10{:#?}\n", (funs1));
+    }
+    let safe = match args.iter().find_map(|a| a.strip_prefix("--guard-safe=")) {
+        Some(list) => purity::parse_safe(list),
+        None => HashSet::new(),
+    };
+    if let Err(errors) = purity::check_guards(&funs1, &safe) {
+        for e in errors {
+            println!("{}", e);
+        }
+        return None;
+    }
+    //let funs = function::resolve_lambdas(funs0.clone());
+    let entry = function::FunctionName::Static("main".chars().collect());
+    let (optimised, inlined, removed) =
+        inline::inline_program(fold::fold_program(funs1.clone()), &entry);
+    if verbose {
+        for i in inlined.iter() {
+            eprintln!("inlined {} into {}", i.callee, i.caller);
+        }
+        for name in removed.iter() {
+            eprintln!("removed {}, it is never called", name);
+        }
+    }
+    // inlining puts arguments where constants can fold again
+    return Some((funs1, fold::fold_program(optimised)));
+}
+
+// writes the bytecode next to the source, program.txt -> program.rbc
+fn compile(args: &[String]) {
+    let path = path_arg(args);
+    let (_, optimised) = match optimise(args) {
+        Some(p) => p,
+        None => process::exit(1),
+    };
+    let source = fs::read_to_string(&path).expect("Cant read file");
+    let bytes = artifact::encode(&bytecode::compile_program(&optimised), &path, &source);
+    let out = match path.rsplit_once('.') {
+        Some((stem, _)) => format!("{}.rbc", stem),
+        None => format!("{}.rbc", path),
+    };
+    fs::write(&out, bytes).expect("Cant write file");
+    println!("compiled {} to {}", path, out);
+}
+
+fn load_compiled(path: &str) -> bytecode::Compiled {
+    let bytes = fs::read(path).expect("Cant read file");
+    match artifact::decode(&bytes, |source| fs::read_to_string(source).ok()) {
+        Ok(code) => return code,
+        Err(e) => {
+            println!("{}: {}", path, e);
+            process::exit(1);
+        }
+    }
+}
+
+// no host function unless --allow=rust::log,.. grants some
+fn capabilities(args: &[String]) -> capability::Capabilities {
+    match args.iter().find_map(|a| a.strip_prefix("--allow=")) {
+        None => return capability::Capabilities::Only(HashSet::new()),
+        Some(list) => match capability::parse(list) {
+            Ok(caps) => return caps,
+            Err(e) => {
+                println!("--allow: {}", e);
+                process::exit(2);
+            }
+        },
+    }
+}
+
+// the limits given, the defaults of the vm for the others
+fn limits(args: &[String]) -> vm::Limits {
+    let defaults = vm::Limits::defaults();
+    return vm::Limits {
+        reductions: flag_value(args, "--max-reductions").or(defaults.reductions),
+        depth: flag_value(args, "--max-depth")
+            .map(|n| n as usize)
+            .or(defaults.depth),
+        heap: flag_value(args, "--max-heap").or(defaults.heap),
+        time: flag_value(args, "--timeout-ms")
+            .map(Duration::from_millis)
+            .or(defaults.time),
+    };
+}
+
+// a script over any limit stops before the tree walker runs it for --verify
+fn run_vm(
+    code: &bytecode::Compiled,
+    entry: &function::FunctionName,
+    args: &[String],
+) -> eval::Data {
+    match vm::call(code, entry, Vec::new(), &limits(args), &capabilities(args)) {
+        Ok(res) => return res,
+        Err(vm::RunError::Runtime(e)) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+        Err(e) => {
+            println!("{}", e);
+            process::exit(3);
+        }
+    }
+}
+
+// the vm finished, so the tree walker must not raise anything either,
+// it can still run out of its limits, having no tail calls
+fn call_main(code: eval::Program, args: &[String]) -> eval::Data {
+    let main = function::FunctionName::Static("main".chars().collect());
+    let walked = eval::limited(&limits(args), &capabilities(args), || {
+        eval::catch(|| function::call(code, HashMap::new(), main, Vec::new()))
+    });
+    match walked {
+        Ok(Ok(res)) => return res,
+        Err(e) => {
+            println!("the tree walker: {}", e);
+            process::exit(3);
+        }
+        Ok(Err(raised)) => panic!(
+            "the tree walker raised {}: {}",
+            raised.class,
+            format::format_data(&raised.term)
+        ),
+    }
+}
+
+// rewrites the file in canonical layout, --check only reports if it would change
+fn fmt(args: &[String]) {
+    let path = path_arg(args);
+    if path.ends_with(".erl") {
+        println!("fmt only supports the name: .. end syntax");
+        process::exit(2);
+    }
+    let original = fs::read_to_string(&path).expect("Cant read file");
+    // a file with a comment inside a function is left alone instead of losing it
+    let formatted = match format::format_source(&original) {
+        Ok(f) => f,
+        Err(e) => {
+            println!("{}:{}", path, e);
+            process::exit(2);
+        }
+    };
+    if args.iter().any(|a| a == "--check") {
+        if original != formatted {
+            println!("{} is not formatted", path);
+            process::exit(1);
+        }
+    } else if original != formatted {
+        fs::write(&path, formatted).expect("Cant write file");
+    }
+}
+
+// reports undefined functions and unbound variables without running anything
+fn check(args: &[String]) {
+    let code = load(&path_arg(args));
+    if let Err(errors) = check::check_program(&code) {
+        for e in errors.iter() {
+            println!("{}", e);
+        }
+        process::exit(1);
+    }
+}
+
+fn lint(args: &[String]) {
+    let path = path_arg(args);
+    let code = load(&path);
+    let entry = function::FunctionName::Static("main".chars().collect());
+    for w in lint::lint_program(&code, &entry) {
+        println!("{}:{}", path, w);
+    }
+}
+
+// DOT by default, cycles without a base clause go to stderr so the graph stays valid
+fn callgraph(args: &[String]) {
+    let graph = callgraph::build(&load(&path_arg(args)));
+    if args.iter().any(|a| a == "--json") {
+        print!("{}", callgraph::to_json(&graph));
+    } else {
+        print!("{}", callgraph::to_dot(&graph));
+    }
+    let looping: Vec<&callgraph::Cycle> = graph.cycles.iter().filter(|c| !c.has_base).collect();
+    for c in looping.iter() {
+        eprintln!("{}", callgraph::describe_cycle(c));
+    }
+    if !looping.is_empty() {
+        process::exit(1);
+    }
+}
+
+// prints the inferred signature of every function, then any type mismatches
+fn types(args: &[String]) {
+    let (schemes, errors) = types::infer_program(&load(&path_arg(args)));
+    for (name, scheme) in schemes.iter() {
+        println!("{} : {}", name, scheme);
+    }
+    for e in errors.iter() {
+        println!("{}", e);
+    }
+    if !errors.is_empty() {
+        process::exit(1);
+    }
 }
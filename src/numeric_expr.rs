@@ -1,8 +1,11 @@
+use std::sync::Arc;
+
 use crate::{
     call_levels,
     eval::{self, Data},
     expr, function,
     tokens::Token,
+    vm::RuntimeErrorKind,
 };
 
 #[derive(Debug, Clone)]
@@ -10,6 +13,7 @@ pub enum NumericExpr {
     Identifier(Vec<char>),
     Call(function::FunctionName, Vec<expr::Expr>),
     Const(NumericData),
+    Negate(Box<NumericExpr>),
     ArrayOperator {
         op: ArrayNumOp,
         v: Vec<NumericExpr>,
@@ -50,47 +54,63 @@ pub fn eval_int(c: eval::Program, p: eval::ProgramState, expr: NumericExpr) -> N
         }
         NumericExpr::Identifier(name) => match expr::var_lookup(name, p) {
             eval::Data::Number(a) => a,
-            a => {
-                println!("BAD NEXPR{:#?}\n", a);
-                unimplemented!()
-            }
+            a => eval::fail(RuntimeErrorKind::BadArith(a)),
         },
+        NumericExpr::Negate(x) => {
+            let NumericData::Int(i) = eval_int(c, p, *x);
+            return checked(vec![i], i.checked_neg());
+        }
         NumericExpr::OrderedOperator { op, left, right } => {
             let NumericData::Int(l1) = eval_int(c.clone(), p.clone(), *left);
             let NumericData::Int(r1) = eval_int(c.clone(), p.clone(), *right);
             match op {
                 OrderedNumOp::SUB => {
-                    return NumericData::Int(l1 - r1);
+                    return checked(vec![l1, r1], l1.checked_sub(r1));
                 }
                 OrderedNumOp::DIV => {
-                    return NumericData::Int(l1 / r1);
+                    return checked(vec![l1, r1], l1.checked_div(r1));
                 }
             }
         }
         NumericExpr::ArrayOperator { op, v } => {
-            let vc = v.iter().map(|x| {
-                let NumericData::Int(i) = eval_int(c.clone(), p.clone(), x.clone());
-                i
-            });
+            let vc: Vec<i64> = v
+                .iter()
+                .map(|x| {
+                    let NumericData::Int(i) = eval_int(c.clone(), p.clone(), x.clone());
+                    i
+                })
+                .collect();
             match op {
                 ArrayNumOp::ADD => {
-                    return NumericData::Int(vc.sum());
+                    let res = vc.iter().try_fold(0, |a, b| i64::checked_add(a, *b));
+                    return checked(vc, res);
                 }
                 ArrayNumOp::MUL => {
-                    return NumericData::Int(vc.fold(1, |a, b| a * b));
+                    let res = vc.iter().try_fold(1, |a, b| i64::checked_mul(a, *b));
+                    return checked(vc, res);
                 }
             }
         }
         NumericExpr::Call(f, args) => match expr::eval_and_call(c, f, args, p) {
             eval::Data::Number(a) => a,
-            a => {
-                println!("BAD NEXPR{:#?}\n", a);
-                unimplemented!()
-            }
+            a => eval::fail(RuntimeErrorKind::BadArith(a)),
         },
     }
 }
 
+// None is an overflow or a division by zero, raised with the operands like the vm does
+fn checked(operands: Vec<i64>, res: Option<i64>) -> NumericData {
+    match res {
+        Some(i) => return NumericData::Int(i),
+        None => {
+            let operands = operands.into_iter().rev().fold(Data::Emptylist, |t, h| {
+                Data::List(Arc::new(Data::Number(NumericData::Int(h))), Arc::new(t))
+            });
+            eval::fail(RuntimeErrorKind::BadArith(operands))
+        }
+    }
+}
+
 pub fn call_levels_to_num_expr(level: call_levels::CallLevel) -> NumericExpr {
     match level.clone() {
         call_levels::CallLevel::OpLevel(token, sublevels) => {
@@ -112,6 +132,9 @@ pub fn call_levels_to_num_expr(level: call_levels::CallLevel) -> NumericExpr {
                     }
                 }
                 Token::SUB => match (subs.get(0), subs.get(1), subs.get(2)) {
+                    (Some(x), None, None) => {
+                        return NumericExpr::Negate(Box::new(x.clone()));
+                    }
                     (Some(left), Some(right), None) => {
                         return NumericExpr::OrderedOperator {
                             op: OrderedNumOp::SUB,
@@ -131,6 +154,10 @@ pub fn call_levels_to_num_expr(level: call_levels::CallLevel) -> NumericExpr {
             }
         }
         call_levels::CallLevel::Identifier(v) => return string_token_to_num_expr(v),
+        // pos + 1 looks pos up like any other atom would
+        call_levels::CallLevel::FunRef(f) => {
+            return NumericExpr::Identifier(f.to_string().chars().collect())
+        }
         call_levels::CallLevel::Call(fname, sublevels) => {
             let subs = sublevels
                 .into_iter()
@@ -1,21 +1,28 @@
 use std::collections::{HashMap, HashSet};
 
 use crate::{
-    call_levels, eval, expr, function, logic_expr, pattern_match,
+    call_levels,
+    capability::{Capabilities, Denied},
+    eval, expr, function, logic_expr, pattern_match,
     segments::{self, Segment},
-    tokens,
+    tokens, types,
 };
 
 #[derive(Debug, Clone)]
 pub struct Fun {
     pub name: Vec<char>,
     pub binds: Vec<Bind>,
+    pub line: usize,
+    pub end: usize,
+    pub spec: Option<types::Type>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Bind {
     pub pattern: Vec<pattern_match::ArgBind>,
     pub filters: Vec<Filter>,
+    pub line: usize,
+    pub end: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -28,12 +35,17 @@ pub struct Filter {
 pub struct FunFun {
     pub name: Vec<char>,
     pub binds: Vec<FunBind>,
+    pub line: usize,               // where the function is defined, 0 when unknown
+    pub end: usize,                // its last line, 0 when unknown
+    pub spec: Option<types::Type>, // from a -spec line right above it
 }
 
 #[derive(Debug, Clone)]
 pub struct FunBind {
     pub pattern: Vec<pattern_match::ArgBind>,
     pub filters: Vec<FunFilter>,
+    pub line: usize, // of the head pattern
+    pub end: usize,  // the last line of its last body, 0 when unknown
 }
 
 #[derive(Debug, Clone)]
@@ -47,50 +59,171 @@ pub type Functions = HashMap<Vec<char>, FunFun>;
 // if function names are duplicated
 // the last version of a function will win
 pub fn to_funs(clauses: Vec<segments::Segment>) -> Functions {
-    return funs_int(clauses, HashMap::new());
+    return fun_list_to_funs(to_fun_list(clauses));
 }
 
+// functions in the order they are written
+pub fn to_fun_list(clauses: Vec<segments::Segment>) -> Vec<FunFun> {
+    return funs_int(clauses, Vec::new());
+}
+
+pub fn fun_list_to_funs(funs: Vec<FunFun>) -> Functions {
+    return funs.into_iter().map(|f| (f.name.clone(), f)).collect();
+}
+
+// the key of a function written with more than one arity, f/2
+// `/` is no identifier character so it never clashes with a plain name
+pub fn arity_name(name: &[char], arity: usize) -> Vec<char> {
+    let mut key = name.to_vec();
+    key.push('/');
+    key.extend(arity.to_string().chars());
+    return key;
+}
+
+// f/2 -> f, names without an arity come back unchanged
+pub fn base_name(key: &[char]) -> &[char] {
+    match key.iter().position(|c| *c == '/') {
+        Some(i) => &key[0..i],
+        None => key,
+    }
+}
+
+// the `name: .. end` source of a whole program, for tests
+#[cfg(test)]
+pub fn from_source(src: &str) -> eval::Program {
+    let segs = segments::matched_terminators_located(tokens::string_to_located(src.to_string()));
+    return to_program(fun_list_to_funs(to_fun_list(segments::prune_clauses(segs))));
+}
+
+// one of the sample programs, by its path from the crate root
+#[cfg(test)]
+pub fn from_file(path: &str) -> eval::Program {
+    let src = std::fs::read_to_string(path).expect("Cant read file");
+    if path.ends_with(".erl") {
+        return from_erlang_source(&src);
+    }
+    return from_source(&src);
+}
+
+// the .erl source of a whole program, for tests
+#[cfg(test)]
+pub fn from_erlang_source(src: &str) -> eval::Program {
+    let tokens = tokens::string_to_located(crate::erlang::strip_line_comments(src));
+    return to_program(fun_list_to_funs(crate::erlang::to_fun_list(tokens)));
+}
+
+// every sample that runs to the end, program_inter.txt is still being written
+#[cfg(test)]
+pub const SAMPLES: [&str; 9] = [
+    "program.txt",
+    "program_case.txt",
+    "program_fold.txt",
+    "program_if.txt",
+    "program_match.txt",
+    "program_seq.txt",
+    "program_try.txt",
+    "program_vm.txt",
+    "program.erl",
+];
+
+// every rust:: call resolves, for the tools that do not run the program
 pub fn to_program(funs: Functions) -> eval::Program {
+    return lower(funs, &Capabilities::All, &mut Vec::new());
+}
+
+// the program when caps grants every rust:: call in it,
+// the calls it does not grant otherwise, with the line of the bind making them
+pub fn to_granted_program(
+    funs: Functions,
+    caps: &Capabilities,
+) -> Result<eval::Program, Vec<Denied>> {
+    let mut denied = Vec::new();
+    let code = lower(funs, caps, &mut denied);
+    if !denied.is_empty() {
+        denied.sort_by_key(|d| d.line);
+        return Err(denied);
+    }
+    return Ok(code);
+}
+
+fn lower(funs: Functions, caps: &Capabilities, denied: &mut Vec<Denied>) -> eval::Program {
     let staticnames = funs.clone();
     let res = funs
         .into_iter()
         .map(|(n, x)| {
             (
                 function::FunctionName::Static(n.to_vec()),
-                funfun_to_fun(x.clone(), staticnames.clone()),
+                funfun_to_fun(x.clone(), staticnames.clone(), caps, denied),
             )
         })
         .collect();
     return res;
 }
 
-fn funfun_to_fun(f: FunFun, context: Functions) -> Fun {
+// every function called from any guard or body of f
+pub fn fun_calls(f: &Fun) -> Vec<function::FunctionName> {
+    let mut acc = Vec::new();
+    for bind in f.binds.iter() {
+        for filter in bind.filters.iter() {
+            logic_expr::calls(&filter.head, &mut acc);
+            expr::calls(&filter.code, &mut acc);
+        }
+    }
+    return acc;
+}
+
+fn funfun_to_fun(
+    f: FunFun,
+    context: Functions,
+    caps: &Capabilities,
+    denied: &mut Vec<Denied>,
+) -> Fun {
     return Fun {
         binds: f
             .binds
             .into_iter()
-            .map(|b| funbind_to_bind(b, context.clone()))
+            .map(|b| funbind_to_bind(b, context.clone(), caps, &f.name, denied))
             .collect(),
         name: f.name,
+        line: f.line,
+        end: f.end,
+        spec: f.spec,
     };
 }
 
-fn funbind_to_bind(b: FunBind, context: Functions) -> Bind {
+fn funbind_to_bind(
+    b: FunBind,
+    context: Functions,
+    caps: &Capabilities,
+    caller: &[char],
+    denied: &mut Vec<Denied>,
+) -> Bind {
+    let scope = call_levels::Scope::new(context, caps.clone());
+    let filters = b
+        .filters
+        .into_iter()
+        .map(|x| funfilter_to_filter(x, &scope))
+        .collect();
+    for f in scope.denied.take().into_iter() {
+        denied.push(Denied {
+            function: f,
+            caller: caller.to_vec(),
+            line: b.line,
+        });
+    }
     return Bind {
         pattern: b.pattern,
-        filters: b
-            .filters
-            .into_iter()
-            .map(|x| funfilter_to_filter(x, context.clone()))
-            .collect(),
+        filters: filters,
+        line: b.line,
+        end: b.end,
     };
 }
 
-fn funfilter_to_filter(f: FunFilter, context: Functions) -> Filter {
+fn funfilter_to_filter(f: FunFilter, context: &call_levels::Scope) -> Filter {
     let h = if f.head.is_empty() {
         logic_expr::LogicExpr::True // no filter -> always true filter
     } else {
-        let h = call_levels::segments_to_call_level(f.head, context.clone());
+        let h = call_levels::segments_to_call_level(f.head, context);
         logic_expr::call_levels_to_logic_expr(h)
     };
     let c = call_levels::segments_to_call_level(f.code, context);
@@ -100,16 +233,28 @@ fn funfilter_to_filter(f: FunFilter, context: Functions) -> Filter {
     };
 }
 
-fn funs_int(clauses: Vec<segments::Segment>, mut done: Functions) -> Functions {
+fn funs_int(clauses: Vec<segments::Segment>, mut done: Vec<FunFun>) -> Vec<FunFun> {
     if clauses.len() == 0 {
         return done;
     }
+    if let Some((spec_name, spec, rest)) = take_spec(&clauses) {
+        let mut funs = funs_int(rest, Vec::new());
+        if funs.is_empty() || funs[0].name != spec_name {
+            println!("SPEC WITHOUT FUNCTION{:#?}\n", spec_name);
+            unimplemented!()
+        }
+        funs[0].spec = Some(spec);
+        done.append(&mut funs);
+        return done;
+    }
     match (clauses[0].clone(), clauses[1].clone()) {
         (
             segments::Segment::UnMatched(i), // [tokens::Token::Identifier(i)]
             segments::Segment::Clause {
                 head: tokens::Token::FunStart,
                 body: b,
+                line,
+                end,
                 ..
             },
         ) => match i[0].clone() {
@@ -118,17 +263,63 @@ fn funs_int(clauses: Vec<segments::Segment>, mut done: Functions) -> Functions {
                 let cfun = FunFun {
                     name: n.clone(),
                     binds: segments_to_binds(b),
+                    line: line,
+                    end: end,
+                    spec: None,
                 };
-                done.insert(n.clone(), cfun);
+                done.push(cfun);
                 let rfun = funs_int(rest, done);
                 return rfun;
             }
             _ => unimplemented!(),
         },
+        // if is a keyword since if expressions, a function of that name has to be renamed
+        (
+            segments::Segment::Clause {
+                head: tokens::Token::If,
+                line,
+                ..
+            },
+            _,
+        ) => {
+            println!(
+                "A FUNCTION CAN NOT BE NAMED if, IT IS A KEYWORD, LINE {}\n",
+                line
+            );
+            unimplemented!()
+        }
         _ => unimplemented!(),
     }
 }
 
+// -spec name(T, ..) -> T. in front of a definition, split into
+// its name, its type and the segments after the dot
+fn take_spec(
+    clauses: &[segments::Segment],
+) -> Option<(Vec<char>, types::Type, Vec<segments::Segment>)> {
+    match clauses.first() {
+        Some(segments::Segment::UnMatched(t))
+            if t.len() >= 2
+                && t[0] == tokens::Token::SUB
+                && t[1] == tokens::Token::Identifier("spec".chars().collect()) => {}
+        _ => return None,
+    }
+    for (i, seg) in clauses.iter().enumerate() {
+        if let segments::Segment::UnMatched(t) = seg {
+            if let Some(dot) = t.iter().position(|x| *x == tokens::Token::Dot) {
+                let mut spec = segments::flatten(clauses[0..i].to_vec());
+                spec.extend_from_slice(&t[0..dot]);
+                let (name, ty) = types::parse_spec(&spec[2..spec.len()]);
+                let mut rest = vec![segments::Segment::UnMatched(t[dot + 1..t.len()].to_vec())];
+                rest.extend_from_slice(&clauses[i + 1..clauses.len()]);
+                return Some((name, ty, rest));
+            }
+        }
+    }
+    println!("SPEC WITHOUT DOT{:#?}\n", clauses[0]);
+    unimplemented!()
+}
+
 fn segments_to_binds(binds: Vec<segments::Segment>) -> Vec<FunBind> {
     if binds.len() == 0 {
         return Vec::new();
@@ -137,15 +328,19 @@ fn segments_to_binds(binds: Vec<segments::Segment>) -> Vec<FunBind> {
         segments::Segment::Clause {
             head: tokens::Token::LeftP,
             body: bindbody,
+            line,
             ..
         } => {
             let rest = binds[1..binds.len()].to_vec();
-            let (filters, rest) = segments_to_filters(rest);
-            let mut obind = segments_to_binds(rest);
+            let (filters, after) = segments_to_filters(rest.clone());
+            let end = end_line(&rest[0..rest.len() - after.len()]);
+            let mut obind = segments_to_binds(after);
             let bindpattern = pattern_match::bind_segment_to_bindpattern(bindbody);
             let cbind = FunBind {
                 pattern: bindpattern,
                 filters: filters,
+                line: line,
+                end: end,
             };
             obind.insert(0, cbind);
             return obind;
@@ -157,6 +352,18 @@ fn segments_to_binds(binds: Vec<segments::Segment>) -> Vec<FunBind> {
     }
 }
 
+// where the last clause of the segments ends
+fn end_line(s: &[Segment]) -> usize {
+    return s
+        .iter()
+        .rev()
+        .find_map(|x| match x {
+            Segment::Clause { end, .. } => Some(*end),
+            _ => None,
+        })
+        .unwrap_or(0);
+}
+
 fn segments_to_filters(s: Vec<segments::Segment>) -> (Vec<FunFilter>, Vec<Segment>) {
     if s.len() == 0 {
         return (Vec::new(), s);
@@ -0,0 +1,265 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use crate::{
+    eval,
+    expr::Expr,
+    function::FunctionName,
+    logic_expr::{self, LogicExpr},
+    program,
+    visit::{self, Visitor},
+};
+
+// Preconditions must be side effect free (see notes.md), so a guard may only call
+// builtins and user functions that never reach a rust:: host call.
+// Host or user functions the embedder knows to be safe can be passed in as `safe`,
+// from the command line with --guard-safe=apply,rust::now.
+
+#[derive(Debug, Clone)]
+pub struct GuardError {
+    pub function: Vec<char>,
+    pub call: FunctionName,
+    pub reason: Impurity,
+}
+
+#[derive(Debug, Clone)]
+pub enum Impurity {
+    HostCall,
+    DynamicCall,
+    // the called function is impure because of this call
+    CallsImpure(FunctionName),
+}
+
+impl fmt::Display for GuardError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name: String = self.function.iter().collect();
+        match &self.reason {
+            Impurity::HostCall => write!(
+                f,
+                "guard in {} calls host function {}, which is not guard safe",
+                name, self.call
+            ),
+            Impurity::DynamicCall => write!(
+                f,
+                "guard in {} calls {} through a variable, which can not be checked",
+                name, self.call
+            ),
+            Impurity::CallsImpure(cause) => write!(
+                f,
+                "guard in {} calls {}, which is not guard safe since it calls {}",
+                name, self.call, cause
+            ),
+        }
+    }
+}
+
+pub fn check_guards(
+    code: &eval::Program,
+    safe: &HashSet<FunctionName>,
+) -> Result<(), Vec<GuardError>> {
+    let impure = impure_functions(code, safe);
+    let mut names: Vec<&FunctionName> = code.keys().collect();
+    names.sort();
+    let mut errors = Vec::new();
+    for name in names {
+        let fun = &code[name];
+        let mut guards: Vec<LogicExpr> = fun
+            .binds
+            .iter()
+            .flat_map(|b| b.filters.iter().map(|f| f.head.clone()))
+            .collect();
+        visit::walk_fun(&mut Guards(&mut guards), fun);
+        for guard in guards.iter() {
+            let mut calls = Vec::new();
+            logic_expr::calls(guard, &mut calls);
+            for call in calls {
+                if let Some(reason) = violation(&call, &impure, safe) {
+                    errors.push(GuardError {
+                        function: fun.name.clone(),
+                        call: call,
+                        reason: reason,
+                    });
+                }
+            }
+        }
+    }
+    if errors.is_empty() {
+        return Ok(());
+    } else {
+        return Err(errors);
+    }
+}
+
+// the guards of the case clauses and if branches in the bodies, they are preconditions too
+struct Guards<'a>(&'a mut Vec<LogicExpr>);
+
+impl<'a> Visitor for Guards<'a> {
+    fn expr(&mut self, e: &Expr) {
+        match e {
+            Expr::Case { clauses, .. } => {
+                for c in clauses.iter() {
+                    self.0.extend(c.filters.iter().map(|f| f.head.clone()));
+                }
+            }
+            Expr::If(branches) => self.0.extend(branches.iter().map(|f| f.head.clone())),
+            _ => {}
+        }
+        visit::walk_expr(self, e);
+    }
+}
+
+// apply,rust::now -> the user function apply and the host function now
+pub fn parse_safe(list: &str) -> HashSet<FunctionName> {
+    return list
+        .split(',')
+        .map(|e| e.trim())
+        .filter(|e| !e.is_empty())
+        .map(|e| match e.strip_prefix("rust::") {
+            Some(name) => FunctionName::Rust(name.chars().collect()),
+            None => FunctionName::Static(e.chars().collect()),
+        })
+        .collect();
+}
+
+fn violation(
+    call: &FunctionName,
+    impure: &HashMap<FunctionName, FunctionName>,
+    safe: &HashSet<FunctionName>,
+) -> Option<Impurity> {
+    if safe.contains(call) {
+        return None;
+    }
+    match call {
+        FunctionName::Rust(_) => Some(Impurity::HostCall),
+        FunctionName::Dynamic(_) => Some(Impurity::DynamicCall),
+        FunctionName::Static(_) => impure
+            .get(call)
+            .map(|cause| Impurity::CallsImpure(cause.clone())),
+        FunctionName::Builtin(_) => None,
+    }
+}
+
+// user function -> the first call that makes it impure
+// grows until no more functions turn impure, so call cycles are handled,
+// functions are visited by name so the cause reported does not change between runs
+fn impure_functions(
+    code: &eval::Program,
+    safe: &HashSet<FunctionName>,
+) -> HashMap<FunctionName, FunctionName> {
+    let mut names: Vec<&FunctionName> = code.keys().collect();
+    names.sort();
+    let mut impure = HashMap::new();
+    loop {
+        let mut changed = false;
+        for name in names.iter().cloned() {
+            let fun = &code[name];
+            if impure.contains_key(name) || safe.contains(name) {
+                continue;
+            }
+            let cause = program::fun_calls(fun)
+                .into_iter()
+                .find(|call| violation(call, &impure, safe).is_some());
+            if let Some(call) = cause {
+                impure.insert(name.clone(), call);
+                changed = true;
+            }
+        }
+        if !changed {
+            return impure;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host_calls_in_guards(src: &str) -> Vec<String> {
+        return guard_errors(src, "");
+    }
+
+    // safe as it is given to --guard-safe=
+    fn guard_errors(src: &str, safe: &str) -> Vec<String> {
+        match check_guards(&program::from_source(src), &parse_safe(safe)) {
+            Ok(()) => return Vec::new(),
+            Err(errors) => return errors.iter().map(|e| e.to_string()).collect(),
+        }
+    }
+
+    // wrapper only reaches rust::log through noisy
+    const NOISY: &str = "noisy:\n    (X)\n    / rust::log(X) \\\nend\n\
+                         wrapper:\n    (X)\n    / noisy(X) \\\nend\n\
+                         main:\n    (X)\n    { wrapper(X) == [] }\n    / a \\\nend\n";
+
+    #[test]
+    fn head_guards_name_the_host_call() {
+        let src = "main:\n    (X)\n    { rust::log(X) == [] }\n    / X \\\nend\n";
+        assert_eq!(
+            host_calls_in_guards(src),
+            vec!["guard in main calls host function rust::log, which is not guard safe"]
+        );
+    }
+
+    #[test]
+    fn an_impure_function_is_named_with_its_cause() {
+        assert_eq!(
+            host_calls_in_guards(NOISY),
+            vec!["guard in main calls wrapper, which is not guard safe since it calls noisy"]
+        );
+    }
+
+    #[test]
+    fn functions_and_host_calls_marked_safe_pass() {
+        assert!(guard_errors(NOISY, "noisy").is_empty());
+        assert!(guard_errors(NOISY, "rust::log").is_empty());
+    }
+
+    #[test]
+    fn pure_recursion_and_builtins_pass() {
+        let src = "len:\n    ([])\n    / 0 \\\n    ([_|T])\n    / 1 + len(T) \\\nend\n\
+                   main:\n    (X)\n    { is_list(X) && len(X) > 1 }\n    / a \\\nend\n";
+        assert!(host_calls_in_guards(src).is_empty());
+    }
+
+    #[test]
+    fn calls_through_a_variable_can_not_be_checked() {
+        let src = "main:\n    (F)\n    { F(1) == 1 }\n    / a \\\nend\n";
+        assert_eq!(
+            host_calls_in_guards(src),
+            vec!["guard in main calls F through a variable, which can not be checked"]
+        );
+    }
+
+    #[test]
+    fn safe_lists_name_user_and_host_functions() {
+        let expected: HashSet<FunctionName> = vec![
+            FunctionName::Static("apply".chars().collect()),
+            FunctionName::Rust("now".chars().collect()),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(parse_safe(" apply, rust::now,,"), expected);
+    }
+
+    #[test]
+    fn case_clause_guards_are_checked() {
+        let src = "main:\n    (X)\n    /\n    case X of\n        \
+                   Y { rust::log(Y) == [] } / a \\\n        _ / b \\\n    end\n    \\\nend\n";
+        assert_eq!(
+            host_calls_in_guards(src),
+            vec!["guard in main calls host function rust::log, which is not guard safe"]
+        );
+    }
+
+    #[test]
+    fn if_guards_are_checked() {
+        let src = "main:\n    ()\n    /\n    \
+                   if { rust::log(1) == [] } / a \\ / b \\ end\n    \\\nend\n";
+        assert_eq!(
+            host_calls_in_guards(src),
+            vec!["guard in main calls host function rust::log, which is not guard safe"]
+        );
+    }
+}
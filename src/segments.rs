@@ -1,4 +1,4 @@
-use crate::tokens::Token;
+use crate::tokens::{self, Token};
 
 #[derive(Debug, Clone)]
 pub enum Segment {
@@ -6,6 +6,8 @@ pub enum Segment {
         head: Token,
         body: Vec<Segment>,
         tail: Token,
+        line: usize, // of the head token, 0 when unknown
+        end: usize,  // of the tail token, 0 when unknown
     },
     UnMatched(Vec<Token>),
 }
@@ -26,10 +28,14 @@ pub fn prune_clauses(t: Vec<Segment>) -> Vec<Segment> {
                 head: h,
                 tail: t,
                 body: b,
+                line: l,
+                end: e,
             } => Segment::Clause {
                 head: h.clone(),
                 tail: t.clone(),
                 body: prune_clauses(b.clone()),
+                line: *l,
+                end: *e,
             },
             a => a.clone(),
         })
@@ -39,13 +45,21 @@ pub fn prune_clauses(t: Vec<Segment>) -> Vec<Segment> {
 
 //recursively match terminators until there are no terminators left
 pub fn matched_terminators(t: Vec<crate::tokens::Token>) -> Vec<Segment> {
-    return clauses_int(t, Vec::new(), Vec::new());
+    return matched_terminators_located(t.into_iter().map(|x| (x, 0)).collect());
 }
 
+// same as matched_terminators, but clauses remember the lines they start and end on
+pub fn matched_terminators_located(t: Vec<tokens::Located>) -> Vec<Segment> {
+    return clauses_int(t, Vec::new(), Vec::new(), true);
+}
+
+// inside a clause `:` no longer starts a function,
+// try a catch error:E -> 0 end uses it between the class and the pattern
 fn clauses_int(
-    t: Vec<crate::tokens::Token>,
+    t: Vec<tokens::Located>,
     mut done: Vec<Segment>,
     mut acc: Vec<Token>,
+    top: bool,
 ) -> Vec<Segment> {
     match t.len() {
         0 => {
@@ -54,16 +68,17 @@ fn clauses_int(
         }
         _ => {
             let rest = t[1..t.len()].to_vec();
-            match crate::tokens::has_pair(t[0].clone()) {
-                Some(p) => {
+            let (token, line) = t[0].clone();
+            match crate::tokens::has_pair(token.clone()) {
+                Some(p) if top || token != Token::FunStart => {
                     done.push(Segment::UnMatched(acc));
-                    let (clause, t) = build_clause(t[0].clone(), p, Vec::new(), rest, 0);
+                    let (clause, t) = build_clause(token, p, line, Vec::new(), rest, 0);
                     done.push(clause);
-                    return clauses_int(t, done, Vec::new());
+                    return clauses_int(t, done, Vec::new(), top);
                 }
                 _ => {
-                    acc.push(t[0].clone());
-                    return clauses_int(rest, done, acc);
+                    acc.push(token);
+                    return clauses_int(rest, done, acc, top);
                 }
             }
         }
@@ -73,33 +88,66 @@ fn clauses_int(
 fn build_clause(
     head: Token,
     tail: Token,
-    mut body: Vec<Token>,
-    t: Vec<crate::tokens::Token>,
+    line: usize,
+    mut body: Vec<tokens::Located>,
+    t: Vec<tokens::Located>,
     depth: usize,
-) -> (Segment, Vec<Token>) {
+) -> (Segment, Vec<tokens::Located>) {
     let rest = if t.len() > 1 {
         t[1..t.len()].to_vec()
     } else {
         Vec::new()
     };
-    if t[0] == tail && depth == 0 {
-        let b2 = matched_terminators(body);
+    if t[0].0 == tail && depth == 0 {
+        // the words of a comment may be keywords, it is not looked into
+        let b2 = match head {
+            Token::CommentStart => vec![Segment::UnMatched(
+                body.into_iter().map(|(t, _)| t).collect(),
+            )],
+            _ => clauses_int(body, Vec::new(), Vec::new(), false),
+        };
         return (
             Segment::Clause {
                 head: head,
                 tail: tail,
                 body: b2,
+                line: line,
+                end: t[0].1,
             },
             rest,
         );
-    } else if t[0] == head {
+    } else if opens_nested(&t[0].0, &tail) {
         body.push(t[0].clone());
-        return build_clause(head, tail, body, rest, depth + 1);
-    } else if t[0] == tail {
+        return build_clause(head, tail, line, body, rest, depth + 1);
+    } else if t[0].0 == tail {
         body.push(t[0].clone());
-        return build_clause(head, tail, body, rest, depth - 1);
+        return build_clause(head, tail, line, body, rest, depth - 1);
     } else {
         body.push(t[0].clone());
-        return build_clause(head, tail, body, rest, depth);
+        return build_clause(head, tail, line, body, rest, depth);
+    }
+}
+
+// a try .. end inside a function nests like a function inside a function would,
+// the `:` of a catch clause inside either does not
+fn opens_nested(t: &Token, tail: &Token) -> bool {
+    return *t != Token::FunStart && tokens::has_pair(t.clone()).as_ref() == Some(tail);
+}
+
+// inverse of matched_terminators, gives back the tokens a segment list was built from
+pub fn flatten(s: Vec<Segment>) -> Vec<Token> {
+    let mut ret = Vec::new();
+    for seg in s {
+        match seg {
+            Segment::UnMatched(mut tv) => ret.append(&mut tv),
+            Segment::Clause {
+                head, body, tail, ..
+            } => {
+                ret.push(head);
+                ret.append(&mut flatten(body));
+                ret.push(tail);
+            }
+        }
     }
+    return ret;
 }
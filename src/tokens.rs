@@ -6,31 +6,53 @@ pub enum MyError {
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     AND,
+    ANDALSO,
+    Arrow,
     Add,
     ArgTerm,
     Assign,
+    Case,
+    Catch,
     CodeEnd,
     CodeStart,
     CommentEnd,
     CommentStart,
+    Dot,
     END,
     Eq,
     FunStart,
+    GT,
+    GTE,
     Identifier(Vec<char>),
+    If,
     LeftB,
     LeftP,
     LeftW,
+    LT,
+    LTE,
     MUL,
     NEQ,
+    NOT,
+    Of,
+    OR,
+    ORELSE,
     Pipe,
     RightB,
     RightP,
     RightW,
     SUB,
+    Semicolon,
     Qualify,
+    Try,
+    When,
+    XOR,
 }
+// a token and the line it starts on, counting from 1
+pub type Located = (Token, usize);
+
 // mapping left pair -> right pair
 // ( -> )
+// `:` only opens a function at the top level, see segments.rs
 pub fn has_pair(t: Token) -> Option<Token> {
     let p = match t {
         Token::CommentStart => Token::CommentEnd,
@@ -39,6 +61,9 @@ pub fn has_pair(t: Token) -> Option<Token> {
         Token::LeftW => Token::RightW,
         Token::CodeStart => Token::CodeEnd,
         Token::FunStart => Token::END,
+        Token::Try => Token::END,
+        Token::Case => Token::END,
+        Token::If => Token::END,
         _ => return None,
     };
     return Some(p);
@@ -46,44 +71,89 @@ pub fn has_pair(t: Token) -> Option<Token> {
 
 pub fn is_operator_token(t: Token) -> bool {
     match t {
-        Token::Add | Token::Eq | Token::NEQ | Token::AND | Token::MUL | Token::SUB => true,
+        Token::Add
+        | Token::Eq
+        | Token::NEQ
+        | Token::AND
+        | Token::MUL
+        | Token::SUB
+        | Token::LT
+        | Token::GT
+        | Token::LTE
+        | Token::GTE
+        | Token::OR
+        | Token::XOR
+        | Token::ANDALSO
+        | Token::ORELSE => true,
         _ => false,
     }
 }
 
+// whitespace separates tokens, any run of characters that do not start
+// an operator becomes a word, which is either a keyword or an identifier
 pub fn string_to(s: String) -> Vec<Token> {
-    let mut ret: Vec<Token> = Vec::new();
+    return string_to_located(s).into_iter().map(|(t, _)| t).collect();
+}
+
+pub fn string_to_located(s: String) -> Vec<Located> {
+    let mut ret: Vec<Located> = Vec::new();
     let text: Vec<char> = s.chars().collect();
     let mut token_head = 0;
-    let mut identifier_head = 0;
-    let mut identifier_tail = 0;
-    loop {
-        let maybe_token = if token_head >= (text.len() - 1) {
-            return ret;
-        } else if token_head + 1 == text.len() {
-            single_char_to_token(text[token_head])
-        } else if token_head + 2 == text.len() {
-            two_chars_to_token(text[token_head], text[token_head + 1])
-        } else {
-            tre_chars_to_token(text[token_head], text[token_head + 1], text[token_head + 2])
-        };
-        match maybe_token {
-            Some((t, ts)) => {
-                if identifier_tail != token_head {
-                    let id = text[identifier_tail..identifier_head].to_vec();
-                    ret.push(Token::Identifier(id));
-                }
-                ret.push(t);
-                token_head = token_head + (ts as usize); // dose not seem to increment
-                identifier_tail = token_head;
-                continue;
+    let mut line = 1;
+    while token_head < text.len() {
+        let c = text[token_head];
+        if c.is_whitespace() {
+            if c == '\n' {
+                line += 1;
             }
-            None => {
+            token_head += 1;
+        } else if is_word_char(c) {
+            let word_tail = token_head;
+            while token_head < text.len() && is_word_char(text[token_head]) {
                 token_head += 1;
-                identifier_head = token_head;
+            }
+            ret.push((word_to_token(text[word_tail..token_head].to_vec()), line));
+        } else {
+            let maybe_token = match (text.get(token_head + 1), text.get(token_head + 2)) {
+                (Some(b), Some(d)) => tre_chars_to_token(c, *b, *d),
+                (Some(b), None) => two_chars_to_token(c, *b),
+                _ => single_char_to_token(c),
+            };
+            match maybe_token {
+                Some((t, ts)) => {
+                    ret.push((t, line));
+                    token_head += ts as usize;
+                }
+                None => {
+                    println!("Cannot tokenize{:#?}\n", c);
+                    unimplemented!()
+                }
             }
         }
     }
+    return ret;
+}
+
+fn is_word_char(c: char) -> bool {
+    return !c.is_whitespace() && c != '&' && single_char_to_token(c).is_none();
+}
+
+fn word_to_token(word: Vec<char>) -> Token {
+    let text: String = word.iter().collect();
+    match text.as_str() {
+        "end" => Token::END,
+        "not" => Token::NOT,
+        "xor" => Token::XOR,
+        "andalso" => Token::ANDALSO,
+        "orelse" => Token::ORELSE,
+        "when" => Token::When,
+        "try" => Token::Try,
+        "catch" => Token::Catch,
+        "case" => Token::Case,
+        "of" => Token::Of,
+        "if" => Token::If,
+        _ => Token::Identifier(word),
+    }
 }
 
 fn single_char_to_token(char: char) -> Option<(Token, u16)> {
@@ -103,6 +173,10 @@ fn single_char_to_token(char: char) -> Option<(Token, u16)> {
         '*' => Token::MUL,
         '-' => Token::SUB,
         '=' => Token::Assign,
+        '<' => Token::LT,
+        '>' => Token::GT,
+        ';' => Token::Semicolon,
+        '.' => Token::Dot,
         _ => {
             return None;
         }
@@ -115,7 +189,11 @@ fn two_chars_to_token(a: char, b: char) -> Option<(Token, u16)> {
         ('/', '*') => Token::CommentStart,
         ('*', '/') => Token::CommentEnd,
         ('&', '&') => Token::AND,
+        ('|', '|') => Token::OR,
         ('=', '=') => Token::Eq,
+        ('=', '<') => Token::LTE,
+        ('>', '=') => Token::GTE,
+        ('-', '>') => Token::Arrow,
         (':', ':') => Token::Qualify,
         _ => return single_char_to_token(a),
     };
@@ -127,9 +205,6 @@ fn tre_chars_to_token(a: char, b: char, c: char) -> Option<(Token, u16)> {
         ('=', '/', '=') => {
             return Some((Token::NEQ, 3));
         }
-        ('e', 'n', 'd') => {
-            return Some((Token::END, 3));
-        }
         _ => {
             return two_chars_to_token(a, b);
         }
@@ -0,0 +1,789 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    callgraph,
+    eval::{self, Data},
+    expr::Expr,
+    function::{self, FunctionName},
+    inline,
+    logic_expr::LogicExpr,
+    numeric_expr::NumericExpr,
+    pattern_match::{self, ArgBind},
+    program,
+    tokens::Token,
+    visit::{self, Visitor},
+};
+
+// Hindley-Milner type inference over the lowered program.
+//
+// Functions are inferred one call graph cycle at a time, callees first.
+// Inside a cycle a function has a single type, once the cycle is done its
+// free type variables are generalised, so map gets ([a], a -> b, [b]) -> [b]
+// and every caller can use it at its own types.
+//
+// A function with a -spec is checked against it instead, the variables of the
+// spec may not be narrowed by the body, and callers see the spec as its type.
+//
+// Lists hold values of one type, so an improper list like [1|true] is a mismatch.
+// == and the comparisons want both sides to have the same type.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Var(usize),
+    Number,
+    Boolean,
+    Atom,
+    List(Box<Type>),
+    Fun(Vec<Type>, Box<Type>),
+    // a variable of a -spec, only equal to itself while its function is checked
+    Named(Vec<char>),
+}
+
+// a type with its variables quantified, map : forall a b. ([a], a -> b, [b]) -> [b]
+#[derive(Debug, Clone)]
+pub struct Scheme {
+    pub vars: Vec<usize>,
+    pub ty: Type,
+}
+
+#[derive(Debug, Clone)]
+pub struct TypeError {
+    pub function: Vec<char>,
+    pub clause: usize,
+    pub expected: Type,
+    pub found: Type,
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", format_type(&self.ty, &mut HashMap::new()))
+    }
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name: String = self.function.iter().collect();
+        // one naming for both so a shared variable prints the same
+        let mut names = HashMap::new();
+        let expected = format_type(&self.expected, &mut names);
+        let found = format_type(&self.found, &mut names);
+        write!(
+            f,
+            "{} clause {}: expected {}, found {}",
+            name,
+            self.clause + 1,
+            expected,
+            found
+        )
+    }
+}
+
+// number, [a], (a, b) -> c, type variables named a, b, c.. in order of appearance
+fn format_type(t: &Type, names: &mut HashMap<usize, String>) -> String {
+    match t {
+        Type::Var(v) => {
+            let next = names.len();
+            return names.entry(*v).or_insert_with(|| var_name(next)).clone();
+        }
+        Type::Number => "number".to_string(),
+        Type::Boolean => "boolean".to_string(),
+        Type::Atom => "atom".to_string(),
+        Type::List(x) => format!("[{}]", format_type(x, names)),
+        Type::Named(n) => n.iter().collect(),
+        Type::Fun(args, ret) => {
+            let args: Vec<String> = args.iter().map(|a| format_type(a, names)).collect();
+            let ret = format_type(ret, names);
+            if args.len() == 1 && !args[0].contains("->") {
+                format!("{} -> {}", args[0], ret)
+            } else {
+                format!("({}) -> {}", args.join(", "), ret)
+            }
+        }
+    }
+}
+
+fn var_name(i: usize) -> String {
+    let letter = (b'a' + (i % 26) as u8) as char;
+    if i < 26 {
+        return letter.to_string();
+    } else {
+        return format!("{}{}", letter, i / 26);
+    }
+}
+
+// the type of every function, sorted by name, and every mismatch found on the way
+pub fn infer_program(code: &eval::Program) -> (Vec<(FunctionName, Scheme)>, Vec<TypeError>) {
+    let mut graph = callgraph::build(code);
+    // a function named as a value is typed before the functions naming it
+    for (name, fun) in code.iter() {
+        for f in inline::fun_refs(fun) {
+            graph.edges.push((name.clone(), f));
+        }
+    }
+    let mut state = Infer {
+        subst: Vec::new(),
+        schemes: HashMap::new(),
+        group: HashMap::new(),
+        errors: Vec::new(),
+        current: (Vec::new(), 0),
+    };
+    for group in callgraph::components(&graph.nodes, &graph.edges) {
+        let funs: Vec<(&FunctionName, &program::Fun)> = group
+            .iter()
+            .filter_map(|name| code.get_key_value(name))
+            .collect();
+        state.group.clear();
+        for (name, fun) in funs.iter() {
+            let arity = fun.binds.first().map(|b| b.pattern.len()).unwrap_or(0);
+            let args = (0..arity).map(|_| state.fresh()).collect();
+            let ty = Type::Fun(args, Box::new(state.fresh()));
+            if let Some(spec) = &fun.spec {
+                state.current = (fun.name.clone(), 0);
+                state.unify(spec, &ty);
+            }
+            state.group.insert((*name).clone(), ty);
+        }
+        for (name, fun) in funs.iter() {
+            state.infer_fun(name, fun);
+        }
+        for (name, _) in funs.iter() {
+            let ty = state.resolve(&state.group[*name]);
+            let ty = unname(&ty, &mut HashMap::new(), &mut state);
+            let mut vars = Vec::new();
+            free_vars(&ty, &mut vars);
+            state
+                .schemes
+                .insert((*name).clone(), Scheme { vars: vars, ty: ty });
+        }
+    }
+    let mut res: Vec<(FunctionName, Scheme)> = state.schemes.into_iter().collect();
+    res.sort_by(|a, b| a.0.cmp(&b.0));
+    return (res, state.errors);
+}
+
+struct Infer {
+    // what each type variable was unified with, None while still free
+    subst: Vec<Option<Type>>,
+    schemes: HashMap<FunctionName, Scheme>,
+    // the cycle being inferred, not generalised yet
+    group: HashMap<FunctionName, Type>,
+    errors: Vec<TypeError>,
+    // function and clause errors are reported against
+    current: (Vec<char>, usize),
+}
+
+type Env = HashMap<Vec<char>, Type>;
+
+impl Infer {
+    fn fresh(&mut self) -> Type {
+        self.subst.push(None);
+        return Type::Var(self.subst.len() - 1);
+    }
+
+    fn infer_fun(&mut self, name: &FunctionName, fun: &program::Fun) {
+        let (args, ret) = match &self.group[name] {
+            Type::Fun(args, ret) => (args.clone(), *ret.clone()),
+            _ => return,
+        };
+        for (i, bind) in fun.binds.iter().enumerate() {
+            self.current = (fun.name.clone(), i);
+            let mut env = Env::new();
+            for (p, a) in bind.pattern.iter().zip(args.iter()) {
+                let pt = self.pattern(p, &mut env);
+                self.unify(a, &pt);
+            }
+            for filter in bind.filters.iter() {
+                self.logic(&filter.head, &env);
+                let ct = self.expr(&filter.code, &env);
+                self.unify(&ret, &ct);
+            }
+        }
+    }
+
+    // the type a pattern matches, adding the variables it binds to env
+    fn pattern(&mut self, p: &ArgBind, env: &mut Env) -> Type {
+        match p {
+            // every _ is a different variable
+            ArgBind::Identifier(n) if pattern_match::is_wildcard(n) => self.fresh(),
+            ArgBind::Identifier(n) => {
+                if let Some(t) = env.get(n) {
+                    return t.clone();
+                }
+                let t = self.fresh();
+                env.insert(n.clone(), t.clone());
+                return t;
+            }
+            ArgBind::Emptylist => Type::List(Box::new(self.fresh())),
+            ArgBind::ConstPattern(d) => self.data(d),
+            ArgBind::ListPattern { head, tail } => {
+                let h = self.pattern(head, env);
+                let t = self.pattern(tail, env);
+                let list = Type::List(Box::new(h));
+                self.unify(&list, &t);
+                return list;
+            }
+        }
+    }
+
+    fn data(&mut self, d: &Data) -> Type {
+        match d {
+            Data::Number(_) => Type::Number,
+            Data::Boolean(_) => Type::Boolean,
+            Data::Atom(_) => Type::Atom,
+            Data::Emptylist => Type::List(Box::new(self.fresh())),
+            Data::List(h, t) => {
+                let h = self.data(h);
+                let t = self.data(t);
+                let list = Type::List(Box::new(h));
+                self.unify(&list, &t);
+                return list;
+            }
+            Data::FunctionPointer(f) => self.function(f),
+        }
+    }
+
+    fn expr(&mut self, e: &Expr, env: &Env) -> Type {
+        match e {
+            Expr::Constant(d) => self.data(d),
+            Expr::Identifier(n) => self.variable(n, env),
+            Expr::Call(f, args) => self.call(f, args, env),
+            Expr::ListBuild(h, t) => {
+                let h = self.expr(h, env);
+                let t = self.expr(t, env);
+                let list = Type::List(Box::new(h));
+                self.unify(&list, &t);
+                return list;
+            }
+            Expr::NumericExpr(n) => {
+                self.numeric(n, env);
+                return Type::Number;
+            }
+            Expr::LogicExpr(l) => {
+                self.logic(l, env);
+                return Type::Boolean;
+            }
+            Expr::Assign { pattern, arg, rest } => {
+                let at = self.expr(arg, env);
+                let mut inner = env.clone();
+                let pt = self.pattern(pattern, &mut inner);
+                self.unify(&pt, &at);
+                return self.expr(rest, &inner);
+            }
+            // what was raised can have any type, so the variables
+            // of a catch pattern get one of their own
+            Expr::Try { body, catches } => {
+                let t = self.expr(body, env);
+                for c in catches.iter() {
+                    let mut inner = env.clone();
+                    let class = self.pattern(&c.class, &mut inner);
+                    self.unify(&Type::Atom, &class);
+                    let mut names = Vec::new();
+                    pattern_match::bound_names(&c.pattern, &mut names);
+                    for n in names {
+                        let fresh = self.fresh();
+                        inner.insert(n, fresh);
+                    }
+                    let ct = self.expr(&c.code, &inner);
+                    self.unify(&t, &ct);
+                }
+                return t;
+            }
+            // every pattern takes the type of arg, every clause gives the type of the case
+            Expr::Case { arg, clauses } => {
+                let at = self.expr(arg, env);
+                let t = self.fresh();
+                for c in clauses.iter() {
+                    let mut inner = env.clone();
+                    let pt = self.pattern(&c.pattern, &mut inner);
+                    self.unify(&at, &pt);
+                    for filter in c.filters.iter() {
+                        self.logic(&filter.head, &inner);
+                        let ct = self.expr(&filter.code, &inner);
+                        self.unify(&t, &ct);
+                    }
+                }
+                return t;
+            }
+            Expr::If(branches) => {
+                let t = self.fresh();
+                for filter in branches.iter() {
+                    self.logic(&filter.head, env);
+                    let ct = self.expr(&filter.code, env);
+                    self.unify(&t, &ct);
+                }
+                return t;
+            }
+        }
+    }
+
+    fn logic(&mut self, e: &LogicExpr, env: &Env) {
+        Operands {
+            infer: self,
+            env: env,
+        }
+        .logic(e);
+    }
+
+    fn numeric(&mut self, e: &NumericExpr, env: &Env) {
+        Operands {
+            infer: self,
+            env: env,
+        }
+        .numeric(e);
+    }
+
+    // a name in expression position, true and 5 reach here from the logic
+    // and numeric lowering, a function named as a value is a FunctionPointer constant
+    fn variable(&mut self, n: &[char], env: &Env) -> Type {
+        if let Some(t) = env.get(n) {
+            return t.clone();
+        }
+        let text: String = n.iter().collect();
+        if text.parse::<bool>().is_ok() {
+            return Type::Boolean;
+        } else if text.parse::<i64>().is_ok() {
+            return Type::Number;
+        }
+        // unbound, check reports it
+        return self.fresh();
+    }
+
+    fn call(&mut self, f: &FunctionName, args: &[Expr], env: &Env) -> Type {
+        let arg_types: Vec<Type> = args.iter().map(|a| self.expr(a, env)).collect();
+        let callee = match f {
+            FunctionName::Static(_) => self.function(f),
+            FunctionName::Dynamic(n) => self.variable(n, env),
+            // rust::log takes anything and gives []
+            FunctionName::Rust(_) => return Type::List(Box::new(self.fresh())),
+            // throw and error never return, so they fit wherever they are
+            FunctionName::Builtin(n) if function::raises(n) => return self.fresh(),
+            // the is_ type tests take anything
+            FunctionName::Builtin(_) => return Type::Boolean,
+        };
+        let ret = self.fresh();
+        self.unify(&callee, &Type::Fun(arg_types, Box::new(ret.clone())));
+        return ret;
+    }
+
+    fn function(&mut self, f: &FunctionName) -> Type {
+        if let Some(t) = self.group.get(f) {
+            return t.clone();
+        }
+        match self.schemes.get(f).cloned() {
+            Some(s) => return self.instantiate(&s),
+            // undefined, check reports it
+            None => return self.fresh(),
+        }
+    }
+
+    fn instantiate(&mut self, s: &Scheme) -> Type {
+        let mapping: HashMap<usize, Type> = s.vars.iter().map(|v| (*v, self.fresh())).collect();
+        return rename(&s.ty, &mapping);
+    }
+
+    fn resolve(&self, t: &Type) -> Type {
+        match t {
+            Type::Var(v) => match &self.subst[*v] {
+                Some(x) => self.resolve(x),
+                None => t.clone(),
+            },
+            Type::List(x) => Type::List(Box::new(self.resolve(x))),
+            Type::Fun(args, ret) => Type::Fun(
+                args.iter().map(|a| self.resolve(a)).collect(),
+                Box::new(self.resolve(ret)),
+            ),
+            _ => t.clone(),
+        }
+    }
+
+    fn unify(&mut self, expected: &Type, found: &Type) {
+        if !self.unify_inner(expected, found) {
+            self.errors.push(TypeError {
+                function: self.current.0.clone(),
+                clause: self.current.1,
+                expected: self.resolve(expected),
+                found: self.resolve(found),
+            });
+        }
+    }
+
+    fn unify_inner(&mut self, a: &Type, b: &Type) -> bool {
+        let a = self.shallow(a);
+        let b = self.shallow(b);
+        match (&a, &b) {
+            (Type::Var(x), Type::Var(y)) if x == y => true,
+            (Type::Var(x), t) | (t, Type::Var(x)) => {
+                if self.occurs(*x, t) {
+                    return false;
+                }
+                self.subst[*x] = Some(t.clone());
+                return true;
+            }
+            (Type::Number, Type::Number)
+            | (Type::Boolean, Type::Boolean)
+            | (Type::Atom, Type::Atom) => true,
+            (Type::Named(x), Type::Named(y)) => x == y,
+            (Type::List(x), Type::List(y)) => self.unify_inner(x, y),
+            (Type::Fun(xa, xr), Type::Fun(ya, yr)) => {
+                xa.len() == ya.len()
+                    && xa
+                        .iter()
+                        .zip(ya.iter())
+                        .all(|(x, y)| self.unify_inner(x, y))
+                    && self.unify_inner(xr, yr)
+            }
+            _ => false,
+        }
+    }
+
+    // follows bound variables until a constructor or a free variable
+    fn shallow(&self, t: &Type) -> Type {
+        match t {
+            Type::Var(v) => match &self.subst[*v] {
+                Some(x) => self.shallow(x),
+                None => t.clone(),
+            },
+            _ => t.clone(),
+        }
+    }
+
+    fn occurs(&self, v: usize, t: &Type) -> bool {
+        match self.shallow(t) {
+            Type::Var(x) => x == v,
+            Type::List(x) => self.occurs(v, &x),
+            Type::Fun(args, ret) => args.iter().any(|a| self.occurs(v, a)) || self.occurs(v, &ret),
+            _ => false,
+        }
+    }
+}
+
+// the operands of logic are booleans and those of arithmetic numbers,
+// what they are compared or called with is typed by Infer::expr
+struct Operands<'a> {
+    infer: &'a mut Infer,
+    env: &'a Env,
+}
+
+impl<'a> Visitor for Operands<'a> {
+    fn expr(&mut self, e: &Expr) {
+        self.infer.expr(e, self.env);
+    }
+
+    fn logic(&mut self, e: &LogicExpr) {
+        match e {
+            LogicExpr::Identifier(n) => {
+                let t = self.infer.variable(n, self.env);
+                self.infer.unify(&Type::Boolean, &t);
+            }
+            LogicExpr::EQ(v) => {
+                let types: Vec<Type> = v.iter().map(|x| self.infer.expr(x, self.env)).collect();
+                for t in types.iter().skip(1) {
+                    self.infer.unify(&types[0], t);
+                }
+            }
+            LogicExpr::NEQ(l, r)
+            | LogicExpr::Compare {
+                left: l, right: r, ..
+            } => {
+                let l = self.infer.expr(l, self.env);
+                let r = self.infer.expr(r, self.env);
+                self.infer.unify(&l, &r);
+            }
+            LogicExpr::Call(f, args) => {
+                let t = self.infer.call(f, args, self.env);
+                self.infer.unify(&Type::Boolean, &t);
+            }
+            _ => visit::walk_logic(self, e),
+        }
+    }
+
+    fn numeric(&mut self, e: &NumericExpr) {
+        match e {
+            NumericExpr::Identifier(n) => {
+                let t = self.infer.variable(n, self.env);
+                self.infer.unify(&Type::Number, &t);
+            }
+            NumericExpr::Call(f, args) => {
+                let t = self.infer.call(f, args, self.env);
+                self.infer.unify(&Type::Number, &t);
+            }
+            _ => visit::walk_numeric(self, e),
+        }
+    }
+}
+
+fn rename(t: &Type, mapping: &HashMap<usize, Type>) -> Type {
+    match t {
+        Type::Var(v) => mapping.get(v).cloned().unwrap_or(t.clone()),
+        Type::List(x) => Type::List(Box::new(rename(x, mapping))),
+        Type::Fun(args, ret) => Type::Fun(
+            args.iter().map(|a| rename(a, mapping)).collect(),
+            Box::new(rename(ret, mapping)),
+        ),
+        _ => t.clone(),
+    }
+}
+
+// spec variables become ordinary ones once their function is done
+fn unname(t: &Type, names: &mut HashMap<Vec<char>, Type>, state: &mut Infer) -> Type {
+    match t {
+        Type::Named(n) => match names.get(n) {
+            Some(v) => v.clone(),
+            None => {
+                let v = state.fresh();
+                names.insert(n.clone(), v.clone());
+                return v;
+            }
+        },
+        Type::List(x) => Type::List(Box::new(unname(x, names, state))),
+        Type::Fun(args, ret) => Type::Fun(
+            args.iter().map(|a| unname(a, names, state)).collect(),
+            Box::new(unname(ret, names, state)),
+        ),
+        _ => t.clone(),
+    }
+}
+
+fn free_vars(t: &Type, acc: &mut Vec<usize>) {
+    match t {
+        Type::Var(v) => {
+            if !acc.contains(v) {
+                acc.push(*v);
+            }
+        }
+        Type::List(x) => free_vars(x, acc),
+        Type::Fun(args, ret) => {
+            args.iter().for_each(|a| free_vars(a, acc));
+            free_vars(ret, acc);
+        }
+        _ => {}
+    }
+}
+
+// -spec name(T, ..) -> T.
+// given the tokens between -spec and the ending dot
+//
+// T is number, boolean, [T], fun((T, ..) -> T)
+// or a variable, a name starting with an upper case letter
+pub fn parse_spec(t: &[Token]) -> (Vec<char>, Type) {
+    match t {
+        [Token::Identifier(name), Token::LeftP, rest @ ..] => {
+            let (args, rest) = spec_args(rest);
+            match rest {
+                [Token::Arrow, rest @ ..] => {
+                    let (ret, rest) = spec_type(rest);
+                    if rest.is_empty() {
+                        return (name.clone(), Type::Fun(args, Box::new(ret)));
+                    }
+                }
+                _ => {}
+            }
+        }
+        _ => {}
+    }
+    println!("BAD SPEC{:#?}\n", t);
+    unimplemented!()
+}
+
+// types up to and including the closing )
+fn spec_args(t: &[Token]) -> (Vec<Type>, &[Token]) {
+    let mut args = Vec::new();
+    let mut rest = t;
+    if let [Token::RightP, after @ ..] = rest {
+        return (args, after);
+    }
+    loop {
+        let (arg, after) = spec_type(rest);
+        args.push(arg);
+        match after {
+            [Token::ArgTerm, after @ ..] => rest = after,
+            [Token::RightP, after @ ..] => return (args, after),
+            _ => {
+                println!("BAD SPEC{:#?}\n", t);
+                unimplemented!()
+            }
+        }
+    }
+}
+
+fn spec_type(t: &[Token]) -> (Type, &[Token]) {
+    match t {
+        [Token::LeftB, rest @ ..] => {
+            let (x, rest) = spec_type(rest);
+            match rest {
+                [Token::RightB, rest @ ..] => return (Type::List(Box::new(x)), rest),
+                _ => {}
+            }
+        }
+        [Token::Identifier(f), Token::LeftP, Token::LeftP, rest @ ..] if *f == ['f', 'u', 'n'] => {
+            let (args, rest) = spec_args(rest);
+            if let [Token::Arrow, rest @ ..] = rest {
+                let (ret, rest) = spec_type(rest);
+                if let [Token::RightP, rest @ ..] = rest {
+                    return (Type::Fun(args, Box::new(ret)), rest);
+                }
+            }
+        }
+        [Token::Identifier(n), rest @ ..] => {
+            let text: String = n.iter().collect();
+            match text.as_str() {
+                "number" => return (Type::Number, rest),
+                "boolean" => return (Type::Boolean, rest),
+                "atom" => return (Type::Atom, rest),
+                _ if n[0].is_uppercase() => return (Type::Named(n.clone()), rest),
+                _ => {}
+            }
+        }
+        _ => {}
+    }
+    println!("BAD SPEC TYPE{:#?}\n", t);
+    unimplemented!()
+}
+
+// inverse of parse_spec, with the leading -spec and the ending dot
+pub fn format_spec(name: &[char], t: &Type) -> String {
+    match t {
+        Type::Fun(args, ret) => format!(
+            "-spec {}({}) -> {}.",
+            name.iter().collect::<String>(),
+            args.iter()
+                .map(spec_type_text)
+                .collect::<Vec<String>>()
+                .join(", "),
+            spec_type_text(ret)
+        ),
+        _ => unimplemented!(),
+    }
+}
+
+fn spec_type_text(t: &Type) -> String {
+    match t {
+        Type::Number => "number".to_string(),
+        Type::Boolean => "boolean".to_string(),
+        Type::Atom => "atom".to_string(),
+        Type::List(x) => format!("[{}]", spec_type_text(x)),
+        Type::Fun(args, ret) => format!(
+            "fun(({}) -> {})",
+            args.iter()
+                .map(spec_type_text)
+                .collect::<Vec<String>>()
+                .join(", "),
+            spec_type_text(ret)
+        ),
+        Type::Named(n) => n.iter().collect(),
+        Type::Var(v) => format!("T{}", v),
+    }
+}
+
+// runtime check of a value against a spec type, variables take any value
+pub fn data_has_type(d: &Data, t: &Type) -> bool {
+    match (d, t) {
+        (_, Type::Named(_)) | (_, Type::Var(_)) => true,
+        (Data::Number(_), Type::Number) => true,
+        (Data::Boolean(_), Type::Boolean) => true,
+        (Data::Atom(_), Type::Atom) => true,
+        (Data::Emptylist, Type::List(_)) => true,
+        (Data::List(h, tail), Type::List(x)) => data_has_type(h, x) && data_has_type(tail, t),
+        (Data::FunctionPointer(_), Type::Fun(_, _)) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{format, numeric_expr::NumericData, program, tokens};
+    use std::{fs, sync::Arc};
+
+    fn errors(src: &str) -> Vec<String> {
+        let (_, errors) = infer_program(&program::from_source(src));
+        return errors.iter().map(|e| e.to_string()).collect();
+    }
+
+    #[test]
+    fn program_txt_has_no_type_errors() {
+        let src = fs::read_to_string("program.txt").expect("Cant read file");
+        assert_eq!(errors(&src), Vec::<String>::new());
+    }
+
+    #[test]
+    fn function_named_as_a_value_has_its_type() {
+        let src = "pos:\n    (X)\n    / X > 0 \\\nend\n\
+                   apply:\n    (F, X)\n    / F(X) \\\nend\n\
+                   main:\n    ()\n    / apply(pos, 1) + 1 \\\nend\n";
+        assert_eq!(
+            errors(src),
+            vec!["main clause 1: expected number, found boolean"]
+        );
+    }
+
+    #[test]
+    fn a_raise_has_the_type_of_the_other_branches() {
+        let src = "inc:\n    (X)\n    / case X of 0 / throw(zero) \\ _ / X + 1 \\ end \\\nend\n";
+        let (schemes, errors) = infer_program(&program::from_source(src));
+        assert!(errors.is_empty(), "{:?}", errors);
+        let inc: Vec<String> = schemes.iter().map(|(_, s)| s.to_string()).collect();
+        assert_eq!(inc, vec!["number -> number"]);
+    }
+
+    fn spec(text: &str) -> (Vec<char>, Type) {
+        return parse_spec(&tokens::string_to(text.to_string()));
+    }
+
+    #[test]
+    fn specs_print_as_they_are_written() {
+        let text = "map([A], fun((A) -> B), [B]) -> [B]";
+        let (name, t) = spec(text);
+        assert_eq!(format_spec(&name, &t), format!("-spec {}.", text));
+    }
+
+    #[test]
+    fn a_spec_variable_may_not_be_narrowed() {
+        let src = "-spec inc(A) -> number.\ninc:\n    (X)\n    / X + 1 \\\nend\n";
+        assert_eq!(errors(src), vec!["inc clause 1: expected number, found A"]);
+    }
+
+    #[test]
+    fn callers_see_the_spec_as_the_type() {
+        let src = "-spec pick(number) -> number.\npick:\n    (X)\n    / X \\\nend\n\
+                   main:\n    ()\n    / pick(true) \\\nend\n";
+        assert_eq!(
+            errors(src),
+            vec!["main clause 1: expected number -> number, found boolean -> a"]
+        );
+    }
+
+    #[test]
+    fn values_are_checked_against_spec_types() {
+        let (_, t) = spec("f([number], fun(() -> A)) -> Any");
+        let Type::Fun(args, ret) = t else {
+            panic!("not a function type")
+        };
+        let one = Data::Number(NumericData::Int(1));
+        let list = |h: Data, t: Data| Data::List(Arc::new(h), Arc::new(t));
+        assert!(data_has_type(&Data::Emptylist, &args[0]));
+        assert!(data_has_type(&list(one.clone(), Data::Emptylist), &args[0]));
+        assert!(!data_has_type(
+            &list(Data::Boolean(true), Data::Emptylist),
+            &args[0]
+        ));
+        assert!(!data_has_type(&one, &args[0]));
+        assert!(data_has_type(
+            &Data::FunctionPointer(FunctionName::Static(vec!['f'])),
+            &args[1]
+        ));
+        assert!(!data_has_type(&one, &args[1]));
+        assert!(data_has_type(&Data::Atom(vec!['o', 'k']), &ret));
+    }
+
+    #[test]
+    fn calls_against_the_spec_raise_at_runtime() {
+        let src = "-spec inc(number) -> number.\ninc:\n    (X)\n    / X \\\nend\n\
+                   main:\n    ()\n    / [inc(1), inc(ok)] \\\nend\n";
+        let raised = eval::run_main(&program::from_source(src)).map_err(|r| r.term);
+        assert_eq!(
+            raised.map_err(|t| format::format_data(&t)),
+            Err("[spec, inc, ok]".to_string())
+        );
+    }
+}
@@ -0,0 +1,254 @@
+use crate::{
+    eval::Data,
+    expr::{Catch, Clause, Expr},
+    function::FunctionName,
+    logic_expr::LogicExpr,
+    numeric_expr::NumericExpr,
+    program,
+};
+
+// One walk over Expr, LogicExpr and NumericExpr for every pass over the code
+//
+// A Visitor looks at the code, a Rewriter gives back new code. Both override
+// the nodes they care about and hand the rest to walk_* or rewrite_*, which go
+// one level down and call back into the visitor for every child.
+//
+// Patterns are not walked, a pass that cares about what they bind overrides
+// the Assign, Try and Case nodes of expr and walks their parts itself.
+
+pub trait Visitor {
+    fn expr(&mut self, e: &Expr) {
+        walk_expr(self, e);
+    }
+
+    fn logic(&mut self, e: &LogicExpr) {
+        walk_logic(self, e);
+    }
+
+    fn numeric(&mut self, e: &NumericExpr) {
+        walk_numeric(self, e);
+    }
+
+    // a variable read in any of the three, true and 5 can reach here from
+    // the logic and numeric lowering
+    fn variable(&mut self, _name: &[char]) {}
+
+    fn constant(&mut self, _d: &Data) {}
+
+    // a call in any of the three, the arguments are walked by default
+    fn call(&mut self, _f: &FunctionName, args: &[Expr]) {
+        args.iter().for_each(|a| self.expr(a));
+    }
+}
+
+pub fn walk_expr<V: Visitor + ?Sized>(v: &mut V, e: &Expr) {
+    match e {
+        Expr::Constant(d) => v.constant(d),
+        Expr::Identifier(i) => v.variable(i),
+        Expr::Call(f, args) => v.call(f, args),
+        Expr::ListBuild(h, t) => {
+            v.expr(h);
+            v.expr(t);
+        }
+        Expr::NumericExpr(n) => v.numeric(n),
+        Expr::LogicExpr(l) => v.logic(l),
+        Expr::Assign { arg, rest, .. } => {
+            v.expr(arg);
+            v.expr(rest);
+        }
+        Expr::Try { body, catches } => {
+            v.expr(body);
+            catches.iter().for_each(|c| v.expr(&c.code));
+        }
+        Expr::Case { arg, clauses } => {
+            v.expr(arg);
+            clauses.iter().for_each(|c| walk_filters(v, &c.filters));
+        }
+        Expr::If(branches) => walk_filters(v, branches),
+    }
+}
+
+pub fn walk_logic<V: Visitor + ?Sized>(v: &mut V, e: &LogicExpr) {
+    match e {
+        LogicExpr::True | LogicExpr::False => {}
+        LogicExpr::Identifier(i) => v.variable(i),
+        LogicExpr::AND(x)
+        | LogicExpr::OR(x)
+        | LogicExpr::ANDALSO(x)
+        | LogicExpr::ORELSE(x)
+        | LogicExpr::XOR(x) => x.iter().for_each(|l| v.logic(l)),
+        LogicExpr::NOT(x) => v.logic(x),
+        LogicExpr::EQ(x) => x.iter().for_each(|e| v.expr(e)),
+        LogicExpr::NEQ(l, r)
+        | LogicExpr::Compare {
+            left: l, right: r, ..
+        } => {
+            v.expr(l);
+            v.expr(r);
+        }
+        LogicExpr::Call(f, args) => v.call(f, args),
+    }
+}
+
+pub fn walk_numeric<V: Visitor + ?Sized>(v: &mut V, e: &NumericExpr) {
+    match e {
+        NumericExpr::Const(_) => {}
+        NumericExpr::Identifier(i) => v.variable(i),
+        NumericExpr::Negate(x) => v.numeric(x),
+        NumericExpr::ArrayOperator { v: x, .. } => x.iter().for_each(|n| v.numeric(n)),
+        NumericExpr::OrderedOperator { left, right, .. } => {
+            v.numeric(left);
+            v.numeric(right);
+        }
+        NumericExpr::Call(f, args) => v.call(f, args),
+    }
+}
+
+// the guards and bodies of a bind, a case clause or an if
+pub fn walk_filters<V: Visitor + ?Sized>(v: &mut V, filters: &[program::Filter]) {
+    for f in filters.iter() {
+        v.logic(&f.head);
+        v.expr(&f.code);
+    }
+}
+
+pub fn walk_fun<V: Visitor + ?Sized>(v: &mut V, f: &program::Fun) {
+    f.binds.iter().for_each(|b| walk_filters(v, &b.filters));
+}
+
+pub trait Rewriter {
+    fn expr(&mut self, e: Expr) -> Expr {
+        return rewrite_expr(self, e);
+    }
+
+    fn logic(&mut self, e: LogicExpr) -> LogicExpr {
+        return rewrite_logic(self, e);
+    }
+
+    fn numeric(&mut self, e: NumericExpr) -> NumericExpr {
+        return rewrite_numeric(self, e);
+    }
+
+    // the filters of a bind, a case clause or an if
+    fn filters(&mut self, v: Vec<program::Filter>) -> Vec<program::Filter> {
+        return rewrite_filters(self, v);
+    }
+}
+
+pub fn rewrite_expr<R: Rewriter + ?Sized>(r: &mut R, e: Expr) -> Expr {
+    match e {
+        Expr::Constant(_) | Expr::Identifier(_) => e,
+        Expr::Call(f, args) => Expr::Call(f, rewrite_all(r, args)),
+        Expr::ListBuild(h, t) => Expr::ListBuild(Box::new(r.expr(*h)), Box::new(r.expr(*t))),
+        Expr::NumericExpr(n) => Expr::NumericExpr(r.numeric(n)),
+        Expr::LogicExpr(l) => Expr::LogicExpr(r.logic(l)),
+        Expr::Assign { pattern, arg, rest } => Expr::Assign {
+            pattern: pattern,
+            arg: Box::new(r.expr(*arg)),
+            rest: Box::new(r.expr(*rest)),
+        },
+        Expr::Try { body, catches } => Expr::Try {
+            body: Box::new(r.expr(*body)),
+            catches: catches
+                .into_iter()
+                .map(|c| Catch {
+                    code: r.expr(c.code),
+                    ..c
+                })
+                .collect(),
+        },
+        Expr::Case { arg, clauses } => Expr::Case {
+            arg: Box::new(r.expr(*arg)),
+            clauses: clauses
+                .into_iter()
+                .map(|c| Clause {
+                    filters: r.filters(c.filters),
+                    ..c
+                })
+                .collect(),
+        },
+        Expr::If(branches) => Expr::If(r.filters(branches)),
+    }
+}
+
+pub fn rewrite_logic<R: Rewriter + ?Sized>(r: &mut R, e: LogicExpr) -> LogicExpr {
+    let mut all =
+        |v: Vec<LogicExpr>| -> Vec<LogicExpr> { v.into_iter().map(|x| r.logic(x)).collect() };
+    match e {
+        LogicExpr::True | LogicExpr::False | LogicExpr::Identifier(_) => e,
+        LogicExpr::AND(v) => LogicExpr::AND(all(v)),
+        LogicExpr::OR(v) => LogicExpr::OR(all(v)),
+        LogicExpr::ANDALSO(v) => LogicExpr::ANDALSO(all(v)),
+        LogicExpr::ORELSE(v) => LogicExpr::ORELSE(all(v)),
+        LogicExpr::XOR(v) => LogicExpr::XOR(all(v)),
+        LogicExpr::NOT(x) => LogicExpr::NOT(Box::new(r.logic(*x))),
+        LogicExpr::EQ(v) => LogicExpr::EQ(rewrite_all(r, v)),
+        LogicExpr::NEQ(left, right) => {
+            LogicExpr::NEQ(Box::new(r.expr(*left)), Box::new(r.expr(*right)))
+        }
+        LogicExpr::Compare { op, left, right } => LogicExpr::Compare {
+            op: op,
+            left: Box::new(r.expr(*left)),
+            right: Box::new(r.expr(*right)),
+        },
+        LogicExpr::Call(f, args) => LogicExpr::Call(f, rewrite_all(r, args)),
+    }
+}
+
+pub fn rewrite_numeric<R: Rewriter + ?Sized>(r: &mut R, e: NumericExpr) -> NumericExpr {
+    match e {
+        NumericExpr::Const(_) | NumericExpr::Identifier(_) => e,
+        NumericExpr::Negate(x) => NumericExpr::Negate(Box::new(r.numeric(*x))),
+        NumericExpr::ArrayOperator { op, v } => NumericExpr::ArrayOperator {
+            op: op,
+            v: v.into_iter().map(|x| r.numeric(x)).collect(),
+        },
+        NumericExpr::OrderedOperator { op, left, right } => NumericExpr::OrderedOperator {
+            op: op,
+            left: Box::new(r.numeric(*left)),
+            right: Box::new(r.numeric(*right)),
+        },
+        NumericExpr::Call(f, args) => NumericExpr::Call(f, rewrite_all(r, args)),
+    }
+}
+
+pub fn rewrite_all<R: Rewriter + ?Sized>(r: &mut R, v: Vec<Expr>) -> Vec<Expr> {
+    return v.into_iter().map(|x| r.expr(x)).collect();
+}
+
+pub fn rewrite_filters<R: Rewriter + ?Sized>(
+    r: &mut R,
+    v: Vec<program::Filter>,
+) -> Vec<program::Filter> {
+    return v
+        .into_iter()
+        .map(|f| program::Filter {
+            head: r.logic(f.head),
+            code: r.expr(f.code),
+        })
+        .collect();
+}
+
+pub fn rewrite_fun<R: Rewriter + ?Sized>(r: &mut R, f: program::Fun) -> program::Fun {
+    return program::Fun {
+        binds: f
+            .binds
+            .into_iter()
+            .map(|b| program::Bind {
+                filters: r.filters(b.filters),
+                ..b
+            })
+            .collect(),
+        ..f
+    };
+}
+
+// every function called, in the order the calls are written
+pub struct Calls<'a>(pub &'a mut Vec<FunctionName>);
+
+impl<'a> Visitor for Calls<'a> {
+    fn call(&mut self, f: &FunctionName, args: &[Expr]) {
+        self.0.push(f.clone());
+        args.iter().for_each(|a| self.expr(a));
+    }
+}
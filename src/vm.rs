@@ -0,0 +1,972 @@
+use std::{
+    cmp::Ordering,
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use itertools::Itertools;
+
+use crate::{
+    bytecode::{Compiled, CompiledFun, Op},
+    capability::{self, Capabilities},
+    eval::{self, Class, Data},
+    format,
+    function::{self, FunctionName},
+    logic_expr::CompareOp,
+    numeric_expr::NumericData,
+    types,
+};
+
+// Runs the bytecode from bytecode.rs
+//
+// One operand stack is shared by every frame, a frame remembers where its
+// part of the stack starts. The tree walker in eval.rs is kept as the
+// reference: run --verify runs both and compares the results.
+//
+// A runtime error stops the program with a RuntimeError carrying the calls
+// that led to it, printed like an Erlang stack trace:
+//
+//     ** exception error: no function clause matching f(1, [])
+//          in function  f/2 (line 3)
+//          in call from main/0, clause 1 (line 12)
+//
+// A tail call replaces the frame of its caller, so the caller is not listed.
+//
+// Inside a try the error is caught instead: the frames and the stack go back
+// to where the try started and its catch clauses get the class and the term
+// of the error, see RuntimeErrorKind::term for the shapes.
+//
+// Scripts we did not write run with Limits, the first one exceeded stops the
+// program with LimitExceeded instead of looping forever or running out of memory.
+// A call that recurses keeps its frame on the heap, not on the stack of the host.
+
+// None is no limit
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    // calls of any kind, a tail call too
+    pub reductions: Option<u64>,
+    // frames waiting for a result, tail calls reuse theirs
+    pub depth: Option<usize>,
+    // list cells built by the program
+    pub heap: Option<u64>,
+    pub time: Option<Duration>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Limit {
+    Reductions(u64),
+    Depth(usize),
+    Heap(u64),
+    Time(Duration),
+}
+
+#[derive(Debug, Clone)]
+pub struct LimitExceeded {
+    pub limit: Limit,
+    // the function running when it happened
+    pub function: FunctionName,
+}
+
+// why the vm stopped before the program finished
+#[derive(Debug, Clone)]
+pub enum RunError {
+    LimitExceeded(LimitExceeded),
+    Denied(capability::Denied),
+    Runtime(RuntimeError),
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunError::LimitExceeded(e) => write!(f, "limit exceeded in {}", e),
+            RunError::Denied(d) => write!(f, "denied at line {}", d),
+            RunError::Runtime(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    // innermost call first
+    pub stack: Vec<StackEntry>,
+}
+
+#[derive(Debug, Clone)]
+pub enum RuntimeErrorKind {
+    // no bind takes the arguments
+    FunctionClause(FunctionName, Vec<Data>),
+    // the value an assignment could not match
+    BadMatch(Data),
+    // the value no clause of a case takes
+    CaseClause(Data),
+    // no branch of an if has a guard that holds
+    IfClause,
+    Unbound(Vec<char>),
+    Undefined(FunctionName, usize),
+    // not a number, or numbers that overflow or divide by zero
+    BadArith(Data),
+    BadBoolean(Data),
+    // a call through a variable that does not hold a function
+    BadFun(Data),
+    BadArity(FunctionName, usize),
+    // a value that does not have the type its -spec says
+    Spec(FunctionName, Data, types::Type),
+    // throw(Term), error(Term) or a catch that took none of its clauses
+    Raised(Class, Data),
+}
+
+#[derive(Debug, Clone)]
+pub struct StackEntry {
+    pub function: FunctionName,
+    pub arity: usize,
+    // the bind that matched, None while the head is being matched
+    pub clause: Option<usize>,
+    pub line: usize,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "** exception {}: {}", self.kind.class(), self.kind)?;
+        for (i, e) in self.stack.iter().enumerate() {
+            let place = if i == 0 {
+                "in function "
+            } else {
+                "in call from"
+            };
+            write!(f, "\n     {} {}/{}", place, e.function, e.arity)?;
+            if let Some(c) = e.clause {
+                write!(f, ", clause {}", c + 1)?;
+            }
+            write!(f, " (line {})", e.line)?;
+        }
+        return Ok(());
+    }
+}
+
+impl fmt::Display for RuntimeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuntimeErrorKind::FunctionClause(name, args) => write!(
+                f,
+                "no function clause matching {}({})",
+                name,
+                args.iter().map(format::format_data).join(", ")
+            ),
+            RuntimeErrorKind::BadMatch(d) => write!(
+                f,
+                "no match of right hand side value {}",
+                format::format_data(d)
+            ),
+            RuntimeErrorKind::CaseClause(d) => {
+                write!(f, "no case clause matching {}", format::format_data(d))
+            }
+            RuntimeErrorKind::IfClause => {
+                write!(f, "no true branch found when evaluating an if expression")
+            }
+            RuntimeErrorKind::Unbound(n) => {
+                write!(f, "variable {} is unbound", n.iter().collect::<String>())
+            }
+            RuntimeErrorKind::Undefined(name, arity) => {
+                write!(f, "undefined function {}/{}", name, arity)
+            }
+            RuntimeErrorKind::BadArith(d) => write!(
+                f,
+                "an error occurred when evaluating an arithmetic expression with {}",
+                format::format_data(d)
+            ),
+            RuntimeErrorKind::BadBoolean(d) => {
+                write!(f, "{} is not a boolean", format::format_data(d))
+            }
+            RuntimeErrorKind::BadFun(d) => {
+                write!(f, "bad function {}", format::format_data(d))
+            }
+            RuntimeErrorKind::BadArity(name, n) => {
+                write!(f, "{} called with {} arguments", name, n)
+            }
+            RuntimeErrorKind::Spec(name, d, t) => write!(
+                f,
+                "{} does not have type {} from the spec of {}",
+                format::format_data(d),
+                types::Scheme {
+                    vars: Vec::new(),
+                    ty: t.clone(),
+                },
+                name
+            ),
+            RuntimeErrorKind::Raised(_, d) => write!(f, "{}", format::format_data(d)),
+        }
+    }
+}
+
+impl RuntimeErrorKind {
+    pub fn class(&self) -> Class {
+        match self {
+            RuntimeErrorKind::Raised(class, _) => *class,
+            _ => Class::Error,
+        }
+    }
+
+    // what a catch clause matches, a list with the reason first:
+    //     [function_clause, f, [Args..]]  [badmatch, Value]  [case_clause, Value]
+    //     [if_clause]  [unbound, Name]  [undef, f, Arity]  [badarith, Value]  [badbool, Value]
+    //     [badfun, Value]  [badarity, f, Count]  [spec, f, Value]
+    // functions and variables are given by their name as an atom,
+    // throw(Term) and error(Term) give Term as it is
+    pub fn term(&self) -> Data {
+        let atom = |s: &str| Data::Atom(s.chars().collect());
+        let name = |f: &FunctionName| Data::Atom(f.to_string().chars().collect());
+        let number = |n: usize| Data::Number(NumericData::Int(n as i64));
+        match self {
+            RuntimeErrorKind::FunctionClause(f, args) => {
+                list(vec![atom("function_clause"), name(f), list(args.clone())])
+            }
+            RuntimeErrorKind::BadMatch(d) => list(vec![atom("badmatch"), d.clone()]),
+            RuntimeErrorKind::CaseClause(d) => list(vec![atom("case_clause"), d.clone()]),
+            RuntimeErrorKind::IfClause => list(vec![atom("if_clause")]),
+            RuntimeErrorKind::Unbound(n) => list(vec![atom("unbound"), Data::Atom(n.clone())]),
+            RuntimeErrorKind::Undefined(f, arity) => {
+                list(vec![atom("undef"), name(f), number(*arity)])
+            }
+            RuntimeErrorKind::BadArith(d) => list(vec![atom("badarith"), d.clone()]),
+            RuntimeErrorKind::BadBoolean(d) => list(vec![atom("badbool"), d.clone()]),
+            RuntimeErrorKind::BadFun(d) => list(vec![atom("badfun"), d.clone()]),
+            RuntimeErrorKind::BadArity(f, n) => list(vec![atom("badarity"), name(f), number(*n)]),
+            RuntimeErrorKind::Spec(f, d, _) => list(vec![atom("spec"), name(f), d.clone()]),
+            RuntimeErrorKind::Raised(_, d) => d.clone(),
+        }
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.limit {
+            Limit::Reductions(n) => write!(f, "{}: more than {} reductions", self.function, n),
+            Limit::Depth(n) => write!(f, "{}: calls nested deeper than {}", self.function, n),
+            Limit::Heap(n) => write!(f, "{}: more than {} list cells", self.function, n),
+            Limit::Time(t) => write!(f, "{}: ran longer than {:?}", self.function, t),
+        }
+    }
+}
+
+// the clock is only read every so many instructions
+const CLOCK_EVERY: u64 = 1024;
+
+const DEFAULT_REDUCTIONS: u64 = 10_000_000;
+const DEFAULT_TIME: Duration = Duration::from_secs(10);
+
+pub struct Usage {
+    pub steps: u64,
+    pub reductions: u64,
+    pub heap: u64,
+    pub deadline: Option<Instant>,
+}
+
+impl Usage {
+    // the clock starts now
+    pub fn start(limits: &Limits) -> Usage {
+        return Usage {
+            steps: 0,
+            reductions: 0,
+            heap: 0,
+            deadline: limits.time.map(|t| Instant::now() + t),
+        };
+    }
+}
+
+impl Limits {
+    // what run uses when it is not given a limit
+    pub fn defaults() -> Limits {
+        return Limits {
+            reductions: Some(DEFAULT_REDUCTIONS),
+            depth: None,
+            heap: None,
+            time: Some(DEFAULT_TIME),
+        };
+    }
+
+    pub fn exceeded(&self, usage: &Usage, depth: usize) -> Option<Limit> {
+        match self.reductions {
+            Some(n) if usage.reductions > n => return Some(Limit::Reductions(n)),
+            _ => {}
+        }
+        match self.depth {
+            Some(n) if depth > n => return Some(Limit::Depth(n)),
+            _ => {}
+        }
+        match self.heap {
+            Some(n) if usage.heap > n => return Some(Limit::Heap(n)),
+            _ => {}
+        }
+        match (self.time, usage.deadline) {
+            (Some(t), Some(d)) if usage.steps % CLOCK_EVERY == 0 && Instant::now() > d => {
+                return Some(Limit::Time(t))
+            }
+            _ => {}
+        }
+        return None;
+    }
+}
+
+struct Frame {
+    fun: usize,
+    pc: usize,
+    base: usize,
+    locals: Vec<Data>,
+}
+
+// a try that is running, what to go back to when its body fails
+struct Handler {
+    frames: usize,
+    stack: usize,
+    pc: usize,
+}
+
+pub fn call(
+    code: &Compiled,
+    name: &FunctionName,
+    args: Vec<Data>,
+    limits: &Limits,
+    caps: &Capabilities,
+) -> Result<Data, RunError> {
+    let fun = match code.index.get(name) {
+        Some(i) => *i,
+        None => {
+            return Err(runtime_error(
+                RuntimeErrorKind::Undefined(name.clone(), args.len()),
+                code,
+                &[],
+            ))
+        }
+    };
+    let mut stack: Vec<Data> = Vec::new();
+    let mut frames = match enter(code, fun, args, 0) {
+        Ok(frame) => vec![frame],
+        Err(kind) => return Err(runtime_error(kind, code, &[])),
+    };
+    let mut usage = Usage::start(limits);
+    let mut handlers: Vec<Handler> = Vec::new();
+    loop {
+        // every instruction that fails breaks out with what went wrong
+        let kind = loop {
+            let depth = frames.len();
+            let frame = frames.last_mut().unwrap();
+            let f = &code.funs[frame.fun];
+            let op = &f.code[frame.pc];
+            usage.steps += 1;
+            match op {
+                Op::Call(_, _)
+                | Op::TailCall(_, _)
+                | Op::CallDynamic(_, _)
+                | Op::CallHost(_, _) => usage.reductions += 1,
+                Op::Cons => usage.heap += 1,
+                _ => {}
+            }
+            if let Some(limit) = limits.exceeded(&usage, depth) {
+                return Err(RunError::LimitExceeded(LimitExceeded {
+                    limit: limit,
+                    function: f.name.clone(),
+                }));
+            }
+            let line = f.line_at(frame.pc);
+            frame.pc += 1;
+            // a host call the script was not granted
+            let denied = |host: &FunctionName| {
+                return RunError::Denied(capability::Denied {
+                    function: host.clone(),
+                    caller: f.name.to_string().chars().collect(),
+                    line: line,
+                });
+            };
+            match op {
+                Op::Const(d) => stack.push(d.clone()),
+                Op::Load(slot) => stack.push(frame.locals[*slot].clone()),
+                Op::Store(slot) => frame.locals[*slot] = stack.pop().unwrap(),
+                Op::Reset => stack.truncate(frame.base),
+                Op::MatchEq(d, fail) => {
+                    if stack.pop().unwrap() != *d {
+                        frame.pc = *fail;
+                    }
+                }
+                Op::MatchBound(slot, fail) => {
+                    if stack.pop().unwrap() != frame.locals[*slot] {
+                        frame.pc = *fail;
+                    }
+                }
+                Op::MatchCons(fail) => match stack.pop().unwrap() {
+                    Data::List(h, t) => {
+                        stack.push(Arc::unwrap_or_clone(t));
+                        stack.push(Arc::unwrap_or_clone(h));
+                    }
+                    _ => frame.pc = *fail,
+                },
+                Op::Switch(consts, cons, default) => match (stack.pop().unwrap(), cons) {
+                    (Data::List(h, t), Some(target)) => {
+                        stack.push(Arc::unwrap_or_clone(t));
+                        stack.push(Arc::unwrap_or_clone(h));
+                        frame.pc = *target;
+                    }
+                    (v, _) => {
+                        frame.pc = match consts.iter().find(|(d, _)| *d == v) {
+                            Some((_, target)) => *target,
+                            None => *default,
+                        }
+                    }
+                },
+                Op::Jump(target) => frame.pc = *target,
+                Op::JumpIfFalse(target) => match pop_booleans(&mut stack, 1) {
+                    Ok(v) if !v[0] => frame.pc = *target,
+                    Ok(_) => {}
+                    Err(kind) => break kind,
+                },
+                Op::JumpIfTrue(target) => match pop_booleans(&mut stack, 1) {
+                    Ok(v) if v[0] => frame.pc = *target,
+                    Ok(_) => {}
+                    Err(kind) => break kind,
+                },
+                Op::Add(n) => match arith(&mut stack, *n, |v| {
+                    v.into_iter().try_fold(0, i64::checked_add)
+                }) {
+                    Ok(()) => {}
+                    Err(kind) => break kind,
+                },
+                Op::Mul(n) => match arith(&mut stack, *n, |v| {
+                    v.into_iter().try_fold(1, i64::checked_mul)
+                }) {
+                    Ok(()) => {}
+                    Err(kind) => break kind,
+                },
+                Op::Sub => match arith(&mut stack, 2, |v| v[0].checked_sub(v[1])) {
+                    Ok(()) => {}
+                    Err(kind) => break kind,
+                },
+                Op::Div => match arith(&mut stack, 2, |v| v[0].checked_div(v[1])) {
+                    Ok(()) => {}
+                    Err(kind) => break kind,
+                },
+                Op::Neg => match arith(&mut stack, 1, |v| v[0].checked_neg()) {
+                    Ok(()) => {}
+                    Err(kind) => break kind,
+                },
+                Op::CheckNumber => match stack.last() {
+                    Some(Data::Number(_)) => {}
+                    a => break RuntimeErrorKind::BadArith(a.cloned().unwrap()),
+                },
+                Op::CheckBoolean => match stack.last() {
+                    Some(Data::Boolean(_)) => {}
+                    a => break RuntimeErrorKind::BadBoolean(a.cloned().unwrap()),
+                },
+                Op::Xor(n) => match pop_booleans(&mut stack, *n) {
+                    Ok(v) => stack.push(Data::Boolean(v.into_iter().fold(false, |a, b| a != b))),
+                    Err(kind) => break kind,
+                },
+                Op::Not => match pop_booleans(&mut stack, 1) {
+                    Ok(v) => stack.push(Data::Boolean(!v[0])),
+                    Err(kind) => break kind,
+                },
+                Op::Eq(n) => {
+                    let v = stack.split_off(stack.len() - n);
+                    stack.push(Data::Boolean(v.into_iter().all_equal()));
+                }
+                Op::Neq => {
+                    let r = stack.pop().unwrap();
+                    let l = stack.pop().unwrap();
+                    stack.push(Data::Boolean(l != r));
+                }
+                Op::Compare(op) => {
+                    let r = stack.pop().unwrap();
+                    let l = stack.pop().unwrap();
+                    let order = eval::term_order(&l, &r);
+                    stack.push(Data::Boolean(match op {
+                        CompareOp::LT => order == Ordering::Less,
+                        CompareOp::GT => order == Ordering::Greater,
+                        CompareOp::LTE => order != Ordering::Greater,
+                        CompareOp::GTE => order != Ordering::Less,
+                    }));
+                }
+                Op::Cons => {
+                    let t = stack.pop().unwrap();
+                    let h = stack.pop().unwrap();
+                    stack.push(Data::List(Arc::new(h), Arc::new(t)));
+                }
+                Op::Call(i, argc) => {
+                    let args = stack.split_off(stack.len() - argc);
+                    let base = stack.len();
+                    match enter(code, *i, args, base) {
+                        Ok(callee) => frames.push(callee),
+                        Err(kind) => break kind,
+                    }
+                }
+                Op::TailCall(i, argc) => {
+                    let args = stack.split_off(stack.len() - argc);
+                    let base = frame.base;
+                    stack.truncate(base);
+                    match enter(code, *i, args, base) {
+                        Ok(callee) => *frame = callee,
+                        Err(kind) => break kind,
+                    }
+                }
+                Op::CallDynamic(slot, argc) => {
+                    let args = stack.split_off(stack.len() - argc);
+                    match frame.locals[*slot].clone() {
+                        Data::FunctionPointer(FunctionName::Static(n)) => {
+                            let name = FunctionName::Static(n);
+                            match code.index.get(&name) {
+                                Some(i) => {
+                                    let base = stack.len();
+                                    match enter(code, *i, args, base) {
+                                        Ok(callee) => frames.push(callee),
+                                        Err(kind) => break kind,
+                                    }
+                                }
+                                None => break RuntimeErrorKind::Undefined(name, args.len()),
+                            }
+                        }
+                        Data::FunctionPointer(host) => {
+                            if !caps.allows(&host) {
+                                return Err(denied(&host));
+                            }
+                            match function::host_call(&host, args) {
+                                Ok(res) => stack.push(res),
+                                Err(kind) => break kind,
+                            }
+                        }
+                        bad => break RuntimeErrorKind::BadFun(bad),
+                    }
+                }
+                Op::CallHost(host, argc) => {
+                    if !caps.allows(host) {
+                        return Err(denied(host));
+                    }
+                    let args = stack.split_off(stack.len() - argc);
+                    match function::host_call(host, args) {
+                        Ok(res) => stack.push(res),
+                        Err(kind) => break kind,
+                    }
+                }
+                Op::Return => {
+                    let res = stack.pop().unwrap();
+                    if let Some(kind) = check_result(f, &res) {
+                        break kind;
+                    }
+                    let base = frame.base;
+                    stack.truncate(base);
+                    frames.pop();
+                    if frames.is_empty() {
+                        return Ok(res);
+                    }
+                    stack.push(res);
+                }
+                Op::NoMatch => {
+                    let args = frame.locals[..f.arity].to_vec();
+                    break RuntimeErrorKind::FunctionClause(f.name.clone(), args);
+                }
+                Op::BadMatch(slot) => {
+                    break RuntimeErrorKind::BadMatch(frame.locals[*slot].clone())
+                }
+                Op::CaseClause(slot) => {
+                    break RuntimeErrorKind::CaseClause(frame.locals[*slot].clone())
+                }
+                Op::IfClause => break RuntimeErrorKind::IfClause,
+                Op::Unbound(name) => break RuntimeErrorKind::Unbound(name.clone()),
+                Op::Undefined(name, argc) => {
+                    break RuntimeErrorKind::Undefined(name.clone(), *argc)
+                }
+                Op::Try(handler) => handlers.push(Handler {
+                    frames: depth,
+                    stack: stack.len(),
+                    pc: *handler,
+                }),
+                Op::EndTry => {
+                    handlers.pop();
+                }
+                Op::Raise => {
+                    let term = stack.pop().unwrap();
+                    let class = Class::from_atom(&stack.pop().unwrap());
+                    break RuntimeErrorKind::Raised(class, term);
+                }
+            }
+        };
+        // the innermost try takes it, its catch clauses start with the class and the term
+        match handlers.pop() {
+            Some(h) => {
+                frames.truncate(h.frames);
+                stack.truncate(h.stack);
+                stack.push(kind.class().atom());
+                stack.push(kind.term());
+                frames.last_mut().unwrap().pc = h.pc;
+            }
+            None => return Err(runtime_error(kind, code, &frames)),
+        }
+    }
+}
+
+// the language level stack, innermost call first,
+// a frame points one past the instruction it is running
+fn runtime_error(kind: RuntimeErrorKind, code: &Compiled, frames: &[Frame]) -> RunError {
+    let stack = frames
+        .iter()
+        .rev()
+        .map(|frame| {
+            let f = &code.funs[frame.fun];
+            let pc = frame.pc.saturating_sub(1);
+            StackEntry {
+                function: f.name.clone(),
+                arity: f.arity,
+                clause: f.clause_at(pc),
+                line: f.line_at(pc),
+            }
+        })
+        .collect();
+    return RunError::Runtime(RuntimeError {
+        kind: kind,
+        stack: stack,
+    });
+}
+
+// a new frame with the arguments in the first slots
+fn enter(
+    code: &Compiled,
+    fun: usize,
+    args: Vec<Data>,
+    base: usize,
+) -> Result<Frame, RuntimeErrorKind> {
+    let f = &code.funs[fun];
+    if args.len() != f.arity {
+        return Err(RuntimeErrorKind::BadArity(f.name.clone(), args.len()));
+    }
+    if let Some(types::Type::Fun(arg_types, _)) = &f.spec {
+        for (a, t) in args.iter().zip(arg_types.iter()) {
+            if !types::data_has_type(a, t) {
+                return Err(RuntimeErrorKind::Spec(f.name.clone(), a.clone(), t.clone()));
+            }
+        }
+    }
+    let mut locals = args;
+    locals.resize(f.slots, Data::Emptylist);
+    return Ok(Frame {
+        fun: fun,
+        pc: 0,
+        base: base,
+        locals: locals,
+    });
+}
+
+fn check_result(f: &CompiledFun, res: &Data) -> Option<RuntimeErrorKind> {
+    if let Some(types::Type::Fun(_, ret)) = &f.spec {
+        if !types::data_has_type(res, ret) {
+            return Some(RuntimeErrorKind::Spec(
+                f.name.clone(),
+                res.clone(),
+                (**ret).clone(),
+            ));
+        }
+    }
+    return None;
+}
+
+// rust:: and builtin functions do not look at the program
+// pops n numbers and pushes what op makes of them, None is an overflow or a division by zero
+fn arith(
+    stack: &mut Vec<Data>,
+    n: usize,
+    op: impl Fn(Vec<i64>) -> Option<i64>,
+) -> Result<(), RuntimeErrorKind> {
+    let mut v = Vec::new();
+    for x in stack.split_off(stack.len() - n).into_iter() {
+        match x {
+            Data::Number(NumericData::Int(i)) => v.push(i),
+            a => return Err(RuntimeErrorKind::BadArith(a)),
+        }
+    }
+    match op(v.clone()) {
+        Some(i) => stack.push(Data::Number(NumericData::Int(i))),
+        None => {
+            let args = v.into_iter().map(|i| Data::Number(NumericData::Int(i)));
+            return Err(RuntimeErrorKind::BadArith(list(args.collect())));
+        }
+    }
+    return Ok(());
+}
+
+fn pop_booleans(stack: &mut Vec<Data>, n: usize) -> Result<Vec<bool>, RuntimeErrorKind> {
+    let mut v = Vec::new();
+    for x in stack.split_off(stack.len() - n).into_iter() {
+        match x {
+            Data::Boolean(b) => v.push(b),
+            a => return Err(RuntimeErrorKind::BadBoolean(a)),
+        }
+    }
+    return Ok(v);
+}
+
+fn list(v: Vec<Data>) -> Data {
+    return v
+        .into_iter()
+        .rev()
+        .fold(Data::Emptylist, |t, h| Data::List(Arc::new(h), Arc::new(t)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bytecode, eval, fold, inline, program};
+
+    // main() through the whole pipeline of run, folded, inlined and compiled
+    fn run_compiled(code: &eval::Program, limits: &Limits) -> Result<Data, RunError> {
+        let main = FunctionName::Static("main".chars().collect());
+        let (optimised, _, _) = inline::inline_program(fold::fold_program(code.clone()), &main);
+        let compiled = bytecode::compile_program(&optimised);
+        return call(&compiled, &main, Vec::new(), limits, &Capabilities::All);
+    }
+
+    #[test]
+    fn samples_give_what_the_tree_walker_gives() {
+        for path in program::SAMPLES {
+            let code = program::from_file(path);
+            let walked = eval::run_main(&code);
+            match run_compiled(&code, &Limits::default()) {
+                Ok(res) => assert_eq!(
+                    Ok(res),
+                    walked,
+                    "the vm gives a different result for {}",
+                    path
+                ),
+                Err(e) => panic!("the vm failed on {}: {}", path, e),
+            }
+        }
+    }
+
+    // a walk over a list loads the rest of it on every step, that must not copy it
+    #[test]
+    fn walking_a_long_list_is_linear() {
+        let code = program::from_source(
+            "count:\n    (0, Acc)\n    / Acc \\\n    (N, Acc)\n    / count(N - 1, [N|Acc]) \\\nend\n\
+             len:\n    ([], A)\n    / A \\\n    ([_|T], A)\n    / len(T, A + 1) \\\nend\n\
+             main:\n    ()\n    / len(count(20000, []), 0) \\\nend\n",
+        );
+        let limits = Limits {
+            time: Some(Duration::from_secs(5)),
+            ..Limits::default()
+        };
+        let res = run_compiled(&code, &limits).expect("walks the list in time");
+        assert_eq!(res, Data::Number(NumericData::Int(20000)));
+    }
+
+    // c() -> a() -> c() runs until the reductions are used up, in the vm and the tree walker
+    const LOOP: &str = "c:\n    ()\n    / a() \\\nend\na:\n    ()\n    / c() \\\nend\n\
+                        main:\n    ()\n    / try c() catch _ -> caught end \\\nend\n";
+
+    fn walk_limited(code: &eval::Program, limits: &Limits) -> Result<Data, LimitExceeded> {
+        let main = code[&FunctionName::Static("main".chars().collect())].clone();
+        match eval::limited(limits, &Capabilities::All, || {
+            eval::call(code.clone(), main, Vec::new())
+        }) {
+            Ok(res) => return Ok(res),
+            Err(RunError::LimitExceeded(e)) => return Err(e),
+            Err(e) => panic!("expected a limit, got {}", e),
+        }
+    }
+
+    #[test]
+    fn endless_recursion_runs_out_of_reductions() {
+        let code = program::from_source(LOOP);
+        let limits = Limits {
+            reductions: Some(1000),
+            ..Limits::default()
+        };
+        match run_compiled(&code, &limits) {
+            Err(RunError::LimitExceeded(e)) => assert!(matches!(e.limit, Limit::Reductions(1000))),
+            other => panic!("expected the reductions to run out, got {:?}", other),
+        }
+        match walk_limited(&code, &limits) {
+            Err(e) => assert!(matches!(e.limit, Limit::Reductions(1000))),
+            other => panic!("expected the reductions to run out, got {:?}", other),
+        }
+    }
+
+    // the tree walker has no tail calls, it stops before the stack of the host overflows
+    #[test]
+    fn the_tree_walker_stops_deep_recursion() {
+        let code = program::from_source(
+            "down:\n    (0)\n    / 0 \\\n    (N)\n    / 1 + down(N - 1) \\\nend\n\
+             main:\n    ()\n    / down(100000) \\\nend\n",
+        );
+        match walk_limited(&code, &Limits::default()) {
+            Err(e) => assert!(matches!(e.limit, Limit::Depth(_))),
+            other => panic!("expected the depth to run out, got {:?}", other),
+        }
+        let limits = Limits {
+            depth: Some(50),
+            ..Limits::default()
+        };
+        match walk_limited(&code, &limits) {
+            Err(e) => assert!(matches!(e.limit, Limit::Depth(50))),
+            other => panic!("expected the depth to run out, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn the_tree_walker_counts_list_cells() {
+        let code = program::from_source(
+            "count:\n    (0, Acc)\n    / Acc \\\n    (N, Acc)\n    / count(N - 1, [N|Acc]) \\\nend\n\
+             main:\n    ()\n    / count(100, []) \\\nend\n",
+        );
+        let limits = Limits {
+            heap: Some(10),
+            ..Limits::default()
+        };
+        match walk_limited(&code, &limits) {
+            Err(e) => assert!(matches!(e.limit, Limit::Heap(10))),
+            other => panic!("expected the heap to run out, got {:?}", other),
+        }
+    }
+
+    // calls to functions that do not exist are runtime errors a try catches
+    #[test]
+    fn undefined_calls_are_caught() {
+        let code = program::from_source(
+            "main:\n    ()\n    /\n\
+             A = try is_number(1, 2) catch error:E -> E end\n\
+             B = try rust::nosuch(1) catch error:E -> E end\n\
+             C = try nosuch(1) catch error:E -> E end\n\
+             [A, B, C]\n    \\\nend\n",
+        );
+        let undef = |f: FunctionName, arity: usize| RuntimeErrorKind::Undefined(f, arity).term();
+        let expected = list(vec![
+            undef(FunctionName::Builtin("is_number".chars().collect()), 2),
+            undef(FunctionName::Rust("nosuch".chars().collect()), 1),
+            undef(FunctionName::Static("nosuch".chars().collect()), 1),
+        ]);
+        let walked = eval::limited(&Limits::default(), &Capabilities::All, || {
+            eval::run_main(&code)
+        });
+        assert_eq!(walked.ok(), Some(Ok(expected.clone())));
+        match run_compiled(&code, &Limits::default()) {
+            Ok(res) => assert_eq!(res, expected),
+            Err(e) => panic!("the vm failed: {}", e),
+        }
+    }
+
+    #[test]
+    fn undefined_function_names_its_arity() {
+        let kind = function::unbound_call("nosuch".chars().collect(), 1);
+        assert_eq!(kind.to_string(), "undefined function nosuch/1");
+        let kind = function::unbound_call("F".chars().collect(), 1);
+        assert_eq!(kind.to_string(), "variable F is unbound");
+    }
+
+    // the error that stops the program names every call still waiting for a result
+    #[test]
+    fn an_uncaught_error_prints_the_calls_that_led_to_it() {
+        let code = program::from_source(
+            "f:\n    ([])\n    / none \\\n    (X)\n    / is_list(X, 1) \\\nend\n\
+             main:\n    ()\n    / [f(1)] \\\nend\n",
+        );
+        let main = FunctionName::Static("main".chars().collect());
+        let compiled = bytecode::compile_program(&code);
+        match call(
+            &compiled,
+            &main,
+            Vec::new(),
+            &Limits::default(),
+            &Capabilities::All,
+        ) {
+            Err(RunError::Runtime(e)) => assert_eq!(
+                e.to_string(),
+                "** exception error: undefined function is_list/2\n     \
+                 in function  f/1, clause 2 (line 4)\n     \
+                 in call from main/0, clause 1 (line 8)"
+            ),
+            other => panic!("expected a runtime error, got {:?}", other),
+        }
+    }
+
+    // a call through a variable checks the arity in both, the walker used to panic
+    #[test]
+    fn wrong_arity_through_a_variable_is_caught() {
+        let code = program::from_source(
+            "pass:\n    (F)\n    / F(1, 2) \\\nend\n\
+             f:\n    (X)\n    / X \\\nend\n\
+             main:\n    ()\n    /\n\
+             try pass(f) catch error:E -> E end\n    \\\nend\n",
+        );
+        let expected =
+            RuntimeErrorKind::BadArity(FunctionName::Static("f".chars().collect()), 2).term();
+        let walked = eval::limited(&Limits::default(), &Capabilities::All, || {
+            eval::run_main(&code)
+        });
+        assert_eq!(walked.ok(), Some(Ok(expected.clone())));
+        match run_compiled(&code, &Limits::default()) {
+            Ok(res) => assert_eq!(res, expected),
+            Err(e) => panic!("the vm failed: {}", e),
+        }
+    }
+
+    // && and || stop at the operand that decides, nosuch is never called
+    #[test]
+    fn connectives_stop_at_the_deciding_operand() {
+        let code = program::from_source(
+            "main:\n    ()\n    /\n\
+             F = is_number(true)\n\
+             T = is_number(1)\n\
+             [F && nosuch(), T || nosuch(), F andalso nosuch(), T orelse nosuch()]\n    \\\nend\n",
+        );
+        let expected = list(vec![
+            Data::Boolean(false),
+            Data::Boolean(true),
+            Data::Boolean(false),
+            Data::Boolean(true),
+        ]);
+        assert_eq!(eval::run_main(&code), Ok(expected.clone()));
+        // unfolded, so the vm itself has to jump over the calls
+        let main = FunctionName::Static("main".chars().collect());
+        let compiled = bytecode::compile_program(&code);
+        match call(
+            &compiled,
+            &main,
+            Vec::new(),
+            &Limits::default(),
+            &Capabilities::All,
+        ) {
+            Ok(res) => assert_eq!(res, expected),
+            Err(e) => panic!("the vm failed: {}", e),
+        }
+        match run_compiled(&code, &Limits::default()) {
+            Ok(res) => assert_eq!(res, expected),
+            Err(e) => panic!("the vm failed: {}", e),
+        }
+    }
+
+    // as in erlang a variable bound before only matches an equal value
+    #[test]
+    fn bound_variables_match_equal_values() {
+        let code = program::from_source(
+            "same:\n    (X, X)\n    / true \\\n    (_, _)\n    / false \\\nend\n\
+             rebind:\n    (N)\n    /\n    A = 1\n    A = N\n    A\n    \\\nend\n\
+             main:\n    ()\n    /\n\
+             A = 1\n\
+             B = try rebind(2) catch error:E -> E end\n\
+             C = case 2 of\n        A / a \\\n        _ / b \\\n    end\n\
+             [rebind(1), same(1, 1), same(1, 2), B, C, A]\n    \\\nend\n",
+        );
+        let int = |i: i64| Data::Number(NumericData::Int(i));
+        let expected = list(vec![
+            int(1),
+            Data::Boolean(true),
+            Data::Boolean(false),
+            RuntimeErrorKind::BadMatch(int(2)).term(),
+            Data::Atom("b".chars().collect()),
+            int(1),
+        ]);
+        let walked = eval::limited(&Limits::default(), &Capabilities::All, || {
+            eval::run_main(&code)
+        });
+        assert_eq!(walked.ok(), Some(Ok(expected.clone())));
+        match run_compiled(&code, &Limits::default()) {
+            Ok(res) => assert_eq!(res, expected),
+            Err(e) => panic!("the vm failed: {}", e),
+        }
+    }
+}